@@ -0,0 +1,200 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Timed captions for quipu recordings
+//!
+//! Subtitle directives are collected with the time they were reached during
+//! playback and written out as a track to ship alongside the recording.
+//! Command boundaries can be written the same way, as asciicast markers.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    // Time since playback started
+    pub start: Duration,
+    pub text: String,
+}
+
+// HH:MM:SS followed by `separator` and milliseconds
+fn timestamp(time: Duration, separator: char) -> String {
+    let ms = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+// WebVTT timestamp, HH:MM:SS.mmm
+fn vtt_timestamp(time: Duration) -> String {
+    timestamp(time, '.')
+}
+
+// SRT timestamp, HH:MM:SS,mmm
+fn srt_timestamp(time: Duration) -> String {
+    timestamp(time, ',')
+}
+
+// Render cues as a WebVTT track. Each cue shows until the next begins; the
+// last until `end`.
+pub fn to_webvtt(cues: &[Cue], end: Duration) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for (i, cue) in cues.iter().enumerate() {
+        let until = cues
+            .get(i + 1)
+            .map_or(end, |next| next.start)
+            .max(cue.start);
+        out.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            vtt_timestamp(cue.start),
+            vtt_timestamp(until),
+            cue.text
+        ));
+    }
+    out
+}
+
+// Render cues as a SubRip (SRT) track, timed as for to_webvtt. SRT has no
+// use for a caption that is never on screen, so cues with no text or no
+// time before the next are left out, and the rest numbered from 1.
+pub fn to_srt(cues: &[Cue], end: Duration) -> String {
+    let mut out = String::new();
+    let mut number = 0;
+    for (i, cue) in cues.iter().enumerate() {
+        let until = cues.get(i + 1).map_or(end, |next| next.start);
+        if cue.text.is_empty() || until <= cue.start {
+            continue;
+        }
+        number += 1;
+        out.push_str(&format!(
+            "{number}\n{} --> {}\n{}\n\n",
+            srt_timestamp(cue.start),
+            srt_timestamp(until),
+            cue.text
+        ));
+    }
+    out
+}
+
+// A string as a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut out = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Render cues as asciicast v2 marker events, `[time, "m", label]`, one per
+// line, ready to be merged into a recording's event stream
+pub fn to_asciicast_markers(cues: &[Cue]) -> String {
+    cues.iter()
+        .map(|cue| {
+            format!(
+                "[{:.6}, \"m\", {}]\n",
+                cue.start.as_secs_f64(),
+                json_string(&cue.text)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vtt_timestamp() {
+        assert_eq!(vtt_timestamp(Duration::ZERO), "00:00:00.000");
+        assert_eq!(
+            vtt_timestamp(Duration::from_millis(3_723_045)),
+            "01:02:03.045"
+        );
+    }
+
+    #[test]
+    fn test_to_webvtt() {
+        let cues = vec![
+            Cue {
+                start: Duration::from_secs(1),
+                text: "First".to_string(),
+            },
+            Cue {
+                start: Duration::from_millis(2500),
+                text: "Second".to_string(),
+            },
+        ];
+        assert_eq!(
+            to_webvtt(&cues, Duration::from_secs(4)),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.500\nFirst\n\n00:00:02.500 --> 00:00:04.000\nSecond\n"
+        );
+        assert_eq!(to_webvtt(&[], Duration::from_secs(4)), "WEBVTT\n");
+    }
+
+    #[test]
+    fn test_to_srt() {
+        let cue = |ms, text: &str| Cue {
+            start: Duration::from_millis(ms),
+            text: text.to_string(),
+        };
+        let cues = vec![
+            cue(1000, "First"),
+            // Replaced at once, then cleared
+            cue(2500, "Never shown"),
+            cue(2500, "Second"),
+            cue(3000, ""),
+            cue(3_723_045, "Last"),
+        ];
+        assert_eq!(
+            to_srt(&cues, Duration::from_secs(3725)),
+            "1\n00:00:01,000 --> 00:00:02,500\nFirst\n\n\
+             2\n00:00:02,500 --> 00:00:03,000\nSecond\n\n\
+             3\n01:02:03,045 --> 01:02:05,000\nLast\n\n"
+        );
+        // A caption reached as playback ends has no time on screen
+        assert_eq!(to_srt(&[cue(1000, "End")], Duration::from_secs(1)), "");
+    }
+
+    #[test]
+    fn test_to_asciicast_markers() {
+        let cues = vec![
+            Cue {
+                start: Duration::from_millis(1500),
+                text: "$ echo \"hi\"<ret>".to_string(),
+            },
+            Cue {
+                start: Duration::from_secs(3),
+                text: "@ shuffle\t\u{1b}".to_string(),
+            },
+        ];
+        assert_eq!(
+            to_asciicast_markers(&cues),
+            "[1.500000, \"m\", \"$ echo \\\"hi\\\"<ret>\"]\n\
+             [3.000000, \"m\", \"@ shuffle\\t\\u001b\"]\n"
+        );
+    }
+}
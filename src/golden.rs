@@ -0,0 +1,160 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Snapshot testing: compare what a session printed with a stored golden
+//! file, showing a line diff where they differ
+
+// Lines of unchanged text shown around each change
+const CONTEXT: usize = 2;
+// Largest lines-by-lines table worth building for a full diff; beyond it
+// every line from the first difference on is shown as changed
+const MAX_TABLE: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Same,
+    Removed,
+    Added,
+}
+
+// Pair each line with how it changed from `expected` to `actual`, via their
+// longest common subsequence
+fn edits<'a>(expected: &[&'a str], actual: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    let prefix = expected
+        .iter()
+        .zip(actual)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (expected_rest, actual_rest) = (&expected[prefix..], &actual[prefix..]);
+    let mut out: Vec<_> = expected[..prefix]
+        .iter()
+        .map(|line| (Edit::Same, *line))
+        .collect();
+
+    let (n, m) = (expected_rest.len(), actual_rest.len());
+    if n.saturating_mul(m) > MAX_TABLE {
+        out.extend(expected_rest.iter().map(|line| (Edit::Removed, *line)));
+        out.extend(actual_rest.iter().map(|line| (Edit::Added, *line)));
+        return out;
+    }
+    // common[i][j]: length of the longest common subsequence of
+    // expected_rest[i..] and actual_rest[j..]
+    let mut common = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected_rest[i] == actual_rest[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected_rest[i] == actual_rest[j] {
+            out.push((Edit::Same, expected_rest[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || common[i + 1][j] >= common[i][j + 1]) {
+            out.push((Edit::Removed, expected_rest[i]));
+            i += 1;
+        } else {
+            out.push((Edit::Added, actual_rest[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+// Control characters written as escapes, so a diff of raw terminal output
+// can be printed without acting on it
+fn escape_line(line: &str) -> String {
+    line.chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+// A line diff from `expected` to `actual`, or None if they are the same.
+// Removed lines start with `-`, added ones with `+`, and each group of
+// changes is headed by the line numbers it starts at in both.
+pub fn diff(expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let expected: Vec<_> = expected.split('\n').collect();
+    let actual: Vec<_> = actual.split('\n').collect();
+    let edits = edits(&expected, &actual);
+
+    // Which edits to show: the changes and the context around them
+    let mut shown = vec![false; edits.len()];
+    for (index, (edit, _)) in edits.iter().enumerate() {
+        if *edit != Edit::Same {
+            let end = (index + CONTEXT + 1).min(edits.len());
+            shown[index.saturating_sub(CONTEXT)..end].fill(true);
+        }
+    }
+
+    let mut out = String::new();
+    let (mut expected_line, mut actual_line) = (1, 1);
+    for (index, (edit, line)) in edits.iter().enumerate() {
+        if shown[index] {
+            if index == 0 || !shown[index - 1] {
+                out.push_str(&format!("@@ -{expected_line} +{actual_line} @@\n"));
+            }
+            let sign = match edit {
+                Edit::Same => ' ',
+                Edit::Removed => '-',
+                Edit::Added => '+',
+            };
+            out.push_str(&format!("{sign}{}\n", escape_line(line)));
+        }
+        match edit {
+            Edit::Same => {
+                expected_line += 1;
+                actual_line += 1;
+            }
+            Edit::Removed => expected_line += 1,
+            Edit::Added => actual_line += 1,
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\n", "a\nb\n"), None);
+        let expected = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let actual = "1\n2\nthree\n4\n5\n6\n7\n8\n9\nten\n";
+        assert_eq!(
+            diff(expected, actual).unwrap(),
+            "@@ -1 +1 @@\n 1\n 2\n-3\n+three\n 4\n 5\n\
+             @@ -8 +8 @@\n 8\n 9\n+ten\n \n"
+        );
+        assert_eq!(
+            diff("$ ls\r\n", "$ ls\r\nfile\r\n").unwrap(),
+            "@@ -1 +1 @@\n $ ls\\r\n+file\\r\n \n"
+        );
+    }
+}
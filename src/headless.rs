@@ -0,0 +1,182 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Headless playback for tests and CI
+//!
+//! Runs a script against a real shell in a PTY with no terminal of our own
+//! involved: typing is instant, nothing is read from stdin or written to
+//! stdout, and everything the session prints is returned once the shell exits.
+//! `simulate` goes further, playing a script with no shell at all to check its
+//! timing.
+
+use anyhow::Result;
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::Duration;
+
+use crate::parser::parse_script;
+use crate::playback::{Keystroke, PlaybackEngine};
+use crate::pty::{DEFAULT_SIZE, EXIT_TIMEOUT, MemorySink, PtyManager, PtyOptions};
+use crate::types::Script;
+
+// Time for the shell to start and draw its first prompt before typing, so
+// the first keystrokes don't race its startup
+const SETTLE: Duration = Duration::from_secs(1);
+
+// Run `script` in `shell` and return what the session printed, with escape
+// sequences and carriage returns removed so it can be compared as plain text.
+// SIGINT is left to the caller.
+pub async fn run_headless(script: &str, shell: &str) -> Result<String> {
+    let output = run_headless_raw(script, shell).await?;
+    Ok(strip_ansi(&String::from_utf8_lossy(&output)))
+}
+
+// Like run_headless, but return the session's output exactly as printed.
+// Typing takes no time; `@ wait` pauses are kept, giving commands time to
+// produce output. `shell` takes priority over the script's `@ shell`, and
+// the shell is sent EOF once the script ends, so it must exit on its own.
+pub async fn run_headless_raw(script: &str, shell: &str) -> Result<Vec<u8>> {
+    let script = parse_script(script).map_err(|e| anyhow::anyhow!("Parse error: {e}"))?;
+    let (setup, script) = script
+        .partition()
+        .map_err(|e| anyhow::anyhow!("Script error: {e}"))?;
+    let (cols, rows) = setup.size.unwrap_or(DEFAULT_SIZE);
+    let options = PtyOptions {
+        env: setup.env,
+        umask: setup.umask,
+        ..PtyOptions::default()
+    };
+
+    let sink = MemorySink::default();
+    let pty = PtyManager::headless(shell, cols, rows, &options, sink.clone())?;
+    let ready_sentinel = setup.ready_sentinel.unwrap_or(false);
+    if !ready_sentinel {
+        tokio::time::sleep(SETTLE).await;
+    }
+
+    let mut engine = PlaybackEngine::without_signal_handler(pty, Arc::new(AtomicBool::new(true)));
+    engine.set_ready_sentinel(ready_sentinel);
+    engine.set_prewarm(setup.prewarm.unwrap_or(false));
+    if let Some(seed) = setup.seed {
+        engine.set_seed(seed);
+    }
+    engine.set_time_scale(0.0, 1.0);
+    engine.set_host_output(sink.clone());
+    engine.execute(script).await?;
+
+    if !engine.into_pty().shutdown(EXIT_TIMEOUT) {
+        anyhow::bail!(
+            "{shell} did not exit within {}s of the script ending",
+            EXIT_TIMEOUT.as_secs()
+        );
+    }
+    Ok(sink.contents())
+}
+
+// Play `script` with no shell, terminal or signal handler, and return every
+// keystroke typed with when it was typed and the delay after it. Times come
+// from tokio's clock: under a paused clock (`#[tokio::test(start_paused =
+// true)]` or tokio::time::pause) the script plays instantly with its timing
+// intact. Setup directives other than `@ seed` are ignored, and nothing is
+// ever printed for output waits (e.g. `@ wait_response`) to see.
+pub async fn simulate(script: Script) -> Result<Vec<Keystroke>> {
+    let (setup, script) = script
+        .partition()
+        .map_err(|e| anyhow::anyhow!("Script error: {e}"))?;
+    let (cols, rows) = setup.size.unwrap_or(DEFAULT_SIZE);
+    let pty = PtyManager::with_sink(MemorySink::default(), cols, rows);
+    let mut engine = PlaybackEngine::without_signal_handler(pty, Arc::new(AtomicBool::new(true)));
+    if let Some(seed) = setup.seed {
+        engine.set_seed(seed);
+    }
+    engine.set_record_keystrokes(true);
+    engine.set_host_output(std::io::sink());
+    engine.execute(script).await?;
+    Ok(engine.keystrokes().to_vec())
+}
+
+// Remove terminal escape sequences (CSI, OSC and the like) and control
+// characters other than newlines and tabs from captured output
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters, then a final byte from @ to ~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC and other strings, ended by BEL or ESC \
+                Some(']' | 'P' | 'X' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // Character set selection takes one more byte
+                Some('(' | ')' | '*' | '+') => {
+                    chars.next();
+                }
+                _ => {}
+            },
+            '\n' | '\t' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("\x1b[?2004h\x1b]133;A\x07$ \x1b[1mls\x1b[0m\r\nfile\x1b(B\r\n"),
+            "$ ls\nfile\n"
+        );
+        assert_eq!(strip_ansi("\x1b]0;title\x1b\\done\x07"), "done");
+        assert_eq!(strip_ansi("tab\there"), "tab\there");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulate() {
+        let script = parse_script("@ seed:1\n$ ab\n@ wait:2\n@ speed:0.5\n$ <ret>").unwrap();
+        let start = tokio::time::Instant::now();
+        let keystrokes = simulate(script).await.unwrap();
+
+        let keystroke = |ms, key: &str, delay_ms| Keystroke {
+            at: Duration::from_millis(ms),
+            key: key.to_string(),
+            delay: Duration::from_millis(delay_ms),
+        };
+        assert_eq!(
+            keystrokes,
+            [
+                keystroke(0, "a", 100),
+                keystroke(100, "b", 100),
+                keystroke(2200, "\r", 500),
+            ]
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(2700));
+    }
+}
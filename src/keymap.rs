@@ -0,0 +1,124 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keymaps overriding or extending quipu's special keys
+//!
+//! A keymap file is TOML with a `[keys]` table mapping key names, as written
+//! between `<` and `>` in a script, to the bytes they send:
+//!
+//! ```toml
+//! [keys]
+//! home = "\x1b[1~"
+//! end = "\x1b[4~"
+//! menu = "\x1b[29~"
+//! ```
+//!
+//! A name in the keymap takes precedence over the built-in key of the same
+//! name, including as the key in a modifier combination such as `<A-home>`.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keymap {
+    keys: HashMap<String, String>,
+}
+
+impl Keymap {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let table: toml::Table = source.parse().map_err(|e| format!("{e}"))?;
+        let keys = match table.get("keys") {
+            Some(toml::Value::Table(keys)) => keys,
+            Some(_) => return Err("'keys' must be a table".to_string()),
+            None => return Err("missing [keys] table".to_string()),
+        };
+
+        let mut keymap = Self::default();
+        for (name, value) in keys {
+            if name.is_empty() || name.contains(['<', '>']) || name.contains(char::is_whitespace) {
+                return Err(format!(
+                    "invalid key name '{name}': it can't be empty or contain '<', '>' or spaces"
+                ));
+            }
+            let seq = value
+                .as_str()
+                .filter(|seq| !seq.is_empty())
+                .ok_or_else(|| format!("bytes for '{name}' must be a non-empty string"))?;
+            keymap.keys.insert(name.clone(), seq.to_string());
+        }
+        Ok(keymap)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read keymap {}", path.display()))?;
+        Self::parse(&source).map_err(|e| anyhow::anyhow!("Invalid keymap {}: {e}", path.display()))
+    }
+
+    // The bytes `name` sends, if the keymap defines it
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.keys.get(name).map(String::as_str)
+    }
+
+    // Define `name`, replacing any existing definition
+    pub fn insert(&mut self, name: &str, seq: &str) {
+        self.keys.insert(name.to_string(), seq.to_string());
+    }
+
+    // Add the keys from `other`, replacing any already defined here
+    pub fn merge(&mut self, other: &Keymap) {
+        self.keys.extend(
+            other
+                .keys
+                .iter()
+                .map(|(name, seq)| (name.clone(), seq.clone())),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keymap() {
+        let keymap =
+            Keymap::parse("[keys]\nhome = \"\\x1b[1~\"\nmenu = \"\\e[29~\"\n\"C-x\" = \"X\"\n")
+                .unwrap();
+        assert_eq!(keymap.get("home"), Some("\x1b[1~"));
+        assert_eq!(keymap.get("menu"), Some("\x1b[29~"));
+        assert_eq!(keymap.get("C-x"), Some("X"));
+        assert_eq!(keymap.get("end"), None);
+    }
+
+    #[test]
+    fn test_merge_keymaps() {
+        let mut keymap = Keymap::parse("[keys]\nhome = \"a\"\nend = \"b\"").unwrap();
+        keymap.merge(&Keymap::parse("[keys]\nhome = \"c\"").unwrap());
+        assert_eq!(keymap.get("home"), Some("c"));
+        assert_eq!(keymap.get("end"), Some("b"));
+    }
+
+    #[test]
+    fn test_invalid_keymaps() {
+        assert!(Keymap::parse("").is_err());
+        assert!(Keymap::parse("keys = 1").is_err());
+        assert!(Keymap::parse("[keys]\nhome = 1").is_err());
+        assert!(Keymap::parse("[keys]\nhome = \"\"").is_err());
+        assert!(Keymap::parse("[keys]\n\"my key\" = \"x\"").is_err());
+        assert!(Keymap::parse("[keys]\n\"<x>\" = \"x\"").is_err());
+    }
+}
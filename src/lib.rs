@@ -0,0 +1,37 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Script keyboard entry in the terminal
+//!
+//! The `quipu` binary is a thin CLI over this library; embedders can parse
+//! scripts (optionally with custom directives) and drive playback directly.
+
+pub mod captions;
+pub mod golden;
+pub mod headless;
+pub mod keymap;
+pub mod parser;
+pub mod playback;
+pub mod pty;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "gif")]
+pub mod render;
+#[cfg(feature = "terminfo")]
+pub mod terminfo;
+pub mod theme;
+pub mod types;
+
+pub use headless::{run_headless, simulate};
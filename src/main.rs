@@ -13,110 +13,607 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-mod parser;
-mod playback;
-mod pty;
-mod types;
-
 use anyhow::{Context, Result};
 use clap::Parser as ClapParser;
-use std::path::PathBuf;
+use quipu::{keymap, parser, playback, pty, types};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
 };
+use std::time::Duration;
 
 #[derive(ClapParser, Debug)]
 #[command(name = "quipu")]
 #[command(about = "Script keyboard entry in the terminal", long_about = None)]
 struct Args {
-    /// The script file to execute
-    #[arg(value_name = "SCRIPT")]
-    script: PathBuf,
+    /// The script files to execute, run one after another in the same session.
+    /// With --allow-remote, http(s) URLs work too.
+    #[arg(value_name = "SCRIPT", required = true)]
+    scripts: Vec<PathBuf>,
+
+    /// Run scripts given as http(s) URLs. They may not use directives that
+    /// act on this machine, such as @verify or @capture.
+    #[arg(long)]
+    allow_remote: bool,
+
+    /// Only let scripts type these bytes into the session: a comma-separated
+    /// list of bytes (0x1b), ranges (0x00-0x1f) and the sets printable,
+    /// unicode and safe (printable text with Tab, Enter and Backspace)
+    #[arg(long, value_name = "BYTES", value_parser = parser::AllowedBytes::parse)]
+    allow_bytes: Option<parser::AllowedBytes>,
+
+    /// Whether typing a byte --allow-bytes leaves out fails to parse the
+    /// script or only warns
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = types::ByteCheck::Error,
+        requires = "allow_bytes"
+    )]
+    disallowed_bytes: types::ByteCheck,
 
-    /// Shell to use for the PTY session (defaults to current shell)
+    /// Shell to use for the PTY session (defaults to $SHELL, then /bin/sh)
     #[arg(short, long)]
     shell: Option<String>,
 
+    /// Send keystrokes to an existing session through this path (e.g. an
+    /// inherited PTY master at /dev/fd/N) instead of spawning a shell
+    #[arg(long, value_name = "PATH")]
+    attach: Option<PathBuf>,
+
+    /// Feed the contents of this file to the shell as input once it starts,
+    /// before playback and before your own keystrokes are passed through
+    #[arg(long, value_name = "PATH", conflicts_with = "attach")]
+    prime: Option<PathBuf>,
+
     /// Suppress informational status messages
     #[arg(short, long)]
     quiet: bool,
+
+    /// How playback ends: as written, without the final Enter, or by clearing
+    /// the screen after a short hold
+    #[arg(long, value_enum, default_value_t = types::Ending::Keep)]
+    ending: types::Ending,
+
+    /// Whether a failed write to the PTY stops playback or skips the command
+    #[arg(long, value_enum, default_value_t = types::WriteErrorPolicy::Abort)]
+    on_write_error: types::WriteErrorPolicy,
+
+    /// Fail if the shell hasn't exited SECS seconds after playback ends,
+    /// killing it, and report how long it took
+    #[arg(long, value_name = "SECS", conflicts_with = "attach")]
+    exit_within: Option<f64>,
+
+    /// Whether Ctrl-C stops playback straight away or is passed to the shell,
+    /// with a quick second Ctrl-C stopping playback
+    #[arg(long, value_enum, default_value_t = types::CtrlCPolicy::Abort)]
+    ctrl_c: types::CtrlCPolicy,
+
+    /// Write `@ subtitle` cues to this file as a WebVTT track, or as SRT if
+    /// it ends in `.srt`
+    #[arg(long, value_name = "PATH")]
+    subtitles: Option<PathBuf>,
+
+    /// Write a marker for each command that types something to this file, as
+    /// asciicast v2 marker events for chapter navigation
+    #[arg(long, value_name = "PATH")]
+    markers: Option<PathBuf>,
+
+    /// Render the session to an animated GIF at this path once playback ends
+    #[cfg(feature = "gif")]
+    #[arg(long, value_name = "PATH", conflicts_with = "attach")]
+    gif: Option<PathBuf>,
+
+    /// Frames per second sampled for --gif
+    #[cfg(feature = "gif")]
+    #[arg(long, value_name = "FPS", default_value_t = 10, requires = "gif",
+          value_parser = clap::value_parser!(u32).range(1..=50))]
+    gif_fps: u32,
+
+    /// Cut the quiet start of the --gif recording, before anything shows on
+    /// screen, down to SECS
+    #[cfg(feature = "gif")]
+    #[arg(long, value_name = "SECS", requires = "gif")]
+    trim_start: Option<f64>,
+
+    /// End the --gif recording SECS after the screen last changes, cutting
+    /// any quiet time after it
+    #[cfg(feature = "gif")]
+    #[arg(long, value_name = "SECS", requires = "gif")]
+    trim_end: Option<f64>,
+
+    /// Save progress to this file at each `@ checkpoint`, so an interrupted
+    /// run can be picked up with --resume
+    #[arg(long, value_name = "PATH")]
+    checkpoint: Option<PathBuf>,
+
+    /// Also save progress to the --checkpoint file after every N top-level
+    /// commands
+    #[arg(long, value_name = "N", requires = "checkpoint")]
+    checkpoint_every: Option<usize>,
+
+    /// Resume from a checkpoint saved by an earlier run of the same scripts
+    /// with the same options, skipping the commands it already played
+    #[arg(long, value_name = "PATH")]
+    resume: Option<PathBuf>,
+
+    /// Write everything the session printed to this file once playback ends
+    #[arg(long, value_name = "PATH", conflicts_with = "attach")]
+    output: Option<PathBuf>,
+
+    /// Compare everything the session printed with this file once playback
+    /// ends, showing a diff and failing if they differ
+    #[arg(long, value_name = "PATH", conflicts_with = "attach")]
+    golden: Option<PathBuf>,
+
+    /// Write the session's output to the --golden file instead of comparing
+    #[arg(long, requires = "golden")]
+    update_golden: bool,
+
+    /// Remove escape sequences and control characters other than newlines
+    /// and tabs from the output before writing or comparing it, leaving the
+    /// plain text
+    #[arg(long)]
+    strip_escapes: bool,
+
+    /// Write the time, key and following delay of every keystroke typed to
+    /// this file as CSV, for analysing typing timing
+    #[arg(long, value_name = "PATH")]
+    keystroke_log: Option<PathBuf>,
+
+    /// Rehearse the demo: press `f` where playback feels too fast and `s`
+    /// where it feels too slow, and the spots are written to this file keyed
+    /// by command number. Your keys don't reach the session meanwhile.
+    #[arg(long, value_name = "PATH", conflicts_with = "attach")]
+    rehearse: Option<PathBuf>,
+
+    /// Hold back what you type while the script is typing, and pass it to
+    /// the session between commands, so live and scripted keys don't mix
+    #[arg(long, conflicts_with_all = ["attach", "rehearse"])]
+    typethrough: bool,
+
+    /// How control characters are written in the keystroke log: as script
+    /// key names (`<ret>`) or in caret notation (`^M`)
+    #[arg(long, value_enum, default_value_t = types::KeyNotation::Names)]
+    key_notation: types::KeyNotation,
+
+    /// Whether window title changes from programs in the session reach your
+    /// terminal; `auto` keeps them when stdout is a terminal
+    #[arg(long, value_enum, default_value_t = types::TitlePolicy::Auto)]
+    titles: types::TitlePolicy,
+
+    /// Leave the palette set by `@ theme` in place after playback instead of
+    /// restoring the terminal's defaults
+    #[arg(long)]
+    keep_theme: bool,
+
+    /// Scale typing speed so playback takes about SECS seconds in total
+    #[arg(long, value_name = "SECS")]
+    duration: Option<f64>,
+
+    /// With --duration, scale waits along with typing instead of keeping
+    /// their written length
+    #[arg(long, requires = "duration")]
+    scale_waits: bool,
+
+    /// Keep each screenful of fast-scrolling output on screen for at least
+    /// SECS seconds, holding back the rest so it can be read
+    #[arg(long, value_name = "SECS", conflicts_with = "attach")]
+    min_frame: Option<f64>,
+
+    /// Hold the session's output back for up to SECS seconds (default
+    /// 0.004) to write it to the terminal in fewer, larger writes; 0 writes
+    /// it as it is read
+    #[arg(long, value_name = "SECS", conflicts_with = "attach")]
+    flush_window: Option<f64>,
+
+    /// Send keepalive keys every SECS seconds during long waits, so idle SSH
+    /// or tmux sessions don't time out
+    #[arg(long, value_name = "SECS")]
+    keepalive: Option<f64>,
+
+    /// Keys to send as a keepalive, in script syntax
+    #[arg(long, value_name = "KEYS", default_value = "<0x00>")]
+    keepalive_keys: String,
+
+    /// Load special-key definitions from a TOML keymap file, overriding the
+    /// built-in keys and any loaded by `@ keymap`
+    #[arg(long, value_name = "PATH")]
+    keymap: Option<PathBuf>,
+
+    /// Merge runs of adjacent `$` lines into one command where that can't
+    /// change their timing, so they're typed in one go
+    #[arg(long)]
+    coalesce: bool,
+
+    /// Play only the sections tagged (with `@ tag`) with one of these
+    /// comma-separated names, keeping the settings from the rest
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    tags: Vec<String>,
+
+    /// Type unrecognised `@` lines as written instead of failing to parse
+    #[arg(long)]
+    lenient: bool,
+
+    /// Print the parsed script in canonical form instead of playing it
+    #[arg(long)]
+    expand: bool,
+
+    /// Print a best-effort shell script approximation of what the script
+    /// types instead of playing it
+    #[arg(long, conflicts_with = "expand")]
+    to_shell: bool,
+
+    /// Print the number of keystrokes, typing speed and estimated duration
+    /// instead of playing the script
+    #[arg(long, conflicts_with_all = ["expand", "to_shell"])]
+    stats: bool,
+
+    /// Seed for the random number generator (jitter, shuffle) to make
+    /// playback reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl Args {
+    // Where to render the session as a GIF, if anywhere
+    fn gif_path(&self) -> Option<&Path> {
+        #[cfg(feature = "gif")]
+        return self.gif.as_deref();
+        #[cfg(not(feature = "gif"))]
+        None
+    }
+}
+
+// Write the session's output to --output, and compare it with --golden or
+// update the golden file
+fn check_output(
+    recording: &pty::Recording,
+    output_path: Option<&Path>,
+    golden: Option<&Path>,
+    update_golden: bool,
+    strip_escapes: bool,
+) -> Result<()> {
+    let mut output = recording.output();
+    if strip_escapes {
+        output = quipu::headless::strip_ansi(&String::from_utf8_lossy(&output)).into_bytes();
+    }
+    if let Some(path) = output_path {
+        std::fs::write(path, &output)
+            .with_context(|| format!("Failed to write the output to {}", path.display()))?;
+    }
+    let Some(path) = golden else {
+        return Ok(());
+    };
+    if update_golden {
+        return std::fs::write(path, &output)
+            .with_context(|| format!("Failed to write the golden file {}", path.display()));
+    }
+    let expected = std::fs::read(path)
+        .with_context(|| format!("Failed to read the golden file {}", path.display()))?;
+    if let Some(diff) = quipu::golden::diff(
+        &String::from_utf8_lossy(&expected),
+        &String::from_utf8_lossy(&output),
+    ) {
+        eprint!("{diff}");
+        anyhow::bail!("The session's output differs from {}", path.display());
+    }
+    Ok(())
+}
+
+// The text of a script argument, and whether it was fetched from a URL
+fn read_script(path: &Path, allow_remote: bool) -> Result<(String, bool)> {
+    let arg = path.to_string_lossy();
+    if !(arg.starts_with("https://") || arg.starts_with("http://")) {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script file: {}", path.display()))?;
+        return Ok((content, false));
+    }
+    if !allow_remote {
+        anyhow::bail!("{arg} is a URL; pass --allow-remote to run scripts from URLs");
+    }
+    #[cfg(feature = "remote")]
+    return Ok((quipu::remote::fetch(&arg)?, true));
+    #[cfg(not(feature = "remote"))]
+    anyhow::bail!("Running scripts from URLs needs quipu built with the `remote` feature")
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let script_content = std::fs::read_to_string(&args.script)
-        .with_context(|| format!("Failed to read script file: {}", args.script.display()))?;
+    let mut custom = parser::CustomDirectives::default();
+    custom.set_lenient(args.lenient);
+    if let Some(allowed) = args.allow_bytes.clone() {
+        custom.set_allowed_bytes(allowed, args.disallowed_bytes);
+    }
+    let keymap = match &args.keymap {
+        Some(path) => keymap::Keymap::load(path)?,
+        None => keymap::Keymap::default(),
+    };
+    custom.set_keymap(keymap.clone());
+    let mut scripts = Vec::new();
+    for path in &args.scripts {
+        let (script_content, remote) = read_script(path, args.allow_remote)?;
+        custom.set_remote(remote);
+        let script = parser::parse_script_with(&script_content, &custom)
+            .map_err(|e| anyhow::anyhow!("Parse error in {}: {e}", path.display()))?;
+        scripts.push(script);
+    }
 
-    let script =
-        parser::parse_script(&script_content).map_err(|e| anyhow::anyhow!("Parse error: {e}"))?;
+    if args.expand {
+        for script in &scripts {
+            print!("{}", script.to_source());
+        }
+        return Ok(());
+    }
+    if args.to_shell {
+        let commands = scripts.into_iter().flat_map(|s| s.commands).collect();
+        print!("{}", types::Script { commands }.to_shell());
+        return Ok(());
+    }
 
-    // Determine shell to use (priority: CLI arg > script directive > $SHELL env > bash)
-    let cli_shell = args.shell.is_some();
-    let default_shell = args
+    let command_count: usize = scripts.iter().map(|s| s.commands.len()).sum();
+    let mut setup = types::Setup::default();
+    let mut commands = Vec::new();
+    for (path, script) in args.scripts.iter().zip(scripts) {
+        let runtime = script
+            .partition()
+            .and_then(|(script_setup, runtime)| setup.merge(script_setup).map(|()| runtime))
+            .map_err(|e| anyhow::anyhow!("Script error in {}: {e}", path.display()))?;
+        commands.extend(runtime.commands);
+    }
+    let mut script = types::Script { commands };
+    if !args.tags.is_empty() {
+        script
+            .select_tags(&args.tags)
+            .map_err(|e| anyhow::anyhow!("--tags: {e}"))?;
+    }
+    if args.coalesce {
+        script.coalesce_typing();
+    }
+
+    let estimate = playback::estimate_duration(&script.commands, &types::PlaybackConfig::default());
+    let time_scale = match args.duration {
+        Some(secs) => {
+            let target = Duration::try_from_secs_f64(secs)
+                .context("--duration must be a non-negative number of seconds")?;
+            estimate
+                .scales_for(target, args.scale_waits)
+                .map_err(|e| anyhow::anyhow!("Can't fit the script into {secs}s: {e}"))?
+        }
+        None => (1.0, 1.0),
+    };
+
+    if args.stats {
+        let estimate = estimate.scaled(time_scale.0, time_scale.1);
+        println!("Keystrokes: {}", estimate.keystrokes);
+        match estimate.wpm() {
+            Some(wpm) => println!(
+                "Typing: {:.1}s (about {wpm:.0} WPM)",
+                estimate.typing.as_secs_f64()
+            ),
+            None => println!("Typing: {:.1}s", estimate.typing.as_secs_f64()),
+        }
+        println!("Waits: {:.1}s", estimate.waits.as_secs_f64());
+        println!("Total: {:.1}s", estimate.total().as_secs_f64());
+        return Ok(());
+    }
+
+    let record = args.gif_path().is_some() || args.output.is_some() || args.golden.is_some();
+    #[cfg(feature = "gif")]
+    let (trim_start, trim_end) = {
+        let secs = |secs: Option<f64>, flag: &str| {
+            secs.map(Duration::try_from_secs_f64)
+                .transpose()
+                .with_context(|| format!("{flag} must be a non-negative number of seconds"))
+        };
+        (
+            secs(args.trim_start, "--trim-start")?,
+            secs(args.trim_end, "--trim-end")?,
+        )
+    };
+
+    // Determine shell to use (priority: CLI arg > script directive > $SHELL env > /bin/sh)
+    let shell = args
         .shell
-        .or_else(|| std::env::var("SHELL").ok())
-        .unwrap_or_else(|| "bash".to_string());
-
-    // Check if script specifies a shell or size (must come before any Type commands)
-    let mut shell = default_shell;
-    let mut cols = 80u16;
-    let mut rows = 24u16;
-
-    for command in &script.commands {
-        match command {
-            types::Command::SetShell(s) => {
-                if !cli_shell {
-                    shell = s.clone();
-                }
-            }
-            types::Command::SetSize(c, r) => {
-                cols = *c;
-                rows = *r;
-            }
-            types::Command::Type(_) => {
-                // Stop looking once we hit a Type command
-                break;
-            }
-            _ => {}
+        .or(setup.shell)
+        .unwrap_or_else(pty::default_shell);
+    // Fill the viewer's terminal, unless the session is recorded, which
+    // should come out the same whoever runs it
+    let (cols, rows) = setup.size.unwrap_or_else(|| {
+        if record {
+            pty::DEFAULT_SIZE
+        } else {
+            pty::host_size()
+        }
+    });
+    let strip_titles = match args.titles {
+        types::TitlePolicy::Auto => !std::io::stdout().is_terminal(),
+        types::TitlePolicy::Keep => false,
+        types::TitlePolicy::Strip => true,
+    };
+    // The sentinel is read back from the session's output, which an attached
+    // terminal doesn't give us
+    let ready_sentinel = setup.ready_sentinel.unwrap_or(false);
+    if ready_sentinel && args.attach.is_some() {
+        anyhow::bail!(
+            "@ready_sentinel needs a shell started by quipu, so can't be used with --attach"
+        );
+    }
+    // Likewise for telling when the shell has settled after prewarming
+    let prewarm = setup.prewarm.unwrap_or(false);
+    if prewarm && args.attach.is_some() {
+        anyhow::bail!("@prewarm needs a shell started by quipu, so can't be used with --attach");
+    }
+    if setup.umask.is_some() {
+        if !cfg!(unix) {
+            anyhow::bail!("@umask is only supported on Unix");
+        }
+        if args.attach.is_some() {
+            anyhow::bail!("@umask needs a shell started by quipu, so can't be used with --attach");
         }
     }
+    let min_frame = args
+        .min_frame
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .context("--min-frame must be a non-negative number of seconds")?;
+    let checkpoint = match &args.resume {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the checkpoint {}", path.display()))?;
+            playback::Checkpoint::parse(&text)
+                .map_err(|e| anyhow::anyhow!("Invalid checkpoint {}: {e}", path.display()))?
+        }
+        None => playback::Checkpoint::default(),
+    };
+    let flush_window = args
+        .flush_window
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .context("--flush-window must be a non-negative number of seconds")?
+        .unwrap_or(pty::DEFAULT_FLUSH_WINDOW);
+    let prime = match &args.prime {
+        Some(path) => std::fs::read(path)
+            .with_context(|| format!("Failed to read primed input: {}", path.display()))?,
+        None => Vec::new(),
+    };
+    let pty_options = pty::PtyOptions {
+        env: setup.env,
+        strip_titles,
+        prime,
+        record,
+        ctrl_c: args.ctrl_c,
+        umask: setup.umask,
+        min_frame,
+        flush_window,
+        ..pty::PtyOptions::default()
+    };
 
     if !args.quiet {
-        println!("Parsed {} commands", script.commands.len());
-        println!("Using shell: {shell}");
+        println!("Parsed {command_count} commands");
+        match &args.attach {
+            Some(path) => println!("Attaching to: {}", path.display()),
+            None => println!("Using shell: {shell}"),
+        }
         println!("Terminal size: {cols}x{rows}");
         println!("Starting playback in 1 second...");
     }
     let running = Arc::new(AtomicBool::new(true));
 
-    let pty = pty::PtyManager::new(&shell, cols, rows, running.clone())
-        .context("Failed to create PTY")?;
+    let pty = if let Some(path) = &args.attach {
+        pty::PtyManager::attach(path, cols, rows, false)?
+    } else {
+        let pty = pty::PtyManager::new(&shell, cols, rows, &pty_options, running.clone())
+            .context("Failed to create PTY")?;
 
-    // Give the freshly spawned shell time to print its prompt and put its line
-    // editor into raw mode before playback types anything. Without this settle,
-    // the first keystroke races shell startup and is echoed by the PTY's
-    // canonical line discipline at column 0, stranding a stray character before
-    // the prompt.
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        // Give the freshly spawned shell time to print its prompt and put its line
+        // editor into raw mode before playback types anything. Without this settle,
+        // the first keystroke races shell startup and is echoed by the PTY's
+        // canonical line discipline at column 0, stranding a stray character before
+        // the prompt. The ready sentinel waits for the shell itself instead.
+        if !ready_sentinel {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+        pty
+    };
 
+    let recording = pty.recording();
     let mut engine = playback::PlaybackEngine::new(pty, running.clone())
         .context("Failed to create playback engine")?;
+    // A --seed argument takes priority over the script's @seed
+    if let Some(seed) = args.seed.or(setup.seed) {
+        engine.set_seed(seed);
+    }
+    engine.set_ready_sentinel(ready_sentinel);
+    engine.set_prewarm(prewarm);
+    engine.set_ending(args.ending);
+    engine.set_write_error_policy(args.on_write_error);
+    if let Some(secs) = args.exit_within {
+        let budget = Duration::try_from_secs_f64(secs)
+            .context("--exit-within must be a non-negative number of seconds")?;
+        engine.set_exit_budget(Some(budget));
+    }
+    engine.set_keep_theme(args.keep_theme);
+    engine.set_time_scale(time_scale.0, time_scale.1);
+    if let Some(secs) = args.keepalive {
+        let keys = parser::parse_type_content_with(&args.keepalive_keys, &keymap)
+            .map_err(|e| anyhow::anyhow!("Invalid --keepalive-keys: {e}"))?;
+        let interval = Duration::try_from_secs_f64(secs)
+            .context("--keepalive must be a non-negative number of seconds")?;
+        engine.set_keepalive(Some(types::Keepalive { interval, keys }));
+    }
+    if let Some(path) = args.subtitles {
+        engine.set_subtitle_path(path);
+    }
+    if let Some(path) = args.markers {
+        engine.set_marker_path(path);
+    }
+    if let Some(path) = args.checkpoint {
+        engine.set_checkpoint_path(path, args.checkpoint_every);
+    }
+    engine.set_typethrough(args.typethrough);
+    if let Some(path) = args.rehearse {
+        engine.set_rehearsal_path(path);
+    }
+    if let Some(path) = args.keystroke_log {
+        engine.set_keystroke_log_path(path);
+        engine.set_key_notation(args.key_notation);
+    }
 
     engine
-        .execute(script)
+        .execute_from(script, &checkpoint)
         .await
         .context("Failed to execute script")?;
 
+    let drift = engine.timing_drift();
+    let elapsed = engine.elapsed();
+    let exit_time = engine.exit_time();
+
     // Drop the engine and PTY explicitly to clean up and restore terminal state
     // before printing completion message
     drop(engine);
 
+    if let Some(recording) = &recording {
+        check_output(
+            recording,
+            args.output.as_deref(),
+            args.golden.as_deref(),
+            args.update_golden,
+            args.strip_escapes,
+        )?;
+    }
+
+    #[cfg(feature = "gif")]
+    if let (Some(path), Some(mut recording)) = (&args.gif, recording) {
+        if trim_start.is_some() || trim_end.is_some() {
+            recording = recording.trimmed(trim_start.unwrap_or(Duration::MAX));
+        }
+        let hold = trim_end.unwrap_or(quipu::render::DEFAULT_HOLD);
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        quipu::render::render_gif(
+            &recording,
+            args.gif_fps,
+            hold,
+            std::io::BufWriter::new(file),
+        )
+        .with_context(|| format!("Failed to render the session to {}", path.display()))?;
+    }
+
     if !args.quiet {
         if running.load(Ordering::SeqCst) {
             println!("\nPlayback complete!");
+            println!("Timing drift: {:.3}s", drift.as_secs_f64());
+            if let Some(target) = args.duration {
+                println!("Duration: {:.1}s (target {target}s)", elapsed.as_secs_f64());
+            }
+            if let Some(exit_time) = exit_time {
+                println!("Shell exited after {:.2}s", exit_time.as_secs_f64());
+            }
         } else {
             println!("\nPlayback interrupted");
         }
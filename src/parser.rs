@@ -17,37 +17,75 @@
 //!
 //! Parses scripts with the format:
 //! - @ directives (speed, jitter, wait)
+//! - @ block markers (shuffle ... end)
 //! - # comments
 //! - $ typing lines
 
 use nom::{
     IResult, Parser,
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::{char, not_line_ending, space0},
-    combinator::{map, value},
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{char, digit1, not_line_ending, space0},
+    combinator::{map, opt, value},
+    multi::separated_list1,
+    sequence::preceded,
 };
+use std::borrow::Cow;
 use std::time::Duration;
 
-use crate::types::{Command, Script};
+use crate::keymap::Keymap;
+use crate::pty::SESSION_TERM;
+use crate::types::{
+    BackspaceKey, ByteCheck, Command, JitterParams, KeyNotation, LongLines, Script, SyncMode,
+    TypoChars, ValueRange, shell_quote,
+};
+
+fn non_negative(text: &str) -> Option<f64> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value >= 0.0)
+}
+
+// The rest of the line up to an optional trailing comment, with what follows
+fn value_text(input: &str) -> (&str, &str) {
+    let end = input.find('#').unwrap_or(input.len());
+    (input[..end].trim(), &input[end..])
+}
+
+// Failure (not Error) so alt() aborts instead of trying other directives
+fn invalid_value(text: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Failure(nom::error::Error::new(text, nom::error::ErrorKind::Verify))
+}
 
+// A non-negative number making up the rest of the line, up to an optional
+// trailing comment, so `0.2.3` or `0.2abc` is rejected as a whole
 fn parse_float(input: &str) -> IResult<&str, f64> {
-    let (rest, value) = nom::number::complete::double(input)?;
-    if !value.is_finite() || value < 0.0 {
-        // Failure (not Error) so alt() aborts instead of trying other directives
-        return Err(nom::Err::Failure(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Verify,
-        )));
-    }
-    Ok((rest, value))
+    let (text, rest) = value_text(input);
+    non_negative(text)
+        .map(|value| (rest, value))
+        .ok_or_else(|| invalid_value(text))
+}
+
+// Like parse_float, but also accepts a `MIN..MAX` range
+fn parse_float_range(input: &str) -> IResult<&str, ValueRange> {
+    let (text, rest) = value_text(input);
+    let range = match text.split_once("..") {
+        Some((min, max)) => non_negative(min.trim())
+            .zip(non_negative(max.trim()))
+            .filter(|(min, max)| min <= max)
+            .map(|(min, max)| ValueRange { min, max }),
+        None => non_negative(text).map(ValueRange::from),
+    };
+    range
+        .map(|range| (rest, range))
+        .ok_or_else(|| invalid_value(text))
 }
 
 fn parse_speed(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
     let (input, _) = tag("speed:")(input)?;
-    let (input, value) = parse_float(input)?;
+    let (input, value) = parse_float_range(input)?;
     Ok((input, Command::SetSpeed(value)))
 }
 
@@ -55,10 +93,174 @@ fn parse_jitter(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
     let (input, _) = tag("jitter:")(input)?;
-    let (input, value) = parse_float(input)?;
+    let (input, value) = parse_float_range(input)?;
     Ok((input, Command::SetJitter(value)))
 }
 
+fn parse_jitter_params(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("jitter_params:")(input)?;
+    let (text, rest) = value_text(input);
+    if text == "off" {
+        return Ok((rest, Command::SetJitterParams(None)));
+    }
+    let params = text
+        .split_once(':')
+        .and_then(|(mean, stddev)| non_negative(mean.trim()).zip(non_negative(stddev.trim())))
+        .map(|(mean, stddev)| JitterParams { mean, stddev })
+        .ok_or_else(|| invalid_value(text))?;
+    Ok((rest, Command::SetJitterParams(Some(params))))
+}
+
+fn parse_on_off(input: &str) -> IResult<&str, bool> {
+    alt((value(true, tag("on")), value(false, tag("off")))).parse(input)
+}
+
+fn parse_sync(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("sync:")(input)?;
+    let (input, mode) = alt((
+        value(SyncMode::Prompt, tag("osc133")),
+        map(parse_on_off, |on| {
+            if on {
+                SyncMode::Schedule
+            } else {
+                SyncMode::Off
+            }
+        }),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetSync(mode)))
+}
+
+fn parse_autoenter(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("autoenter:")(input)?;
+    let (input, on) = parse_on_off(input)?;
+    Ok((input, Command::SetAutoEnter(on)))
+}
+
+fn parse_ready_sentinel(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("ready_sentinel:")(input)?;
+    let (input, on) = parse_on_off(input)?;
+    Ok((input, Command::SetReadySentinel(on)))
+}
+
+fn parse_prewarm(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("prewarm:")(input)?;
+    let (input, on) = parse_on_off(input)?;
+    Ok((input, Command::SetPrewarm(on)))
+}
+
+fn parse_backspace(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("backspace:")(input)?;
+    let (input, key) = alt((
+        value(BackspaceKey::Del, tag("del")),
+        value(BackspaceKey::Bs, tag("bs")),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetBackspace(key)))
+}
+
+fn parse_word_typos(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("word_typos:")(input)?;
+    let (text, _) = value_text(input);
+    let (input, rate) = parse_float(input)?;
+    if rate > 1.0 {
+        return Err(invalid_value(text));
+    }
+    Ok((input, Command::SetWordTypos(rate)))
+}
+
+// Never allowed in `@ typo_chars`, since a slip on one of them could change
+// what a shell command does
+const SHELL_SIGNIFICANT: &str = "'\"`|&;<>()$*?[]{}!#~\\";
+
+// Characters and FIRST-LAST ranges, run together as in `a-zA-Z0-9`. A `-` at
+// the start or end stands for itself.
+fn typo_chars(text: &str) -> Option<TypoChars> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let range = match chars.get(i..i + 3) {
+            Some(&[first, '-', last]) if first != '-' => {
+                i += 3;
+                (first, last)
+            }
+            _ => {
+                i += 1;
+                (chars[i - 1], chars[i - 1])
+            }
+        };
+        ranges.push(range);
+    }
+    let safe = |c: char| !c.is_whitespace() && !c.is_control() && !SHELL_SIGNIFICANT.contains(c);
+    let valid = !ranges.is_empty()
+        && ranges
+            .iter()
+            .all(|&(first, last)| first <= last && (first..=last).all(safe));
+    valid.then_some(TypoChars(ranges))
+}
+
+fn parse_typo_chars(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("typo_chars:")(input)?;
+    let (text, rest) = value_text(input);
+    let chars = typo_chars(text).ok_or_else(|| invalid_value(text))?;
+    Ok((rest, Command::SetTypoChars(chars)))
+}
+
+fn parse_word_pause(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("word_pause:")(input)?;
+    let (input, value) = parse_float(input)?;
+    Ok((input, Command::SetWordPause(Duration::from_secs_f64(value))))
+}
+
+fn parse_long_lines(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("long_lines:")(input)?;
+    let (text, rest) = value_text(input);
+    if text == "off" {
+        return Ok((rest, Command::SetLongLines(None)));
+    }
+    let (chars, max_speedup) = match text.split_once(':') {
+        Some((chars, max_speedup)) => (chars.trim(), Some(max_speedup.trim())),
+        None => (text, None),
+    };
+    let threshold = chars
+        .parse()
+        .ok()
+        .filter(|chars| *chars > 0)
+        .ok_or_else(|| invalid_value(text))?;
+    let max_speedup = match max_speedup {
+        Some(max_speedup) => non_negative(max_speedup)
+            .filter(|max_speedup| *max_speedup >= 1.0)
+            .ok_or_else(|| invalid_value(text))?,
+        None => LongLines::DEFAULT_MAX_SPEEDUP,
+    };
+    let long_lines = LongLines {
+        threshold,
+        max_speedup,
+    };
+    Ok((rest, Command::SetLongLines(Some(long_lines))))
+}
+
 fn parse_wait(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
@@ -67,6 +269,20 @@ fn parse_wait(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::Wait(Duration::from_secs_f64(value))))
 }
 
+fn parse_wait_until(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("wait_until:")(input)?;
+    let (text, rest) = value_text(input);
+    let (time, next_day) = match text.strip_suffix(":next_day") {
+        Some(time) => (time.trim(), true),
+        None => (text, false),
+    };
+    let time =
+        chrono::NaiveTime::parse_from_str(time, "%H:%M:%S").map_err(|_| invalid_value(text))?;
+    Ok((rest, Command::WaitUntil(time, next_day)))
+}
+
 fn parse_shell(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
@@ -75,16 +291,152 @@ fn parse_shell(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::SetShell(shell.trim().to_string())))
 }
 
+// Largest accepted terminal dimension; anything beyond is certainly a typo
+const MAX_DIMENSION: u16 = 10000;
+
+fn parse_dimension(input: &str) -> IResult<&str, u16> {
+    let (rest, digits) = digit1(input)?;
+    match digits.parse::<u16>() {
+        Ok(n) if (1..=MAX_DIMENSION).contains(&n) => Ok((rest, n)),
+        _ => Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+// Named sizes for `@ size` and `@ resize`. The HD sizes fill a 16:9 frame
+// with 8x16 pixel cells.
+const SIZE_PRESETS: &[(&str, u16, u16)] = &[
+    ("vt100", 80, 24),
+    ("vga", 80, 25),
+    ("hd", 160, 45),
+    ("fullhd", 240, 67),
+];
+
+// COLS:ROWS, or the name of a preset
+fn parse_dimensions(input: &str) -> IResult<&str, (u16, u16)> {
+    if input.starts_with(|c: char| c.is_ascii_digit()) {
+        let (input, cols) = parse_dimension(input)?;
+        let (input, _) = char(':')(input)?;
+        let (input, rows) = parse_dimension(input)?;
+        return Ok((input, (cols, rows)));
+    }
+    let (rest, name) = take_while1(|c: char| c.is_ascii_alphanumeric())(input)?;
+    match SIZE_PRESETS.iter().find(|(preset, _, _)| *preset == name) {
+        Some(&(_, cols, rows)) => Ok((rest, (cols, rows))),
+        None => Err(invalid_value(name)),
+    }
+}
+
 fn parse_size(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
     let (input, _) = tag("size:")(input)?;
-    let (input, cols) = nom::character::complete::u16(input)?;
-    let (input, _) = char(':')(input)?;
-    let (input, rows) = nom::character::complete::u16(input)?;
+    let (input, (cols, rows)) = parse_dimensions(input)?;
     Ok((input, Command::SetSize(cols, rows)))
 }
 
+fn parse_env(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("env:")(input)?;
+    let (input, name) =
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_').parse(input)?;
+    let (input, _) = char('=')(input)?;
+    let (input, value) = not_line_ending(input)?;
+    Ok((
+        input,
+        Command::SetEnv(name.to_string(), value.trim().to_string()),
+    ))
+}
+
+fn parse_seed(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("seed:")(input)?;
+    let (text, rest) = value_text(input);
+    match text.parse::<u64>() {
+        Ok(seed) => Ok((rest, Command::SetSeed(seed))),
+        Err(_) => Err(invalid_value(text)),
+    }
+}
+
+// An octal mask, as `umask` takes it
+fn parse_umask(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("umask:")(input)?;
+    let (text, rest) = value_text(input);
+    if text.is_empty() || text.len() > 4 {
+        return Err(invalid_value(text));
+    }
+    match u32::from_str_radix(text, 8) {
+        Ok(mask) if mask <= 0o777 => Ok((rest, Command::SetUmask(mask))),
+        _ => Err(invalid_value(text)),
+    }
+}
+
+fn parse_expect_exit(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect_exit:")(input)?;
+    let (text, rest) = value_text(input);
+    match text.parse::<u32>() {
+        Ok(code) => Ok((rest, Command::ExpectExit(code))),
+        Err(_) => Err(invalid_value(text)),
+    }
+}
+
+// Shorthand for setting the variables a prompt may show, so recordings made on
+// different machines look alike
+fn parse_identity(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, var) = alt((
+        value("HOSTNAME", tag("hostname:")),
+        value("USER", tag("user:")),
+    ))
+    .parse(input)?;
+    let (input, name) = not_line_ending(input)?;
+    Ok((
+        input,
+        Command::SetEnv(var.to_string(), name.trim().to_string()),
+    ))
+}
+
+// Returns the raw text; expanded in parse_script like type content
+fn parse_retype(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("retype:")(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Retype(text.trim().to_string())))
+}
+
+fn parse_overwrite(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("overwrite:")(input)?;
+    let (rest, spec) = not_line_ending(input)?;
+    let overwrite = spec.split_once(':').and_then(|(col, text)| {
+        let col = col.trim().parse::<usize>().ok().filter(|col| *col > 0)?;
+        Some(Command::Overwrite(col, text.to_string()))
+    });
+    overwrite
+        .map(|command| (rest, command))
+        .ok_or_else(|| invalid_value(spec))
+}
+
+fn parse_secret(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("secret:")(input)?;
+    let (input, _) = opt(char('$')).parse(input)?;
+    let (input, var) = take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_').parse(input)?;
+    Ok((input, Command::TypeSecret(var.to_string())))
+}
+
 fn parse_capture(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
@@ -93,143 +445,649 @@ fn parse_capture(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::Capture(path.trim().into())))
 }
 
-fn parse_directive(input: &str) -> IResult<&str, Command> {
-    alt((
-        parse_speed,
-        parse_jitter,
-        parse_wait,
-        parse_shell,
-        parse_size,
-        parse_capture,
-    ))
-    .parse(input)
+fn parse_capture_reset(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("capture_reset")(input)?;
+    Ok((input, Command::ResetCapture))
 }
 
-fn parse_comment(input: &str) -> IResult<&str, ()> {
-    let (input, _) = char('#')(input)?;
-    let (input, _) = not_line_ending(input)?;
-    Ok((input, ()))
+fn parse_push(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("push")(input)?;
+    Ok((input, Command::PushConfig))
 }
 
-fn parse_key_spec(input: &str) -> IResult<&str, &str> {
-    let (input, _) = char('<')(input)?;
-    let (input, key_spec) = take_until(">")(input)?;
-    let (input, _) = char('>')(input)?;
-    Ok((input, key_spec))
+fn parse_pop(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("pop")(input)?;
+    Ok((input, Command::PopConfig))
 }
 
-fn base_key_seq(key: &str) -> Option<&'static str> {
-    Some(match key {
-        "esc" => "\x1b",
-        "space" => " ",
-        "ret" | "return" | "enter" => "\r",
-        "tab" => "\t",
-        "backspace" | "bs" => "\x7f",
-        "F1" => "\x1bOP",
-        "F2" => "\x1bOQ",
-        "F3" => "\x1bOR",
-        "F4" => "\x1bOS",
-        "F5" => "\x1b[15~",
-        "F6" => "\x1b[17~",
-        "F7" => "\x1b[18~",
-        "F8" => "\x1b[19~",
-        "F9" => "\x1b[20~",
-        "F10" => "\x1b[21~",
-        "F11" => "\x1b[23~",
-        "F12" => "\x1b[24~",
-        "up" => "\x1b[A",
-        "down" => "\x1b[B",
-        "right" => "\x1b[C",
-        "left" => "\x1b[D",
-        "home" => "\x1b[H",
-        "end" => "\x1b[F",
-        "pageup" | "pgup" => "\x1b[5~",
-        "pagedown" | "pgdn" => "\x1b[6~",
-        "insert" | "ins" => "\x1b[2~",
-        "delete" | "del" => "\x1b[3~",
-        _ => return None,
-    })
+fn parse_flush(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("flush")(input)?;
+    Ok((input, Command::Flush))
 }
 
-fn resolve_key(spec: &str) -> Result<String, String> {
-    if let Some(seq) = base_key_seq(spec) {
-        return Ok(seq.to_string());
-    }
-    if spec.contains('-') {
-        return resolve_modifier_combo(spec);
-    }
-    Err(format!(
-        "unknown key <{spec}> (escape literal angle brackets as \\< and \\>)"
-    ))
+fn parse_checkpoint(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("checkpoint")(input)?;
+    Ok((input, Command::Checkpoint))
 }
 
-// Control code for Ctrl-<key>; Shift makes no difference at the byte level
-fn ctrl_code(key: &str, spec: &str) -> Result<String, String> {
-    let mut chars = key.chars();
-    if let (Some(ch), None) = (chars.next(), chars.next()) {
-        let ch = ch.to_ascii_lowercase();
-        return match ch {
-            // Ctrl-letter maps to ASCII 1-26
-            'a'..='z' => Ok(char::from(ch as u8 - b'a' + 1).to_string()),
-            ' ' => Ok("\x00".to_string()),
-            '[' => Ok("\x1b".to_string()), // Ctrl-[ maps to ESC
-            ']' => Ok("\x1d".to_string()),
-            '\\' => Ok("\x1c".to_string()),
-            _ => Err(format!("<{spec}> has no control code")),
-        };
-    }
-    if key == "space" {
-        return Ok("\x00".to_string());
-    }
-    Err(format!("<{spec}> has no control code"))
+// Returns the raw key text; expanded in parse_script like type content
+fn parse_suggestion_key(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("suggestion_key:")(input)?;
+    let (input, key) = not_line_ending(input)?;
+    Ok((input, Command::SetSuggestionKey(key.trim().to_string())))
 }
 
-fn resolve_modifier_combo(spec: &str) -> Result<String, String> {
-    let parts: Vec<&str> = spec.split('-').collect();
-    let (modifiers, key) = parts.split_at(parts.len() - 1);
-    let key = key[0];
+// Returns the raw key text, if any; expanded in parse_script like type content
+fn parse_wait_key(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("wait_key")(input)?;
+    let (input, key) = opt(preceded(char(':'), not_line_ending)).parse(input)?;
+    let key = key.map(str::trim).filter(|key| !key.is_empty());
+    Ok((input, Command::WaitKey(key.map(str::to_string))))
+}
 
-    let mut has_ctrl = false;
-    let mut has_alt = false;
-    let mut has_shift = false;
+// Shorthand for `@ wait_key:<ret>`
+fn parse_wait_enter(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("wait_enter")(input)?;
+    Ok((input, Command::WaitKey(Some("\r".to_string()))))
+}
 
-    for m in modifiers {
-        match *m {
-            "C" | "c" | "Ctrl" | "ctrl" => has_ctrl = true,
-            "A" | "a" | "Alt" | "alt" | "M" | "m" | "Meta" | "meta" => has_alt = true,
-            "S" | "s" | "Shift" | "shift" => has_shift = true,
-            _ => return Err(format!("unknown modifier '{m}' in <{spec}>")),
-        }
+// Returns the raw query text; expanded in parse_script like type content. The
+// timeout follows the last ':'.
+fn parse_wait_response(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("wait_response:")(input)?;
+    let (spec, rest) = value_text(input);
+    let Some((query, timeout)) = spec.rsplit_once(':') else {
+        return Err(invalid_value(spec));
+    };
+    let (_, secs) = parse_float(timeout)?;
+    if query.trim().is_empty() {
+        return Err(invalid_value(spec));
     }
+    let command = Command::WaitResponse(query.trim().to_string(), Duration::from_secs_f64(secs));
+    Ok((rest, command))
+}
 
-    let is_single_char = key.chars().count() == 1;
+fn parse_accept_suggestion(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    value(Command::AcceptSuggestion, tag("accept_suggestion")).parse(input)
+}
 
-    if has_ctrl {
-        let code = if is_single_char || key == "space" {
-            ctrl_code(key, spec)?
-        } else if has_alt {
-            // Ctrl-Alt-<special>: fall back to Alt behaviour
-            base_key_seq(key)
-                .ok_or_else(|| format!("unknown key '{key}' in <{spec}>"))?
-                .to_string()
-        } else {
-            return Err(format!("<{spec}> has no control code"));
-        };
-        // Alt prepends ESC
-        return Ok(if has_alt { format!("\x1b{code}") } else { code });
-    }
+fn parse_echo(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("echo:")(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Echo(text.trim().to_string())))
+}
 
-    if has_alt {
-        let base = if let Some(seq) = base_key_seq(key) {
-            seq.to_string()
-        } else if is_single_char {
-            if has_shift {
-                key.to_uppercase()
-            } else {
-                key.to_string()
-            }
+fn parse_reveal(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("reveal:")(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Reveal(text.trim().to_string())))
+}
+
+fn parse_subtitle(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    // `@ caption` is another name for it
+    let (input, _) = alt((tag("subtitle:"), tag("caption:"))).parse(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Subtitle(text.trim().to_string())))
+}
+
+fn parse_verify(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("verify:")(input)?;
+    let (input, command) = not_line_ending(input)?;
+    if command.trim().is_empty() {
+        return Err(invalid_value(command));
+    }
+    Ok((input, Command::Verify(command.trim().to_string())))
+}
+
+fn parse_set_title(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("set_title:")(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::SetTitle(text.trim().to_string())))
+}
+
+// `@ resize:COLS:ROWS`, with an optional `:SECS` pause for the program to
+// redraw at the new size
+fn parse_resize(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("resize:")(input)?;
+    let (input, (cols, rows)) = parse_dimensions(input)?;
+    let (input, pause) = opt(preceded(char(':'), parse_float)).parse(input)?;
+    let pause = pause.map_or(Duration::ZERO, Duration::from_secs_f64);
+    Ok((input, Command::Resize(cols, rows, pause)))
+}
+
+fn parse_theme(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("theme:")(input)?;
+    let (input, path) = not_line_ending(input)?;
+    Ok((input, Command::Theme(path.trim().into())))
+}
+
+// `on`, `off` or a terminal type, checked when the script is parsed
+fn parse_terminfo(input: &str) -> IResult<&str, String> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("terminfo:")(input)?;
+    let (term, rest) = value_text(input);
+    if term.is_empty() || term.contains(char::is_whitespace) {
+        return Err(invalid_value(term));
+    }
+    Ok((rest, term.to_string()))
+}
+
+// Applied while parsing, so it becomes a Line rather than a Command
+fn parse_keymap(input: &str) -> IResult<&str, String> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("keymap:")(input)?;
+    let (input, path) = not_line_ending(input)?;
+    if path.trim().is_empty() {
+        return Err(invalid_value(path));
+    }
+    Ok((input, path.trim().to_string()))
+}
+
+// Expanded on the host while parsing, so it becomes a Line rather than a
+// Command. `glob_optional` lets the pattern match nothing.
+fn parse_glob(input: &str) -> IResult<&str, (String, bool)> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, optional) = alt((
+        value(true, tag("glob_optional:")),
+        value(false, tag("glob:")),
+    ))
+    .parse(input)?;
+    let (input, pattern) = not_line_ending(input)?;
+    if pattern.trim().is_empty() {
+        return Err(invalid_value(pattern));
+    }
+    Ok((input, (pattern.trim().to_string(), optional)))
+}
+
+// The paths matching a glob pattern, in order and quoted for the shell where
+// needed, separated by spaces
+fn expand_glob(pattern: &str, optional: bool) -> Result<String, String> {
+    let paths =
+        glob::glob(pattern).map_err(|e| format!("invalid glob pattern {pattern:?}: {e}"))?;
+    let mut words = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| format!("can't expand {pattern:?}: {e}"))?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| format!("{} is not valid UTF-8", path.display()))?;
+        words.push(shell_quote(path));
+    }
+    if words.is_empty() && !optional {
+        return Err(format!("{pattern:?} matches no paths"));
+    }
+    Ok(words.join(" "))
+}
+
+fn parse_reset_theme(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("reset_theme")(input)?;
+    Ok((input, Command::ResetTheme))
+}
+
+// Directives that set the pace of typing and the keys it sends
+fn parse_pacing_directive(input: &str) -> IResult<&str, Command> {
+    alt((
+        parse_speed,
+        parse_jitter,
+        parse_jitter_params,
+        parse_sync,
+        parse_word_typos,
+        parse_typo_chars,
+        parse_word_pause,
+        parse_long_lines,
+        parse_backspace,
+        parse_wait,
+        parse_wait_until,
+        parse_autoenter,
+        parse_push,
+        parse_pop,
+    ))
+    .parse(input)
+}
+
+// Directives that shape the session and what is typed into it
+fn parse_session_directive(input: &str) -> IResult<&str, Command> {
+    alt((
+        parse_shell,
+        parse_size,
+        parse_env,
+        parse_identity,
+        parse_seed,
+        parse_umask,
+        parse_expect_exit,
+        parse_secret,
+        parse_retype,
+        parse_overwrite,
+        parse_flush,
+        parse_checkpoint,
+        parse_suggestion_key,
+        parse_accept_suggestion,
+        parse_resize,
+        parse_wait_key,
+        parse_wait_enter,
+        parse_wait_response,
+    ))
+    .parse(input)
+}
+
+// Directives that act on the host terminal or the recording, bypassing the
+// session
+fn parse_presentation_directive(input: &str) -> IResult<&str, Command> {
+    alt((
+        parse_capture,
+        parse_capture_reset,
+        parse_echo,
+        parse_reveal,
+        parse_subtitle,
+        parse_set_title,
+        parse_theme,
+        parse_reset_theme,
+        parse_verify,
+        parse_ready_sentinel,
+        parse_prewarm,
+    ))
+    .parse(input)
+}
+
+fn parse_directive(input: &str) -> IResult<&str, Command> {
+    alt((
+        parse_pacing_directive,
+        parse_session_directive,
+        parse_presentation_directive,
+    ))
+    .parse(input)
+}
+
+// Opening marker of a block of commands, closed by `@ end`
+#[derive(Debug, Clone, PartialEq)]
+enum Block {
+    Shuffle,
+    NoEcho,
+    // Kept only when running on one of the listed OSes (std::env::consts::OS
+    // values), with an optional `@ else`
+    IfOs(Vec<String>),
+    // A section for `--tags` to pick out
+    Tag(Vec<String>),
+    // `$` lines sent together as one bracketed paste
+    Paste,
+    // Commands with the viewer typing in between
+    Live,
+}
+
+impl Block {
+    fn name(&self) -> &'static str {
+        match self {
+            Block::Shuffle => "shuffle",
+            Block::NoEcho => "noecho",
+            Block::IfOs(_) => "if_os",
+            Block::Tag(_) => "tag",
+            Block::Paste => "paste",
+            Block::Live => "live",
+        }
+    }
+}
+
+const KNOWN_OSES: &[&str] = &[
+    "linux",
+    "macos",
+    "windows",
+    "freebsd",
+    "netbsd",
+    "openbsd",
+    "dragonfly",
+    "solaris",
+    "illumos",
+    "android",
+    "ios",
+];
+
+fn parse_os_name(input: &str) -> IResult<&str, String> {
+    let (rest, name) = take_while1(|c: char| c.is_ascii_alphanumeric()).parse(input)?;
+    if !KNOWN_OSES.contains(&name) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, name.to_string()))
+}
+
+fn parse_if_os(input: &str) -> IResult<&str, Block> {
+    let (input, _) = tag("if_os:")(input)?;
+    let (input, names) = separated_list1(char('|'), parse_os_name).parse(input)?;
+    Ok((input, Block::IfOs(names)))
+}
+
+fn parse_tag(input: &str) -> IResult<&str, Block> {
+    let (input, _) = tag("tag:")(input)?;
+    let (text, rest) = value_text(input);
+    let names: Vec<String> = text
+        .split('|')
+        .map(|name| name.trim().to_string())
+        .collect();
+    let valid = |name: &String| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    if !names.iter().all(valid) {
+        return Err(invalid_value(text));
+    }
+    Ok((rest, Block::Tag(names)))
+}
+
+fn parse_block_start(input: &str) -> IResult<&str, Block> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    alt((
+        value(Block::Shuffle, tag("shuffle")),
+        value(Block::NoEcho, tag("noecho")),
+        value(Block::Paste, tag("paste")),
+        value(Block::Live, tag("live")),
+        parse_if_os,
+        parse_tag,
+    ))
+    .parse(input)
+}
+
+fn parse_block_else(input: &str) -> IResult<&str, ()> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    value((), tag("else")).parse(input)
+}
+
+fn parse_block_end(input: &str) -> IResult<&str, ()> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    value((), tag("end")).parse(input)
+}
+
+fn parse_comment(input: &str) -> IResult<&str, ()> {
+    let (input, _) = char('#')(input)?;
+    let (input, _) = not_line_ending(input)?;
+    Ok((input, ()))
+}
+
+fn parse_key_spec(input: &str) -> IResult<&str, &str> {
+    let (input, _) = char('<')(input)?;
+    let (input, key_spec) = take_until(">")(input)?;
+    let (input, _) = char('>')(input)?;
+    Ok((input, key_spec))
+}
+
+// The nested form of a modifier combination, `<C-<up>>`, a common slip for the
+// canonical `<C-up>`. Returns the canonical spec.
+fn parse_nested_key_spec(input: &str) -> IResult<&str, String> {
+    let not_bracket = |c: char| c != '<' && c != '>';
+    let (input, _) = char('<')(input)?;
+    let (input, modifiers) = take_while1(not_bracket)(input)?;
+    if !modifiers.ends_with('-') {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let (input, _) = char('<')(input)?;
+    let (input, key) = take_while1(not_bracket)(input)?;
+    let (input, _) = tag(">>")(input)?;
+    Ok((input, format!("{modifiers}{key}")))
+}
+
+fn base_key_seq(key: &str) -> Option<&'static str> {
+    Some(match key {
+        "esc" => "\x1b",
+        "space" => " ",
+        "ret" | "return" | "enter" => "\r",
+        "tab" => "\t",
+        "backspace" | "bs" => "\x7f",
+        "F1" => "\x1bOP",
+        "F2" => "\x1bOQ",
+        "F3" => "\x1bOR",
+        "F4" => "\x1bOS",
+        "F5" => "\x1b[15~",
+        "F6" => "\x1b[17~",
+        "F7" => "\x1b[18~",
+        "F8" => "\x1b[19~",
+        "F9" => "\x1b[20~",
+        "F10" => "\x1b[21~",
+        "F11" => "\x1b[23~",
+        "F12" => "\x1b[24~",
+        "up" => "\x1b[A",
+        "down" => "\x1b[B",
+        "right" => "\x1b[C",
+        "left" => "\x1b[D",
+        "home" => "\x1b[H",
+        "end" => "\x1b[F",
+        "pageup" | "pgup" => "\x1b[5~",
+        "pagedown" | "pgdn" => "\x1b[6~",
+        "insert" | "ins" => "\x1b[2~",
+        "delete" | "del" => "\x1b[3~",
+        _ => return None,
+    })
+}
+
+// Why a key spec (the text between `<` and `>`) could not be resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    Unknown { spec: String },
+    UnknownModifier { spec: String, modifier: String },
+    UnknownComboKey { spec: String, key: String },
+    NoControlCode { spec: String },
+    NoEscapeSequence { spec: String },
+    NotAscii { spec: String },
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::Unknown { spec } => write!(
+                f,
+                "unknown key <{spec}> (escape literal angle brackets as \\< and \\>)"
+            ),
+            KeyError::UnknownModifier { spec, modifier } => {
+                write!(f, "unknown modifier '{modifier}' in <{spec}>")
+            }
+            KeyError::UnknownComboKey { spec, key } => {
+                write!(f, "unknown key '{key}' in <{spec}>")
+            }
+            KeyError::NoControlCode { spec } => write!(f, "<{spec}> has no control code"),
+            KeyError::NoEscapeSequence { spec } => {
+                write!(f, "<{spec}> has no standard escape sequence")
+            }
+            KeyError::NotAscii { spec } => write!(f, "<{spec}> is not an ASCII byte"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+// A named key from the keymap, falling back to the built-in keys
+fn key_seq(key: &str, keymap: &Keymap) -> Option<String> {
+    keymap
+        .get(key)
+        .or_else(|| base_key_seq(key))
+        .map(str::to_string)
+}
+
+// The bytes a key spec such as "ret", "C-x" or "A-F5" sends, as written between
+// the angle brackets of a `$` line
+pub fn resolve_key(spec: &str) -> Result<String, KeyError> {
+    resolve_key_with(spec, &Keymap::default())
+}
+
+// resolve_key with the keys in `keymap` taking precedence over built-in ones
+pub fn resolve_key_with(spec: &str, keymap: &Keymap) -> Result<String, KeyError> {
+    if let Some(seq) = key_seq(spec, keymap) {
+        return Ok(seq);
+    }
+    // Raw ASCII byte, e.g. <0x1e>
+    if let Some(hex) = spec.strip_prefix("0x")
+        && hex.len() == 2
+        && let Ok(byte) = u8::from_str_radix(hex, 16)
+    {
+        return if byte.is_ascii() {
+            Ok(char::from(byte).to_string())
+        } else {
+            Err(KeyError::NotAscii { spec: spec.into() })
+        };
+    }
+    if spec.contains('-') {
+        return resolve_modifier_combo(spec, keymap);
+    }
+    Err(KeyError::Unknown { spec: spec.into() })
+}
+
+// Control code for Ctrl-<key>; Shift makes no difference at the byte level
+fn ctrl_code(key: &str, spec: &str) -> Result<String, KeyError> {
+    let no_code = || KeyError::NoControlCode { spec: spec.into() };
+    let mut chars = key.chars();
+    if let (Some(ch), None) = (chars.next(), chars.next()) {
+        let ch = ch.to_ascii_lowercase();
+        return match ch {
+            // Ctrl-letter maps to ASCII 1-26
+            'a'..='z' => Ok(char::from(ch as u8 - b'a' + 1).to_string()),
+            ' ' => Ok("\x00".to_string()),
+            '[' => Ok("\x1b".to_string()), // Ctrl-[ maps to ESC
+            ']' => Ok("\x1d".to_string()),
+            '\\' => Ok("\x1c".to_string()),
+            '^' => Ok("\x1e".to_string()),
+            '/' => Ok("\x1f".to_string()),
+            '@' => Ok("\x00".to_string()),
+            _ => Err(no_code()),
+        };
+    }
+    if key == "space" {
+        return Ok("\x00".to_string());
+    }
+    Err(no_code())
+}
+
+// xterm's form of a cursor, editing or function key sequence with modifiers,
+// where `param` is 1 plus 1 for Shift, 2 for Alt and 4 for Ctrl: `\x1b[A`
+// becomes `\x1b[1;5A` with Ctrl, and `\x1b[15~` becomes `\x1b[15;5~`.
+// None for sequences without such a form.
+fn xterm_modified(seq: &str, param: u8) -> Option<String> {
+    if let Some(rest) = seq.strip_prefix("\x1b[") {
+        if let Some(number) = rest.strip_suffix('~')
+            && !number.is_empty()
+            && number.chars().all(|c| c.is_ascii_digit())
+        {
+            return Some(format!("\x1b[{number};{param}~"));
+        }
+        if rest.len() == 1 && rest.chars().all(|c| c.is_ascii_uppercase()) {
+            return Some(format!("\x1b[1;{param}{rest}"));
+        }
+    }
+    // F1-F4 send SS3 sequences unmodified, but CSI ones with modifiers
+    let rest = seq.strip_prefix("\x1bO")?;
+    (rest.len() == 1 && rest.chars().all(|c| c.is_ascii_uppercase()))
+        .then(|| format!("\x1b[1;{param}{rest}"))
+}
+
+fn resolve_modifier_combo(spec: &str, keymap: &Keymap) -> Result<String, KeyError> {
+    let parts: Vec<&str> = spec.split('-').collect();
+    let (modifiers, key) = parts.split_at(parts.len() - 1);
+    let key = key[0];
+    let unknown_key = || KeyError::UnknownComboKey {
+        spec: spec.into(),
+        key: key.into(),
+    };
+
+    let mut has_ctrl = false;
+    let mut has_alt = false;
+    let mut has_shift = false;
+
+    for m in modifiers {
+        match *m {
+            "C" | "c" | "Ctrl" | "ctrl" => has_ctrl = true,
+            "A" | "a" | "Alt" | "alt" | "M" | "m" | "Meta" | "meta" => has_alt = true,
+            "S" | "s" | "Shift" | "shift" => has_shift = true,
+            _ => {
+                return Err(KeyError::UnknownModifier {
+                    spec: spec.into(),
+                    modifier: (*m).into(),
+                });
+            }
+        }
+    }
+
+    let is_single_char = key.chars().count() == 1;
+
+    // Ctrl or Shift with a cursor, editing or function key, e.g. `<C-up>`
+    if (has_ctrl || has_shift)
+        && !is_single_char
+        && let Some(seq) = key_seq(key, keymap)
+        && let Some(modified) = xterm_modified(
+            &seq,
+            1 + u8::from(has_shift) + 2 * u8::from(has_alt) + 4 * u8::from(has_ctrl),
+        )
+    {
+        return Ok(modified);
+    }
+
+    if has_ctrl {
+        let code = if is_single_char || key == "space" {
+            ctrl_code(key, spec)?
+        } else if has_alt {
+            // Ctrl-Alt-<special>: fall back to Alt behaviour
+            key_seq(key, keymap).ok_or_else(unknown_key)?
         } else {
-            return Err(format!("unknown key '{key}' in <{spec}>"));
+            return Err(KeyError::NoControlCode { spec: spec.into() });
+        };
+        // Alt prepends ESC
+        return Ok(if has_alt { format!("\x1b{code}") } else { code });
+    }
+
+    if has_alt {
+        let base = if let Some(seq) = key_seq(key, keymap) {
+            seq
+        } else if is_single_char {
+            if has_shift {
+                key.to_uppercase()
+            } else {
+                key.to_string()
+            }
+        } else {
+            return Err(unknown_key());
         };
         return Ok(format!("\x1b{base}"));
     }
@@ -238,235 +1096,2442 @@ fn resolve_modifier_combo(spec: &str) -> Result<String, String> {
     if key == "tab" {
         return Ok("\x1b[Z".to_string()); // Backtab
     }
-    if is_single_char {
-        return Ok(key.to_uppercase());
+    if is_single_char {
+        return Ok(key.to_uppercase());
+    }
+    Err(KeyError::NoEscapeSequence { spec: spec.into() })
+}
+
+// Format for a bare `<now>`
+const DEFAULT_NOW_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// The local date and time for `<now>` or `<now:FORMAT>`, with FORMAT in
+// strftime syntax. Resolved as the script is parsed, so every `<now>` in a
+// run shows the time it started.
+fn expand_now(spec: &str) -> Option<Result<String, String>> {
+    let format = match spec.strip_prefix("now") {
+        Some("") => DEFAULT_NOW_FORMAT,
+        Some(rest) => rest.strip_prefix(':')?,
+        None => return None,
+    };
+    let items: Vec<_> = chrono::format::StrftimeItems::new(format).collect();
+    if items.is_empty() || items.contains(&chrono::format::Item::Error) {
+        return Some(Err(format!("invalid date format in <{spec}>")));
+    }
+    Some(Ok(chrono::Local::now()
+        .format_with_items(items.into_iter())
+        .to_string()))
+}
+
+// Most numbers a `<range:...>` may type, so a typo such as a huge END fails
+// instead of building the whole text
+const MAX_RANGE_VALUES: u64 = 10_000;
+
+// Expand a `range:START..END[:STEP][|SEP]` key spec into the numbers it
+// covers, joined by SEP (a space by default). Descending ranges count down;
+// STEP must be positive. Returns None if the spec isn't a valid range, and
+// an error if it covers more than MAX_RANGE_VALUES numbers.
+fn expand_range(spec: &str) -> Option<Result<String, String>> {
+    let body = spec.strip_prefix("range:")?;
+    let (bounds, sep) = body.split_once('|').unwrap_or((body, " "));
+    let (bounds, step) = match bounds.split_once(':') {
+        Some((bounds, step)) => (bounds, step.parse::<u64>().ok()?),
+        None => (bounds, 1),
+    };
+    let (start, end) = bounds.split_once("..")?;
+    let (start, end) = (start.parse::<i64>().ok()?, end.parse::<i64>().ok()?);
+    if step == 0 {
+        return None;
+    }
+    let count = start.abs_diff(end) / step + 1;
+    if count > MAX_RANGE_VALUES {
+        return Some(Err(format!(
+            "<{spec}> covers {count} numbers, more than the limit of {MAX_RANGE_VALUES}"
+        )));
+    }
+
+    let mut values = Vec::new();
+    let mut current = i128::from(start);
+    let step = i128::from(step);
+    while (start <= end && current <= i128::from(end))
+        || (start > end && current >= i128::from(end))
+    {
+        values.push(current.to_string());
+        current += if start <= end { step } else { -step };
+    }
+    Some(Ok(values.join(sep)))
+}
+
+// Expand the special keys and escapes in the text of a `$` line
+pub fn parse_type_content(input: &str) -> Result<String, String> {
+    parse_type_content_with(input, &Keymap::default())
+}
+
+// parse_type_content with the keys in `keymap` taking precedence over
+// built-in ones
+pub fn parse_type_content_with(input: &str, keymap: &Keymap) -> Result<String, String> {
+    let mut result = String::new();
+    let mut remaining = input;
+
+    while !remaining.is_empty() {
+        if remaining.starts_with("\\<") || remaining.starts_with("\\>") {
+            result.push_str(&remaining[1..2]);
+            remaining = &remaining[2..];
+        } else if let Ok((rest, spec)) = parse_nested_key_spec(remaining) {
+            result.push_str(&resolve_key_with(&spec, keymap).map_err(|e| e.to_string())?);
+            remaining = rest;
+        } else if remaining.starts_with('<') {
+            if let Ok((rest, spec)) = parse_key_spec(remaining) {
+                if let Some(range) = expand_range(spec) {
+                    result.push_str(&range?);
+                } else if let Some(now) = expand_now(spec) {
+                    result.push_str(&now?);
+                } else if spec.starts_with("range:") {
+                    // Not a valid range: type it as written
+                    result.push_str(&remaining[..remaining.len() - rest.len()]);
+                } else {
+                    result.push_str(&resolve_key_with(spec, keymap).map_err(|e| e.to_string())?);
+                }
+                remaining = rest;
+            } else {
+                // No closing '>' on the line: literal '<' (e.g. shell redirection)
+                result.push('<');
+                remaining = &remaining[1..];
+            }
+        } else {
+            let c = remaining.chars().next().unwrap();
+            result.push(c);
+            remaining = &remaining[c.len_utf8()..];
+        }
+    }
+
+    Ok(result)
+}
+
+// Canonical key names for encode_type_content, longest sequences first so
+// escape sequences are matched whole
+const ENCODE_KEYS: &[(&str, &str)] = &[
+    ("\x1b[15~", "F5"),
+    ("\x1b[17~", "F6"),
+    ("\x1b[18~", "F7"),
+    ("\x1b[19~", "F8"),
+    ("\x1b[20~", "F9"),
+    ("\x1b[21~", "F10"),
+    ("\x1b[23~", "F11"),
+    ("\x1b[24~", "F12"),
+    ("\x1b[5~", "pageup"),
+    ("\x1b[6~", "pagedown"),
+    ("\x1b[2~", "insert"),
+    ("\x1b[3~", "delete"),
+    ("\x1bOP", "F1"),
+    ("\x1bOQ", "F2"),
+    ("\x1bOR", "F3"),
+    ("\x1bOS", "F4"),
+    ("\x1b[A", "up"),
+    ("\x1b[B", "down"),
+    ("\x1b[C", "right"),
+    ("\x1b[D", "left"),
+    ("\x1b[H", "home"),
+    ("\x1b[F", "end"),
+    ("\x1b[Z", "S-tab"),
+    ("\x1b", "esc"),
+    ("\r", "ret"),
+    ("\t", "tab"),
+    ("\x7f", "bs"),
+    ("\x00", "C-space"),
+    ("\x1c", "C-\\"),
+    ("\x1d", "C-]"),
+    ("\x1e", "C-^"),
+    ("\x1f", "C-/"),
+];
+
+// Append `<tag>`. A literal '\' just before it would read back as escaping the
+// '<', so trailing backslashes are written as bytes instead.
+fn push_key_tag(result: &mut String, tag: &str) {
+    let backslashes = result.len() - result.trim_end_matches('\\').len();
+    result.truncate(result.len() - backslashes);
+    result.push_str(&"<0x5c>".repeat(backslashes));
+    result.push_str(&format!("<{tag}>"));
+}
+
+// Inverse of parse_type_content: render resolved text back into type syntax,
+// naming special bytes where possible and falling back to <0x..>
+pub fn encode_type_content(text: &str) -> String {
+    encode_type_content_with(text, BackspaceKey::Del)
+}
+
+// encode_type_content for text following an `@ backspace`, so `<bs>` names
+// whichever byte it will read back as
+pub fn encode_type_content_with(text: &str, backspace: BackspaceKey) -> String {
+    let mut result = String::new();
+    let mut remaining = text;
+
+    while !remaining.is_empty() {
+        if backspace == BackspaceKey::Bs
+            && let Some(c) = remaining
+                .chars()
+                .next()
+                .filter(|c| matches!(c, '\x08' | '\x7f'))
+        {
+            push_key_tag(&mut result, if c == '\x08' { "bs" } else { "0x7f" });
+            remaining = &remaining[1..];
+            continue;
+        }
+        if let Some((seq, name)) = ENCODE_KEYS
+            .iter()
+            .find(|(seq, _)| remaining.starts_with(seq))
+        {
+            push_key_tag(&mut result, name);
+            remaining = &remaining[seq.len()..];
+            continue;
+        }
+        let c = remaining.chars().next().unwrap();
+        let at_edge = result.is_empty() || remaining.len() == c.len_utf8();
+        match c {
+            '<' | '>' => {
+                result.push('\\');
+                result.push(c);
+            }
+            // Edge whitespace is trimmed when the line is parsed
+            ' ' if at_edge => push_key_tag(&mut result, "space"),
+            '\x01'..='\x1a' => {
+                push_key_tag(
+                    &mut result,
+                    &format!("C-{}", char::from(c as u8 - 1 + b'a')),
+                );
+            }
+            c if c.is_ascii_control() => push_key_tag(&mut result, &format!("0x{:02x}", c as u8)),
+            c => result.push(c),
+        }
+        remaining = &remaining[c.len_utf8()..];
+    }
+
+    // Text that would read back as an @after attribute types its '@' as a byte
+    if let Some((pos, _)) = after_attribute(&result) {
+        result.replace_range(pos..=pos, "<0x40>");
+    }
+    result
+}
+
+// Render resolved text for a log so its control characters are visible, in
+// the chosen notation. Only for reading: caret notation doesn't escape a
+// literal '^', so it can't be parsed back.
+pub fn display_keys(text: &str, notation: KeyNotation) -> String {
+    match notation {
+        KeyNotation::Names => encode_type_content(text),
+        KeyNotation::Caret => text
+            .chars()
+            .map(|c| match c {
+                '\x00'..='\x1f' => format!("^{}", char::from(c as u8 + b'@')),
+                '\x7f' => "^?".to_string(),
+                c => c.to_string(),
+            })
+            .collect(),
+    }
+}
+
+const AFTER_ATTRIBUTE: &str = "@after:";
+
+// A trailing ` @after:N` on a type line: the byte offset of the '@' and the
+// pause it asks for. Anything that isn't a valid wait (e.g. `@after:noon`)
+// stays part of the typed text.
+fn after_attribute(raw: &str) -> Option<(usize, Duration)> {
+    let pos = raw.rfind(AFTER_ATTRIBUTE)?;
+    if pos > 0 && !raw[..pos].ends_with(char::is_whitespace) {
+        return None;
+    }
+    match parse_float(&raw[pos + AFTER_ATTRIBUTE.len()..]) {
+        Ok(("", secs)) => Some((pos, Duration::from_secs_f64(secs))),
+        _ => None,
+    }
+}
+
+// Returns the raw text; special keys are expanded in parse_script so
+// unknown key specs can be reported with a line number
+fn parse_type(input: &str) -> IResult<&str, Command> {
+    let (input, _) = char('$')(input)?;
+    let (input, _) = space0(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Type(text.to_string())))
+}
+
+// Typed between a `$` line and a `>` line continuing it, so the shell shows
+// the command split over several lines
+const VISIBLE_CONTINUATION: &str = " \\\r";
+
+fn parse_continuation(input: &str) -> IResult<&str, String> {
+    let (input, _) = char('>')(input)?;
+    let (input, _) = space0(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, text.to_string()))
+}
+
+#[derive(Clone)]
+enum Line {
+    Command(Command),
+    Continuation(String),
+    BlockStart(Block),
+    BlockElse,
+    BlockEnd,
+    Comment,
+    Keymap(String),
+    Terminfo(String),
+    Glob(String, bool),
+}
+
+fn parse_line(input: &str) -> IResult<&str, Line> {
+    alt((
+        map(parse_directive, Line::Command),
+        map(parse_block_start, Line::BlockStart),
+        value(Line::BlockElse, parse_block_else),
+        value(Line::BlockEnd, parse_block_end),
+        value(Line::Comment, parse_comment),
+        map(parse_keymap, Line::Keymap),
+        map(parse_terminfo, Line::Terminfo),
+        map(parse_glob, |(pattern, optional)| {
+            Line::Glob(pattern, optional)
+        }),
+        map(parse_type, Line::Command),
+        map(parse_continuation, Line::Continuation),
+    ))
+    .parse(input)
+}
+
+struct OpenBlock {
+    block: Block,
+    // Line the block was opened on
+    line: usize,
+    // Commands collected so far in the enclosing scope
+    outer: Vec<Command>,
+    // Commands before `@ else`, once one has been seen
+    then_branch: Option<Vec<Command>>,
+}
+
+// Whether the innermost open block is a paste
+fn in_paste(blocks: &[OpenBlock]) -> bool {
+    blocks
+        .last()
+        .is_some_and(|open| matches!(open.block, Block::Paste))
+}
+
+// Turn a closed block's body into the commands it stands for
+fn close_block(open: OpenBlock, body: Vec<Command>) -> Vec<Command> {
+    match open.block {
+        Block::Shuffle => vec![Command::Shuffle(body)],
+        Block::NoEcho => vec![Command::NoEcho(body)],
+        Block::Tag(names) => vec![Command::Tagged(names, body)],
+        Block::Live => vec![Command::Live(body)],
+        Block::Paste => {
+            let lines = body
+                .into_iter()
+                .filter_map(|command| match command {
+                    Command::Type(text) => Some(text),
+                    _ => None,
+                })
+                .collect();
+            vec![Command::Paste(lines)]
+        }
+        Block::IfOs(names) => {
+            let (then_branch, else_branch) = match open.then_branch {
+                Some(then_branch) => (then_branch, body),
+                None => (body, Vec::new()),
+            };
+            if names.iter().any(|name| name == std::env::consts::OS) {
+                then_branch
+            } else {
+                else_branch
+            }
+        }
+    }
+}
+
+// The bytes a script may type into the session, e.g. to keep Ctrl-C and
+// escape sequences out of scripts from elsewhere
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedBytes([bool; 256]);
+
+// Named sets of bytes for AllowedBytes::parse
+const BYTE_SETS: &[(&str, &[(u8, u8)])] = &[
+    ("printable", &[(0x20, 0x7e)]),
+    ("unicode", &[(0x80, 0xff)]),
+    // Printable text plus Tab, Enter and Backspace
+    (
+        "safe",
+        &[
+            (0x20, 0x7e),
+            (0x80, 0xff),
+            (0x09, 0x09),
+            (0x0d, 0x0d),
+            (0x7f, 0x7f),
+        ],
+    ),
+];
+
+impl AllowedBytes {
+    // Parse a comma-separated list of bytes (`0x1b`), ranges (`0x00-0x1f`)
+    // and named sets: `printable` (0x20-0x7e), `unicode` (0x80-0xff, the
+    // bytes of non-ASCII characters) and `safe` (both, with Tab, Enter and
+    // Backspace)
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let byte = |text: &str| {
+            text.strip_prefix("0x")
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("{text:?} is not a byte such as 0x1b"))
+        };
+        let mut allowed = [false; 256];
+        for item in spec.split(',').map(str::trim) {
+            let ranges = match BYTE_SETS.iter().find(|(name, _)| *name == item) {
+                Some((_, ranges)) => ranges.to_vec(),
+                None => match item.split_once('-') {
+                    Some((start, end)) => vec![(byte(start)?, byte(end)?)],
+                    None => vec![(byte(item)?, byte(item)?)],
+                },
+            };
+            for (start, end) in ranges {
+                if start > end {
+                    return Err(format!("{item:?} is an empty range"));
+                }
+                allowed[usize::from(start)..=usize::from(end)].fill(true);
+            }
+        }
+        Ok(Self(allowed))
+    }
+
+    fn first_disallowed(&self, text: &str) -> Option<u8> {
+        text.bytes().find(|&b| !self.0[usize::from(b)])
+    }
+}
+
+// Check typed text against the allowed bytes, if any are set: an error, or
+// with ByteCheck::Warn a warning on stderr
+fn check_bytes(text: &str, custom: &CustomDirectives, line_num: usize) -> Result<(), String> {
+    let Some((allowed, check)) = &custom.allowed_bytes else {
+        return Ok(());
+    };
+    let Some(byte) = allowed.first_disallowed(text) else {
+        return Ok(());
+    };
+    let shown = if byte.is_ascii() {
+        format!(
+            "{byte:#04x} ({})",
+            display_keys(&char::from(byte).to_string(), KeyNotation::Names)
+        )
+    } else {
+        format!("{byte:#04x}")
+    };
+    let message = format!("Line {}: types {shown}, which is not allowed", line_num + 1);
+    match check {
+        ByteCheck::Error => Err(message),
+        ByteCheck::Warn => {
+            eprintln!("Warning: {message}");
+            Ok(())
+        }
+    }
+}
+
+type DirectiveHandler = Box<dyn Fn(&str, &str) -> Option<Result<Command, String>>>;
+
+// Project-specific directives layered over the built-in ones. Built-ins always
+// take precedence: a handler is only consulted for an `@` line no built-in
+// directive recognises, and handlers are tried in registration order until one
+// returns Some.
+#[derive(Default)]
+pub struct CustomDirectives {
+    handlers: Vec<DirectiveHandler>,
+    lenient: bool,
+    keymap: Keymap,
+    remote: bool,
+    allowed_bytes: Option<(AllowedBytes, ByteCheck)>,
+}
+
+impl CustomDirectives {
+    // Register a handler called with the directive name and its value (the text
+    // after the first ':', or "" if there is none). Returning None passes the
+    // directive on to the next handler. Text in a returned Command::Type is
+    // expanded like a `$` line.
+    pub fn register<F>(&mut self, handler: F)
+    where
+        F: Fn(&str, &str) -> Option<Result<Command, String>> + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+    }
+
+    // Type `@` lines that neither a built-in nor a handler recognises as
+    // written, like a `$` line, instead of failing. Off by default so typos in
+    // directive names are caught.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    // Parse a script fetched from elsewhere, which may not touch this machine:
+    // directives that run programs, read or write files or read the
+    // environment (`@ shell`, `@ secret`, `@ capture`, `@ verify`, `@ theme`
+    // and `@ keymap`) are errors, whether built in or custom
+    pub fn set_remote(&mut self, remote: bool) {
+        self.remote = remote;
+    }
+
+    // Hold the text typed into the session (`$` lines and their
+    // continuations, `@ retype`, `@ overwrite`, `@ glob` and the suggestion
+    // key) to `allowed`, failing or warning on other bytes. `@ secret`
+    // values are only known at playback time, so they aren't checked.
+    pub fn set_allowed_bytes(&mut self, allowed: AllowedBytes, check: ByteCheck) {
+        self.allowed_bytes = Some((allowed, check));
+    }
+
+    // Keys that take precedence over both the built-in keys and any a script
+    // loads with `@ keymap`
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    fn resolve(&self, line: &str) -> Option<Result<Command, String>> {
+        let directive = line.strip_prefix('@')?.trim_start();
+        let (name, value) = directive.split_once(':').unwrap_or((directive, ""));
+        let (name, value) = (name.trim(), value.trim());
+        self.handlers
+            .iter()
+            .find_map(|handler| handler(name, value))
+            .or_else(|| self.lenient.then(|| Ok(Command::Type(line.to_string()))))
+    }
+}
+
+// Name of the directive on an `@` line
+fn directive_name(line: &str) -> &str {
+    line.trim_start_matches('@')
+        .trim_start()
+        .split(':')
+        .next()
+        .unwrap_or_default()
+}
+
+// `value` with each comma between two digits read as a decimal point, if that
+// makes `line` parse, as `@ speed:0,2` is 0.2 written for a comma locale
+fn decimal_comma_fix(line: &str, value: &str) -> Option<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let fixed: String = chars
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            let between_digits = i > 0
+                && chars[i - 1].is_ascii_digit()
+                && chars.get(i + 1).is_some_and(char::is_ascii_digit);
+            if c == ',' && between_digits { '.' } else { c }
+        })
+        .collect();
+    let start = line.rfind(value).filter(|_| fixed != value)?;
+    let fixed_line = format!("{}{fixed}{}", &line[..start], &line[start + value.len()..]);
+    let parses = parses_fully(&fixed_line)
+        || strip_trailing_separator(&fixed_line).is_some_and(|line| parses_fully(&line));
+    parses.then_some(fixed)
+}
+
+// `line` without the one `;` or `,` ending its directive, ahead of any
+// comment, as in `@ speed:0.2;` written for other tools. Two in a row are
+// left alone, as they are more likely a mistake.
+fn strip_trailing_separator(line: &str) -> Option<String> {
+    if !line.starts_with('@') {
+        return None;
+    }
+    let (head, comment) = line.split_at(line.find('#').unwrap_or(line.len()));
+    let stripped = head.trim_end().strip_suffix([';', ','])?;
+    if stripped.ends_with([';', ',']) {
+        return None;
+    }
+    Some(format!("{stripped} {comment}").trim_end().to_string())
+}
+
+// Whether `line` parses with nothing left over but a comment
+fn parses_fully(line: &str) -> bool {
+    matches!(
+        parse_line(line),
+        Ok((rest, _)) if rest.trim().is_empty() || rest.trim_start().starts_with('#')
+    )
+}
+
+fn size_preset_names() -> String {
+    let names: Vec<&str> = SIZE_PRESETS.iter().map(|(name, _, _)| *name).collect();
+    names.join(", ")
+}
+
+// What was wrong with a directive's value, and what it must be instead
+fn value_requirement(name: &str) -> (&'static str, String) {
+    match name {
+        "size" => (
+            "value",
+            format!(
+                "must be COLS:ROWS, each between 1 and {MAX_DIMENSION}, or one of {}",
+                size_preset_names()
+            ),
+        ),
+        "resize" => (
+            "value",
+            format!(
+                "must be COLS:ROWS, each between 1 and {MAX_DIMENSION}, or one of {}, \
+                 and a pause must be a non-negative number",
+                size_preset_names()
+            ),
+        ),
+        "if_os" => (
+            "value",
+            format!(
+                "must be OS names separated by '|', from {}",
+                KNOWN_OSES.join(", ")
+            ),
+        ),
+        "seed" => (
+            "number",
+            "must be a whole number from 0 to 18446744073709551615".to_string(),
+        ),
+        "umask" => (
+            "value",
+            "must be an octal mask from 000 to 777, e.g. 022".to_string(),
+        ),
+        "word_typos" => ("number", "must be a number from 0 to 1".to_string()),
+        "typo_chars" => (
+            "value",
+            "must be characters and FIRST-LAST ranges, e.g. a-zA-Z0-9, without spaces, \
+             quotes or shell operators"
+                .to_string(),
+        ),
+        "jitter_params" => (
+            "value",
+            "must be MEAN:STDDEV, both non-negative multiples of the speed, or off".to_string(),
+        ),
+        "long_lines" => (
+            "value",
+            "must be a number of characters above 0, optionally followed by \
+             :MAX_SPEEDUP of at least 1, or off"
+                .to_string(),
+        ),
+        "keymap" => ("path", "must be the path to a keymap file".to_string()),
+        "glob" | "glob_optional" => ("pattern", "must be a glob pattern".to_string()),
+        "terminfo" => ("value", "must be on, off or a terminal type".to_string()),
+        "tag" => (
+            "value",
+            "must be tag names separated by '|', each made of letters, digits, '-' and '_'"
+                .to_string(),
+        ),
+        "verify" => ("command", "must be a command to run".to_string()),
+        "overwrite" => (
+            "value",
+            "must be COL:TEXT, with COL counting from 1 and some text".to_string(),
+        ),
+        "wait_response" => (
+            "value",
+            "must be a query and a timeout in seconds, e.g. <esc>[c:1".to_string(),
+        ),
+        "wait_until" => (
+            "time",
+            "must be a local time as HH:MM:SS, optionally followed by :next_day".to_string(),
+        ),
+        "expect_exit" => (
+            "exit code",
+            "must be a whole number, e.g. 0 for success".to_string(),
+        ),
+        "speed" | "jitter" => (
+            "number",
+            "must be a non-negative number or a MIN..MAX range".to_string(),
+        ),
+        _ => ("number", "must be a non-negative number".to_string()),
+    }
+}
+
+// Make `<backspace>` and `<bs>` send `key`, unless the caller's keymap says
+// otherwise
+fn set_backspace_keys(keymap: &mut Keymap, key: BackspaceKey, custom: &CustomDirectives) {
+    let mut backspace = Keymap::default();
+    backspace.insert("backspace", key.bytes());
+    backspace.insert("bs", key.bytes());
+    keymap.merge(&backspace);
+    keymap.merge(&custom.keymap);
+}
+
+pub fn parse_script(input: &str) -> Result<Script, String> {
+    parse_script_with(input, &CustomDirectives::default())
+}
+
+// Keys from the terminfo entry for `term`
+#[cfg(feature = "terminfo")]
+fn terminfo_keymap(term: &str) -> Result<Keymap, String> {
+    crate::terminfo::keymap(term)
+}
+
+#[cfg(not(feature = "terminfo"))]
+fn terminfo_keymap(_term: &str) -> Result<Keymap, String> {
+    Err("@terminfo needs quipu built with the terminfo feature".to_string())
+}
+
+// The keys `$` lines resolve against: those from `@ terminfo` while it is on,
+// overridden by the keymap
+fn active_keymap<'a>(terminfo: &Option<Keymap>, keymap: &'a Keymap) -> Cow<'a, Keymap> {
+    match terminfo {
+        Some(terminfo) => {
+            let mut active = terminfo.clone();
+            active.merge(keymap);
+            Cow::Owned(active)
+        }
+        None => Cow::Borrowed(keymap),
+    }
+}
+
+// Lines may end in \n or \r\n, and a missing newline after the last line
+// makes no difference
+pub fn parse_script_with(input: &str, custom: &CustomDirectives) -> Result<Script, String> {
+    let mut commands = Vec::new();
+    // Open blocks, innermost last
+    let mut blocks: Vec<OpenBlock> = Vec::new();
+    let mut keymap = custom.keymap.clone();
+    let mut terminfo: Option<Keymap> = None;
+    // The shell's TERM, which `@ terminfo:on` looks up
+    let mut session_term = SESSION_TERM.to_string();
+    // The Backspace key, and those saved by `@ push`
+    let mut backspace = BackspaceKey::default();
+    let mut saved_backspace: Vec<BackspaceKey> = Vec::new();
+
+    for (line_num, line) in input.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        // Retried without a trailing `;` or `,` only if it doesn't parse as
+        // written, so text that ends in one, as in `@ echo:Done;`, keeps it
+        let lenient = strip_trailing_separator(trimmed)
+            .filter(|line| !parses_fully(trimmed) && parses_fully(line));
+        let mut parsed = match &lenient {
+            Some(line) => parse_line(line),
+            None => parse_line(trimmed),
+        };
+        if matches!(parsed, Err(nom::Err::Error(_)))
+            && let Some(result) = custom.resolve(trimmed)
+        {
+            let cmd = result.map_err(|e| format!("Line {}: {e}", line_num + 1))?;
+            parsed = Ok(("", Line::Command(cmd)));
+        }
+
+        match parsed {
+            Ok((remaining, _))
+                if !remaining.trim().is_empty() && !remaining.trim_start().starts_with('#') =>
+            {
+                return Err(format!(
+                    "Line {}: Unexpected text after command: '{}'",
+                    line_num + 1,
+                    remaining
+                ));
+            }
+            Ok((_, Line::BlockStart(_))) if in_paste(&blocks) => {
+                return Err(format!(
+                    "Line {}: only $ lines can appear inside a @paste block",
+                    line_num + 1
+                ));
+            }
+            Ok((_, Line::BlockStart(block))) => {
+                blocks.push(OpenBlock {
+                    block,
+                    line: line_num + 1,
+                    outer: std::mem::take(&mut commands),
+                    then_branch: None,
+                });
+            }
+            Ok((_, Line::BlockElse)) => match blocks.last_mut() {
+                Some(open)
+                    if matches!(open.block, Block::IfOs(_)) && open.then_branch.is_none() =>
+                {
+                    open.then_branch = Some(std::mem::take(&mut commands));
+                }
+                _ => {
+                    return Err(format!(
+                        "Line {}: @else must be inside an @if_os block, at most once",
+                        line_num + 1
+                    ));
+                }
+            },
+            Ok((_, Line::BlockEnd)) => {
+                let Some(mut open) = blocks.pop() else {
+                    return Err(format!("Line {}: @end without an open block", line_num + 1));
+                };
+                let outer = std::mem::take(&mut open.outer);
+                let body = std::mem::replace(&mut commands, outer);
+                commands.extend(close_block(open, body));
+            }
+            Ok((_, Line::Command(cmd))) => {
+                if in_paste(&blocks)
+                    && (!matches!(&cmd, Command::Type(text) if after_attribute(text).is_none()))
+                {
+                    return Err(format!(
+                        "Line {}: only $ lines can appear inside a @paste block",
+                        line_num + 1
+                    ));
+                }
+                if custom.remote
+                    && let Some(name) = cmd.host_directive()
+                {
+                    return Err(format!(
+                        "Line {}: @{name} is not allowed in a remote script",
+                        line_num + 1
+                    ));
+                }
+                if let Some(open) = blocks.iter().find(|open| {
+                    matches!(
+                        open.block,
+                        Block::Shuffle | Block::NoEcho | Block::Tag(_) | Block::Live
+                    )
+                }) && cmd.is_setup()
+                {
+                    return Err(format!(
+                        "Line {}: @shell, @size, @env, @seed, @ready_sentinel, @prewarm and @umask \
+                         cannot appear inside a @{} block",
+                        line_num + 1,
+                        open.block.name()
+                    ));
+                }
+                if let Command::SetEnv(name, value) = &cmd
+                    && name == "TERM"
+                {
+                    session_term = value.clone();
+                }
+                if let Some(open) = blocks.last()
+                    && cmd == Command::Checkpoint
+                {
+                    return Err(format!(
+                        "Line {}: @checkpoint cannot appear inside a @{} block",
+                        line_num + 1,
+                        open.block.name()
+                    ));
+                }
+                if matches!(cmd, Command::PushConfig | Command::PopConfig)
+                    && blocks
+                        .iter()
+                        .any(|open| matches!(open.block, Block::Shuffle))
+                {
+                    return Err(format!(
+                        "Line {}: @push and @pop cannot appear inside a @shuffle block",
+                        line_num + 1
+                    ));
+                }
+                let expand = |raw: &str| {
+                    parse_type_content_with(raw, &active_keymap(&terminfo, &keymap))
+                        .map_err(|e| format!("Line {}: {e}", line_num + 1))
+                };
+                // For text typed into the session
+                let expand_typed = |raw: &str| {
+                    let text = expand(raw)?;
+                    check_bytes(&text, custom, line_num)?;
+                    Ok::<_, String>(text)
+                };
+                match cmd {
+                    Command::Type(raw) => {
+                        let after = after_attribute(&raw);
+                        let text = after.map_or(raw.as_str(), |(pos, _)| raw[..pos].trim_end());
+                        commands.push(Command::Type(expand_typed(text)?));
+                        commands.extend(after.map(|(_, pause)| Command::Wait(pause)));
+                    }
+                    Command::Retype(raw) => commands.push(Command::Retype(expand_typed(&raw)?)),
+                    Command::Overwrite(col, raw) => {
+                        // Spaces are how text is blanked out in place, so
+                        // those the line ends with are kept
+                        let raw = raw + &line[line.trim_end().len()..];
+                        if raw.is_empty() {
+                            let (what, requirement) = value_requirement("overwrite");
+                            return Err(format!(
+                                "Line {}: invalid {what} in @overwrite: '{col}:' {requirement}",
+                                line_num + 1
+                            ));
+                        }
+                        commands.push(Command::Overwrite(col, expand_typed(&raw)?));
+                    }
+                    Command::SetSuggestionKey(raw) => {
+                        commands.push(Command::SetSuggestionKey(expand_typed(&raw)?));
+                    }
+                    Command::WaitKey(Some(raw)) => {
+                        commands.push(Command::WaitKey(Some(expand(&raw)?)));
+                    }
+                    Command::WaitResponse(raw, timeout) => {
+                        commands.push(Command::WaitResponse(expand(&raw)?, timeout));
+                    }
+                    Command::SetBackspace(key) => {
+                        backspace = key;
+                        set_backspace_keys(&mut keymap, key, custom);
+                        commands.push(cmd);
+                    }
+                    Command::PushConfig => {
+                        saved_backspace.push(backspace);
+                        commands.push(cmd);
+                    }
+                    Command::PopConfig => {
+                        let Some(key) = saved_backspace.pop() else {
+                            return Err(format!(
+                                "Line {}: @pop without a matching @push",
+                                line_num + 1
+                            ));
+                        };
+                        if key != backspace {
+                            backspace = key;
+                            set_backspace_keys(&mut keymap, key, custom);
+                        }
+                        commands.push(cmd);
+                    }
+                    other => commands.push(other),
+                }
+            }
+            Ok((_, Line::Continuation(_))) if in_paste(&blocks) => {
+                return Err(format!(
+                    "Line {}: only $ lines can appear inside a @paste block",
+                    line_num + 1
+                ));
+            }
+            Ok((_, Line::Continuation(raw))) => {
+                let Some(Command::Type(text)) = commands.last_mut() else {
+                    return Err(format!(
+                        "Line {}: '>' continues a '$' line and must directly follow one",
+                        line_num + 1
+                    ));
+                };
+                let after = after_attribute(&raw);
+                let raw = after.map_or(raw.as_str(), |(pos, _)| raw[..pos].trim_end());
+                let continued = parse_type_content_with(raw, &active_keymap(&terminfo, &keymap))
+                    .map_err(|e| format!("Line {}: {e}", line_num + 1))?;
+                check_bytes(&continued, custom, line_num)?;
+                text.push_str(VISIBLE_CONTINUATION);
+                text.push_str(&continued);
+                commands.extend(after.map(|(_, pause)| Command::Wait(pause)));
+            }
+            Ok((_, Line::Comment)) => {}
+            Ok((_, Line::Glob(_, optional))) if custom.remote => {
+                return Err(format!(
+                    "Line {}: @{} is not allowed in a remote script",
+                    line_num + 1,
+                    if optional { "glob_optional" } else { "glob" }
+                ));
+            }
+            Ok((_, Line::Glob(pattern, optional))) => {
+                let text = expand_glob(&pattern, optional)
+                    .map_err(|e| format!("Line {}: @glob: {e}", line_num + 1))?;
+                check_bytes(&text, custom, line_num)?;
+                commands.push(Command::Type(text));
+            }
+            Ok((_, Line::Keymap(_))) if custom.remote => {
+                return Err(format!(
+                    "Line {}: @keymap is not allowed in a remote script",
+                    line_num + 1
+                ));
+            }
+            Ok((_, Line::Keymap(path))) => {
+                let loaded = Keymap::load(std::path::Path::new(&path))
+                    .map_err(|e| format!("Line {}: {e:#}", line_num + 1))?;
+                keymap.merge(&loaded);
+                // Keys set by the caller still win
+                keymap.merge(&custom.keymap);
+            }
+            Ok((_, Line::Terminfo(term))) => {
+                let term = match term.as_str() {
+                    "off" => None,
+                    "on" => Some(session_term.as_str()),
+                    name => Some(name),
+                };
+                terminfo = term
+                    .map(terminfo_keymap)
+                    .transpose()
+                    .map_err(|e| format!("Line {}: {e}", line_num + 1))?;
+            }
+            Err(nom::Err::Failure(e)) if e.code == nom::error::ErrorKind::Verify => {
+                let name = directive_name(trimmed);
+                let (what, requirement) = value_requirement(name);
+                let hint = decimal_comma_fix(trimmed, e.input)
+                    .map(|fixed| format!(" (use '.' for decimals: '{fixed}')"))
+                    .unwrap_or_default();
+                return Err(format!(
+                    "Line {}: invalid {what} in @{name}: '{}' {requirement}{hint}",
+                    line_num + 1,
+                    e.input,
+                ));
+            }
+            Err(e) => {
+                return Err(format!("Line {}: Parse error: {}", line_num + 1, e));
+            }
+        }
+    }
+
+    if let Some(open) = blocks.last() {
+        return Err(format!(
+            "Line {}: @{} block is missing its @end",
+            open.line,
+            open.block.name()
+        ));
+    }
+
+    Ok(Script { commands })
+}
+
+// Whether a script survives a trip through to_source: parsing the canonical
+// source gives back the commands the input parsed to. Input the parser
+// rejects has nothing to round-trip and counts as stable. Meant for property
+// tests and fuzzing, where a false result points at an escaping bug.
+pub fn reparse_stable(input: &str) -> bool {
+    let Ok(script) = parse_script(input) else {
+        return true;
+    };
+    parse_script(&script.to_source()).is_ok_and(|reparsed| reparsed.commands == script.commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_speed() {
+        let input = "@ speed:0.2";
+        let result = parse_speed(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::SetSpeed(0.2.into()));
+    }
+
+    #[test]
+    fn test_parse_jitter() {
+        let input = "@ jitter:0.02";
+        let result = parse_jitter(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::SetJitter(0.02.into()));
+    }
+
+    #[test]
+    fn test_parse_wait() {
+        let input = "@ wait:2.0";
+        let result = parse_wait(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::Wait(Duration::from_secs_f64(2.0)));
+    }
+
+    #[test]
+    fn test_parse_expect_exit() {
+        let script = parse_script("$ make test<ret>\n@ expect_exit:0").unwrap();
+        assert_eq!(script.commands[1], Command::ExpectExit(0));
+        assert_eq!(
+            parse_script("@ expect_exit:-1").unwrap_err(),
+            "Line 1: invalid exit code in @expect_exit: '-1' must be a whole number, e.g. 0 for success"
+        );
+    }
+
+    #[test]
+    fn test_parse_word_typos() {
+        let script = parse_script("@ word_typos:0.05\n@ word_typos: 1").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::SetWordTypos(0.05), Command::SetWordTypos(1.0)]
+        );
+        assert_eq!(
+            parse_script("@ word_typos:1.5").unwrap_err(),
+            "Line 1: invalid number in @word_typos: '1.5' must be a number from 0 to 1"
+        );
+    }
+
+    #[test]
+    fn test_parse_sync() {
+        let script = parse_script("@ sync:on\n@ sync:off\n@ sync:osc133\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSync(SyncMode::Schedule),
+                Command::SetSync(SyncMode::Off),
+                Command::SetSync(SyncMode::Prompt),
+            ]
+        );
+        assert!(parse_script("@ sync:maybe").is_err());
+    }
+
+    #[test]
+    fn test_nested_modifier_keys() {
+        for (nested, canonical) in [
+            ("<A-<up>>", "<A-up>"),
+            ("<C-A-<F5>>", "<C-A-F5>"),
+            ("<S-<tab>>", "<S-tab>"),
+            ("x<A-<ret>>y", "x<A-ret>y"),
+        ] {
+            assert_eq!(
+                parse_type_content(nested),
+                parse_type_content(canonical),
+                "{nested}"
+            );
+            assert!(parse_type_content(nested).is_ok(), "{nested}");
+        }
+        assert_eq!(parse_type_content("<C-<up>>"), Ok("\x1b[1;5A".to_string()));
+        // Errors name the canonical form
+        assert_eq!(
+            parse_type_content("<C-<ret>>"),
+            Err("<C-ret> has no control code".to_string())
+        );
+        // Not nested modifiers: unchanged
+        assert_eq!(parse_type_content("<S-<>"), Ok("<".to_string()));
+        assert!(parse_type_content("<<up>>").is_err());
+    }
+
+    #[test]
+    fn test_parse_caption() {
+        assert_eq!(
+            parse_script("@ caption: Step 1\n@ subtitle:Step 2")
+                .unwrap()
+                .commands,
+            vec![
+                Command::Subtitle("Step 1".to_string()),
+                Command::Subtitle("Step 2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_typo_chars() {
+        for (spec, ranges) in [
+            ("a-z", vec![('a', 'z')]),
+            (
+                "a-zA-Z0-9_-",
+                vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_'), ('-', '-')],
+            ),
+            ("-xé", vec![('-', '-'), ('x', 'x'), ('é', 'é')]),
+        ] {
+            let script = parse_script(&format!("@ typo_chars:{spec} # note")).unwrap();
+            assert_eq!(
+                script.commands,
+                vec![Command::SetTypoChars(TypoChars(ranges))],
+                "{spec}"
+            );
+        }
+        for bad in [
+            "@ typo_chars:",
+            "@ typo_chars:z-a",
+            "@ typo_chars:a-z|",
+            "@ typo_chars:a z",
+        ] {
+            let err = parse_script(bad).unwrap_err();
+            assert!(err.contains("FIRST-LAST ranges"), "{bad}: {err}");
+        }
+        // Ranges can't sneak in shell punctuation either
+        assert!(parse_script("@ typo_chars:!-~").is_err());
+    }
+
+    #[test]
+    fn test_parse_word_pause() {
+        let script = parse_script("@ word_pause:0.15\n@ word_pause:0").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetWordPause(Duration::from_millis(150)),
+                Command::SetWordPause(Duration::ZERO),
+            ]
+        );
+        assert!(parse_script("@ word_pause:-1").is_err());
+        assert!(parse_script("@ word_pause:x").is_err());
+    }
+
+    #[test]
+    fn test_parse_verify() {
+        let script = parse_script("@ verify: test -f out.txt # comment kept").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Verify(
+                "test -f out.txt # comment kept".to_string()
+            )]
+        );
+        assert!(parse_script("@ verify:").is_err());
+    }
+
+    #[test]
+    fn test_parse_backspace() {
+        let script = parse_script(
+            "$ a<bs>\n@ backspace:bs\n$ a<bs><backspace><A-bs><0x7f>\n@ backspace:del\n$ <bs>",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("a\x7f".to_string()),
+                Command::SetBackspace(BackspaceKey::Bs),
+                Command::Type("a\x08\x08\x1b\x08\x7f".to_string()),
+                Command::SetBackspace(BackspaceKey::Del),
+                Command::Type("\x7f".to_string()),
+            ]
+        );
+        assert!(parse_script("@ backspace:ctrl-h").is_err());
+
+        // A keymap passed by the caller still wins
+        let mut custom = CustomDirectives::default();
+        custom.set_keymap(Keymap::parse("[keys]\nbs = \"B\"").unwrap());
+        let script = parse_script_with("@ backspace:bs\n$ <bs><backspace>", &custom).unwrap();
+        assert_eq!(script.commands[1], Command::Type("B\x08".to_string()));
+    }
+
+    #[test]
+    fn test_parse_shell() {
+        let input = "@ shell:/bin/zsh";
+        let result = parse_shell(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::SetShell("/bin/zsh".to_string()));
+    }
+
+    #[test]
+    fn test_parse_type() {
+        let input = "$ echo hello";
+        let result = parse_type(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::Type("echo hello".to_string()));
+    }
+
+    #[test]
+    fn test_parse_type_with_special_keys() {
+        assert_eq!(
+            parse_type_content("echo hello<ret>"),
+            Ok("echo hello\r".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_type_with_ctrl() {
+        assert_eq!(parse_type_content("<C-c>"), Ok("\x03".to_string()));
+    }
+
+    #[test]
+    fn test_parse_type_with_escaped() {
+        assert_eq!(
+            parse_type_content(r"\<not a key\>"),
+            Ok("<not a key>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_type_unclosed_bracket_is_literal() {
+        // Shell redirection with no '>' on the line stays literal
+        assert_eq!(
+            parse_type_content("cat < input.txt"),
+            Ok("cat < input.txt".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_type_unknown_key_is_error() {
+        assert!(parse_type_content("<Ret>").is_err());
+        assert!(parse_type_content("<D-x>").is_err());
+        assert!(parse_type_content("cat <file >out").is_err());
+    }
+
+    #[test]
+    fn test_parse_type_range() {
+        assert_eq!(
+            parse_type_content("echo <range:1..5>"),
+            Ok("echo 1 2 3 4 5".to_string())
+        );
+        assert_eq!(
+            parse_type_content("<range:5..1>"),
+            Ok("5 4 3 2 1".to_string())
+        );
+        assert_eq!(
+            parse_type_content("<range:1..10:3>"),
+            Ok("1 4 7 10".to_string())
+        );
+        assert_eq!(
+            parse_type_content("<range:10..1:4>"),
+            Ok("10 6 2".to_string())
+        );
+        assert_eq!(
+            parse_type_content("<range:-1..1|,>"),
+            Ok("-1,0,1".to_string())
+        );
+        assert_eq!(parse_type_content("<range:3..3>"), Ok("3".to_string()));
+        assert!(parse_type_content("<range:1..10000>").is_ok());
+        assert!(parse_type_content("<range:1..20000:2>").is_ok());
+    }
+
+    #[test]
+    fn test_parse_type_range_over_limit() {
+        assert_eq!(
+            parse_type_content("<range:0..9223372036854775807>"),
+            Err(
+                "<range:0..9223372036854775807> covers 9223372036854775808 numbers, \
+                 more than the limit of 10000"
+                    .to_string()
+            )
+        );
+        assert!(parse_type_content("<range:10000..-1>").is_err());
+        let err = parse_script("$ ls\n$ seq <range:1..10001>").unwrap_err();
+        assert!(err.starts_with("Line 2: "), "{err}");
+    }
+
+    #[test]
+    fn test_parse_type_invalid_range_is_literal() {
+        for input in [
+            "<range:a..5>",
+            "<range:1..5:0>",
+            "<range:1-5>",
+            "<range:1..5:-1>",
+        ] {
+            assert_eq!(parse_type_content(input), Ok(input.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_parse_type_now() {
+        let year = chrono::Local::now().format("%Y").to_string();
+        assert_eq!(
+            parse_type_content("git tag v<now:%Y>"),
+            Ok(format!("git tag v{year}"))
+        );
+        assert_eq!(parse_type_content("<now:built>"), Ok("built".to_string()));
+        let now = parse_type_content("<now>").unwrap();
+        assert_eq!(now.len(), "2026-01-31 23:59:59".len());
+        assert!(now.starts_with(&year), "{now}");
+        for bad in ["<now:%Q>", "<now:>"] {
+            assert!(
+                parse_type_content(bad)
+                    .unwrap_err()
+                    .contains("invalid date format")
+            );
+        }
+        // Only `now` itself or `now:` starts one
+        assert!(parse_type_content("<nowhere>").is_err());
+    }
+
+    #[test]
+    fn test_resolve_key() {
+        assert_eq!(resolve_key("ret"), Ok("\r".to_string()));
+        assert_eq!(resolve_key("A-F5"), Ok("\x1b\x1b[15~".to_string()));
+        assert_eq!(
+            resolve_key("C-ret"),
+            Err(KeyError::NoControlCode {
+                spec: "C-ret".to_string()
+            })
+        );
+        assert_eq!(
+            resolve_key("D-x"),
+            Err(KeyError::UnknownModifier {
+                spec: "D-x".to_string(),
+                modifier: "D".to_string()
+            })
+        );
+        assert_eq!(
+            resolve_key("Ret"),
+            Err(KeyError::Unknown {
+                spec: "Ret".to_string()
+            })
+        );
+        assert_eq!(
+            resolve_key("A-nope").unwrap_err().to_string(),
+            "unknown key 'nope' in <A-nope>"
+        );
+    }
+
+    #[test]
+    fn test_modified_special_keys() {
+        for (spec, seq) in [
+            ("C-up", "\x1b[1;5A"),
+            ("S-left", "\x1b[1;2D"),
+            ("C-A-up", "\x1b[1;7A"),
+            ("C-S-right", "\x1b[1;6C"),
+            ("C-A-S-home", "\x1b[1;8H"),
+            ("C-F5", "\x1b[15;5~"),
+            ("C-S-F5", "\x1b[15;6~"),
+            ("S-F1", "\x1b[1;2P"),
+            ("C-delete", "\x1b[3;5~"),
+            // Alt alone keeps its ESC prefix
+            ("A-up", "\x1b\x1b[A"),
+        ] {
+            assert_eq!(resolve_key(spec), Ok(seq.to_string()), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_parse_shift_tab() {
+        assert_eq!(parse_type_content("<S-tab>"), Ok("\x1b[Z".to_string()));
+    }
+
+    #[test]
+    fn test_parse_env() {
+        let script =
+            parse_script("@ env:GREETING=hello world\n@ hostname:demo-box\n@ user:demo\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetEnv("GREETING".to_string(), "hello world".to_string()),
+                Command::SetEnv("HOSTNAME".to_string(), "demo-box".to_string()),
+                Command::SetEnv("USER".to_string(), "demo".to_string()),
+            ]
+        );
+        assert!(parse_script("@ env:=x").is_err());
+        assert!(parse_script("@ env:NO_VALUE").is_err());
+        assert!(parse_script("@ shuffle\n@ env:A=b\n@ end").is_err());
+    }
+
+    #[test]
+    fn test_parse_secret() {
+        let script = parse_script("@ secret:$SUDO_PASSWORD\n@ secret:TOKEN\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::TypeSecret("SUDO_PASSWORD".to_string()),
+                Command::TypeSecret("TOKEN".to_string()),
+            ]
+        );
+        // The value is never read at parse time, so nothing can leak from the script
+        assert_eq!(
+            script.to_source(),
+            "@ secret:$SUDO_PASSWORD\n@ secret:$TOKEN\n"
+        );
+        assert!(parse_script("@ secret:$").is_err());
+    }
+
+    #[test]
+    fn test_parse_retype() {
+        let script = parse_script("$ git comit\n@ retype:git commit -m \"x\"<ret>").unwrap();
+        assert_eq!(
+            script.commands[1],
+            Command::Retype("git commit -m \"x\"\r".to_string())
+        );
+        assert!(parse_script("@ retype:<bogus>").is_err());
+    }
+
+    #[test]
+    fn test_parse_overwrite() {
+        let script = parse_script("$ ls -la\n@ overwrite:4:-lh # size").unwrap();
+        assert_eq!(
+            script.commands[1],
+            Command::Overwrite(4, "-lh # size".to_string())
+        );
+        assert_eq!(
+            parse_script("@ overwrite:1:a:b").unwrap().commands,
+            vec![Command::Overwrite(1, "a:b".to_string())]
+        );
+        // Spaces are kept, to blank out text or to start with one
+        assert_eq!(
+            parse_script("@ overwrite:3:  ").unwrap().commands,
+            vec![Command::Overwrite(3, "  ".to_string())]
+        );
+        assert_eq!(
+            parse_script("@ overwrite:3: -h").unwrap().commands,
+            vec![Command::Overwrite(3, " -h".to_string())]
+        );
+        assert_eq!(
+            parse_script("@ overwrite:3:<space>\\<x<tab>")
+                .unwrap()
+                .commands,
+            vec![Command::Overwrite(3, " <x\t".to_string())]
+        );
+        for bad in [
+            "@ overwrite:0:x",
+            "@ overwrite:x:y",
+            "@ overwrite:3:",
+            "@ overwrite:3",
+        ] {
+            let err = parse_script(bad).unwrap_err();
+            assert!(err.contains("COL:TEXT"), "{bad}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_parse_capture() {
+        let input = "@ capture:out.txt";
+        let result = parse_capture(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::Capture("out.txt".into()));
+        assert_eq!(
+            parse_script("@ capture_reset # fresh").unwrap().commands,
+            vec![Command::ResetCapture]
+        );
+    }
+
+    #[test]
+    fn test_parse_push_pop() {
+        let script = parse_script(
+            "@ push\n@ backspace:bs\n$ <bs>\n@ push # again\n@ pop\n$ <bs>\n@ pop\n$ <bs>",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::PushConfig,
+                Command::SetBackspace(BackspaceKey::Bs),
+                Command::Type("\x08".to_string()),
+                Command::PushConfig,
+                Command::PopConfig,
+                Command::Type("\x08".to_string()),
+                Command::PopConfig,
+                // The Backspace key is restored along with the rest
+                Command::Type("\x7f".to_string()),
+            ]
+        );
+
+        let err = parse_script("@ push\n@ pop\n@ pop").unwrap_err();
+        assert_eq!(err, "Line 3: @pop without a matching @push");
+        let err = parse_script("@ shuffle\n@ push\n@ end").unwrap_err();
+        assert!(
+            err.contains("cannot appear inside a @shuffle block"),
+            "{err}"
+        );
+        // Pushes needn't be popped
+        assert!(parse_script("@ push\n@ speed:0.01").is_ok());
+    }
+
+    #[test]
+    fn test_parse_flush() {
+        assert_eq!(
+            parse_script("$ ls\n@ flush # before the next action\n@ flush")
+                .unwrap()
+                .commands,
+            vec![
+                Command::Type("ls".to_string()),
+                Command::Flush,
+                Command::Flush
+            ]
+        );
+        assert!(parse_script("@ flush:now").is_err());
+    }
+
+    #[test]
+    fn test_parse_checkpoint() {
+        assert_eq!(
+            parse_script("$ ls\n@ checkpoint # after the build")
+                .unwrap()
+                .commands,
+            vec![Command::Type("ls".to_string()), Command::Checkpoint]
+        );
+        assert!(parse_script("@ checkpoint:1").is_err());
+        let err = parse_script("@ tag:a\n@ checkpoint\n@ end").unwrap_err();
+        assert_eq!(err, "Line 2: @checkpoint cannot appear inside a @tag block");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        let input = "@ size:120:40";
+        let result = parse_size(input);
+        assert!(result.is_ok());
+        let (_, cmd) = result.unwrap();
+        assert_eq!(cmd, Command::SetSize(120, 40));
+    }
+
+    #[test]
+    fn test_size_presets() {
+        let script = parse_script(
+            "@ size:vga
+@ resize:hd:0.5 # wider",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSize(80, 25),
+                Command::Resize(160, 45, Duration::from_millis(500)),
+            ]
+        );
+        let err = parse_script("@ size:huge").unwrap_err();
+        assert_eq!(
+            err,
+            "Line 1: invalid value in @size: 'huge' must be COLS:ROWS, each between 1 and \
+             10000, or one of vt100, vga, hd, fullhd"
+        );
+        assert!(parse_script("@ size:VGA").is_err());
+    }
+
+    #[test]
+    fn test_parse_suggestion_directives() {
+        let script = parse_script("@ suggestion_key:<end>\n@ accept_suggestion\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSuggestionKey("\x1b[F".to_string()),
+                Command::AcceptSuggestion,
+            ]
+        );
+        assert!(parse_script("@ suggestion_key:<nope>").is_err());
+    }
+
+    #[test]
+    fn test_custom_directives() {
+        let mut custom = CustomDirectives::default();
+        custom.register(|name, value| match name {
+            "greet" => Some(Ok(Command::Type(format!("echo hi {value}<ret>")))),
+            "speed" => Some(Err("never consulted".to_string())),
+            "broken" => Some(Err("broken on purpose".to_string())),
+            _ => None,
+        });
+
+        let script = parse_script_with("@ greet:there\n@ speed:0.2\n", &custom).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("echo hi there\r".to_string()),
+                Command::SetSpeed(0.2.into()),
+            ]
+        );
+
+        let err = parse_script_with("$ a\n@ broken", &custom).unwrap_err();
+        assert!(
+            err.contains("Line 2: broken on purpose"),
+            "unexpected error: {err}"
+        );
+        assert!(parse_script_with("@ unknown:1", &custom).is_err());
+        assert!(parse_script("@ greet:there").is_err());
+    }
+
+    #[test]
+    fn test_size_bounds() {
+        assert!(parse_script("@ size:1:1").is_ok());
+        assert!(parse_script("@ size:10000:10000").is_ok());
+        for line in [
+            "@ size:0:0",
+            "@ size:80:0",
+            "@ size:10001:50",
+            "@ size:999999:50",
+        ] {
+            let err = parse_script(line).unwrap_err();
+            assert!(
+                err.contains("Line 1") && err.contains("between 1 and 10000"),
+                "unexpected error for {line}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_negative_directive_values_are_errors() {
+        let err = parse_script("@ wait:-1").unwrap_err();
+        assert!(err.contains("Line 1"), "unexpected error: {err}");
+        assert!(parse_script("@ speed:-0.1").is_err());
+        assert!(parse_script("@ jitter:-0.5").is_err());
+    }
+
+    #[test]
+    fn test_malformed_numbers_are_errors() {
+        for (line, value) in [
+            ("@ speed:0.2.3", "0.2.3"),
+            ("@ speed:0.2abc", "0.2abc"),
+            ("@ jitter:abc", "abc"),
+            ("@ wait:", ""),
+            ("@ wait:1 2", "1 2"),
+            ("@ wait:inf", "inf"),
+        ] {
+            let err = parse_script(&format!("$ x\n{line}")).unwrap_err();
+            let name = directive_name(line);
+            let range = if name == "wait" {
+                ""
+            } else {
+                " or a MIN..MAX range"
+            };
+            assert_eq!(
+                err,
+                format!(
+                    "Line 2: invalid number in @{name}: '{value}' must be a non-negative number{range}"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_decimal_comma_errors_suggest_a_point() {
+        assert_eq!(
+            parse_script("@ speed:0,2").unwrap_err(),
+            "Line 1: invalid number in @speed: '0,2' must be a non-negative number \
+             or a MIN..MAX range (use '.' for decimals: '0.2')"
+        );
+        let err = parse_script("@ jitter_params:0,5:0,1").unwrap_err();
+        assert!(err.ends_with("(use '.' for decimals: '0.5:0.1')"), "{err}");
+        // No suggestion when a point wouldn't fix it either
+        for line in ["@ speed:1,2,3", "@ expect_exit:0,5", "@ wait:,5"] {
+            let err = parse_script(line).unwrap_err();
+            assert!(!err.contains("decimals"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_trailing_separators_are_ignored() {
+        let script = parse_script(
+            "@ speed:0.2;\n@ wait:2.0,\n@ size:80:24 ; # comment\n@ flush,\n@ shuffle;\n@ end;",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(0.2.into()),
+                Command::Wait(Duration::from_secs(2)),
+                Command::SetSize(80, 24),
+                Command::Flush,
+                Command::Shuffle(Vec::new()),
+            ]
+        );
+        // Kept where the directive takes it as text
+        assert_eq!(
+            parse_script("@ echo:Done;").unwrap().commands,
+            vec![Command::Echo("Done;".to_string())]
+        );
+        // Only one, and not in the middle of a value
+        for line in [
+            "@ speed:0.2;;",
+            "@ wait:2,;",
+            "@ wait:;",
+            "@ wait:1; 2",
+            "@ size:80;:24",
+        ] {
+            let err = parse_script(line).unwrap_err();
+            assert!(err.contains("Line 1"), "{line}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_reparse_stable() {
+        assert!(reparse_stable("$ a<0x5c><0x5c><esc>b"));
+        assert!(reparse_stable("$ meet @after:noon\n$ echo <0x40>after:1"));
+        assert!(reparse_stable("$ <space>x<space>\n@ retype:\\<ret\\>"));
+        // Nothing to round-trip
+        assert!(reparse_stable("@ speed:fast"));
+    }
+
+    #[test]
+    fn test_parse_speed_and_jitter_ranges() {
+        let script = parse_script("@ speed:0.08..0.12\n@ jitter: 0 .. 0.5 # varied").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(ValueRange {
+                    min: 0.08,
+                    max: 0.12
+                }),
+                Command::SetJitter(ValueRange { min: 0.0, max: 0.5 }),
+            ]
+        );
+        for line in [
+            "@ speed:0.2..0.1",
+            "@ speed:0.1..",
+            "@ jitter:..1",
+            "@ speed:-1..1",
+        ] {
+            let err = parse_script(line).unwrap_err();
+            assert!(
+                err.contains("MIN..MAX"),
+                "unexpected error for {line}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_number_with_trailing_comment() {
+        let script = parse_script("@ speed:0.2 # brisk\n@ wait:1#s").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(0.2.into()),
+                Command::Wait(Duration::from_secs(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let input = r"@ speed:0.2
+@ jitter:0.02
+# This is a comment
+$ echo hello
+@ wait:1.0
+$ ls -la
+";
+        let result = parse_script(input);
+        if let Err(e) = &result {
+            eprintln!("Parse error: {e}");
+        }
+        assert!(result.is_ok());
+        let script = result.unwrap();
+        assert_eq!(script.commands.len(), 5);
+    }
+
+    #[test]
+    fn test_parse_shuffle_block() {
+        let script = parse_script("$ a\n@ shuffle\n$ b\n@ wait:1\n$ c\n@ end\n$ d\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("a".to_string()),
+                Command::Shuffle(vec![
+                    Command::Type("b".to_string()),
+                    Command::Wait(Duration::from_secs(1)),
+                    Command::Type("c".to_string()),
+                ]),
+                Command::Type("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shuffle_block_errors() {
+        let err = parse_script("@ shuffle\n$ a\n").unwrap_err();
+        assert!(err.contains("Line 1"), "unexpected error: {err}");
+        let err = parse_script("$ a\n@ end\n").unwrap_err();
+        assert!(err.contains("Line 2"), "unexpected error: {err}");
+        let err = parse_script("@ shuffle\n@ size:80:24\n@ end\n").unwrap_err();
+        assert!(err.contains("Line 2"), "unexpected error: {err}");
+        assert!(parse_script("@ shuffle\n@ shell:/bin/sh\n@ end\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_noecho_block() {
+        let script = parse_script("$ sudo -v<ret>\n@ noecho\n$ hunter2<ret>\n@ end").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("sudo -v\r".to_string()),
+                Command::NoEcho(vec![Command::Type("hunter2\r".to_string())]),
+            ]
+        );
+        let err = parse_script("@ noecho\n@ env:A=1\n@ end").unwrap_err();
+        assert!(err.contains("@noecho block"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_parse_tag_block() {
+        let script = parse_script(
+            "@ tag:intro|git-101 # first demo\n$ ls<ret>\n@ tag:deep\n$ pwd\n@ end\n@ end",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Tagged(
+                vec!["intro".to_string(), "git-101".to_string()],
+                vec![
+                    Command::Type("ls\r".to_string()),
+                    Command::Tagged(
+                        vec!["deep".to_string()],
+                        vec![Command::Type("pwd".to_string())]
+                    ),
+                ],
+            )]
+        );
+        for bad in ["@ tag:", "@ tag:a||b", "@ tag:two words"] {
+            let err = parse_script(&format!("{bad}\n@ end")).unwrap_err();
+            assert!(err.contains("tag names separated by '|'"), "{bad}: {err}");
+        }
+        let err = parse_script("@ tag:intro\n@ size:80:24\n@ end").unwrap_err();
+        assert!(err.contains("@tag block"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_parse_paste_block() {
+        let script =
+            parse_script("@ paste\n$ if true; then\n$\n$ echo <tab>hi\n$ fi\n@ end\n$ <ret>")
+                .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Paste(vec![
+                    "if true; then".to_string(),
+                    String::new(),
+                    "echo \thi".to_string(),
+                    "fi".to_string(),
+                ]),
+                Command::Type("\r".to_string()),
+            ]
+        );
+        for bad in [
+            "@ paste\n@ wait:1\n@ end",
+            "@ paste\n$ a @after:1\n@ end",
+            "@ paste\n$ a\n> b\n@ end",
+            "@ paste\n@ noecho\n@ end\n@ end",
+        ] {
+            let err = parse_script(bad).unwrap_err();
+            assert!(
+                err.contains("Line 2") || err.contains("Line 3"),
+                "{bad}: {err}"
+            );
+            assert!(err.contains("inside a @paste block"), "{bad}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_parse_wait_until() {
+        let at = |h, m, s| chrono::NaiveTime::from_hms_opt(h, m, s).unwrap();
+        let script =
+            parse_script("@ wait_until:09:30:00\n@ wait_until: 21:05:30:next_day # stream")
+                .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::WaitUntil(at(9, 30, 0), false),
+                Command::WaitUntil(at(21, 5, 30), true),
+            ]
+        );
+        for value in ["9:30", "25:00:00", "09:30:00:tomorrow", ""] {
+            let err = parse_script(&format!("@ wait_until:{value}")).unwrap_err();
+            assert_eq!(
+                err,
+                format!(
+                    "Line 1: invalid time in @wait_until: '{value}' must be a local time as \
+                     HH:MM:SS, optionally followed by :next_day"
+                )
+            );
+        }
     }
-    Err(format!("<{spec}> has no standard escape sequence"))
-}
 
-fn parse_type_content(input: &str) -> Result<String, String> {
-    let mut result = String::new();
-    let mut remaining = input;
+    #[test]
+    fn test_parse_live_block() {
+        let script = parse_script("@ live\n$ ls<ret>\n@ wait:5\n@ end").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Live(vec![
+                Command::Type("ls\r".to_string()),
+                Command::Wait(Duration::from_secs(5)),
+            ])]
+        );
+        let err = parse_script("@ live\n@ size:80:24\n@ end").unwrap_err();
+        assert!(
+            err.contains("inside a @live block"),
+            "unexpected error: {err}"
+        );
+    }
 
-    while !remaining.is_empty() {
-        if remaining.starts_with("\\<") || remaining.starts_with("\\>") {
-            result.push_str(&remaining[1..2]);
-            remaining = &remaining[2..];
-        } else if remaining.starts_with('<') {
-            if let Ok((rest, spec)) = parse_key_spec(remaining) {
-                result.push_str(&resolve_key(spec)?);
-                remaining = rest;
-            } else {
-                // No closing '>' on the line: literal '<' (e.g. shell redirection)
-                result.push('<');
-                remaining = &remaining[1..];
-            }
-        } else {
-            let c = remaining.chars().next().unwrap();
-            result.push(c);
-            remaining = &remaining[c.len_utf8()..];
+    #[test]
+    fn test_parse_glob() {
+        let dir = std::env::temp_dir().join(format!("quipu-glob-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.txt", "a.txt", "it's here.txt", "c.md"] {
+            std::fs::write(dir.join(name), "").unwrap();
         }
+        let dir_name = dir.display();
+        let script =
+            parse_script(&format!("$ cat<space>\n@ glob:{dir_name}/*.txt\n$ <ret>")).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("cat ".to_string()),
+                Command::Type(format!(
+                    "{dir_name}/a.txt {dir_name}/b.txt '{dir_name}/it'\\''s here.txt'"
+                )),
+                Command::Type("\r".to_string()),
+            ]
+        );
+
+        let err = parse_script(&format!("$ ls\n@ glob:{dir_name}/*.rs")).unwrap_err();
+        assert!(err.starts_with("Line 2: @glob:"), "unexpected error: {err}");
+        assert!(err.contains("matches no paths"), "unexpected error: {err}");
+        let script = parse_script(&format!("@ glob_optional:{dir_name}/*.rs")).unwrap();
+        assert_eq!(script.commands, vec![Command::Type(String::new())]);
+        assert!(parse_script("@ glob:[").is_err());
+        assert!(parse_script("@ glob:").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(result)
-}
+    #[test]
+    fn test_allowed_bytes() {
+        let allowed = AllowedBytes::parse("printable, 0x0d,0x00-0x02").unwrap();
+        assert_eq!(allowed.first_disallowed("ls -l\r\x01"), None);
+        assert_eq!(allowed.first_disallowed("ls\t"), Some(b'\t'));
+        assert_eq!(allowed.first_disallowed("caf\u{e9}"), Some(0xc3));
+        for bad in ["", "0x100", "27", "0x1f-0x00", "ascii"] {
+            assert!(AllowedBytes::parse(bad).is_err(), "{bad}");
+        }
 
-// Returns the raw text; special keys are expanded in parse_script so
-// unknown key specs can be reported with a line number
-fn parse_type(input: &str) -> IResult<&str, Command> {
-    let (input, _) = char('$')(input)?;
-    let (input, _) = space0(input)?;
-    let (input, text) = not_line_ending(input)?;
-    Ok((input, Command::Type(text.to_string())))
-}
+        let mut custom = CustomDirectives::default();
+        let safe = AllowedBytes::parse("safe").unwrap();
+        custom.set_allowed_bytes(safe.clone(), ByteCheck::Error);
+        let script = "$ echo caf\u{e9}<tab><ret>\n@ retype:x<bs>";
+        assert!(parse_script_with(script, &custom).is_ok());
+        for (input, line) in [
+            ("$ sleep 9<C-c>", 1),
+            ("$ ls\n> <esc>", 2),
+            ("$ ls\n@ retype:<up>", 2),
+            ("@ overwrite:1:a\x07b", 1),
+            ("@ suggestion_key:<right>", 1),
+        ] {
+            let err = parse_script_with(input, &custom).unwrap_err();
+            assert!(
+                err.starts_with(&format!("Line {line}: types 0x")),
+                "{input}: {err}"
+            );
+        }
+        assert_eq!(
+            parse_script_with("$ a<C-c>", &custom).unwrap_err(),
+            "Line 1: types 0x03 (<C-c>), which is not allowed"
+        );
+        custom.set_allowed_bytes(safe, ByteCheck::Warn);
+        assert!(parse_script_with("$ a<C-c>", &custom).is_ok());
+    }
 
-fn parse_line(input: &str) -> IResult<&str, Option<Command>> {
-    alt((
-        map(parse_directive, Some),
-        value(None, parse_comment),
-        map(parse_type, Some),
-    ))
-    .parse(input)
-}
+    #[test]
+    fn test_parse_after_attribute() {
+        let script = parse_script("$ npm install<ret> @after:3\n$ ls<ret>").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("npm install\r".to_string()),
+                Command::Wait(Duration::from_secs(3)),
+                Command::Type("ls\r".to_string()),
+            ]
+        );
+    }
 
-pub fn parse_script(input: &str) -> Result<Script, String> {
-    let mut commands = Vec::new();
+    #[test]
+    fn test_parse_visible_continuation() {
+        let script =
+            parse_script("$ docker run --rm\n>   -it alpine\n> sh<ret> @after:2\n").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("docker run --rm \\\r-it alpine \\\rsh\r".to_string()),
+                Command::Wait(Duration::from_secs(2)),
+            ]
+        );
+        assert_eq!(
+            parse_script(&script.to_source()).unwrap().commands,
+            script.commands
+        );
 
-    for (line_num, line) in input.lines().enumerate() {
-        let trimmed = line.trim();
+        let err = parse_script("@ wait:1\n> sh").unwrap_err();
+        assert!(err.contains("Line 2"), "unexpected error: {err}");
+        assert!(parse_script("$ a @after:1\n> b").is_err());
+    }
 
-        if trimmed.is_empty() {
-            continue;
+    #[test]
+    fn test_after_attribute_needs_a_valid_wait() {
+        for line in [
+            "$ mail me @after:noon",
+            "$ x @after:-1",
+            "$ user@after:3",
+            "$ x <0x40>after:3",
+        ] {
+            let script = parse_script(line).unwrap();
+            assert_eq!(script.commands.len(), 1, "{line}");
         }
+        assert_eq!(encode_type_content("x @after:3"), "x <0x40>after:3");
+    }
 
-        match parse_line(trimmed) {
-            Ok((remaining, Some(cmd))) => {
-                if !remaining.trim().is_empty() {
-                    return Err(format!(
-                        "Line {}: Unexpected text after command: '{}'",
-                        line_num + 1,
-                        remaining
-                    ));
-                }
-                let cmd = match cmd {
-                    Command::Type(raw) => Command::Type(
-                        parse_type_content(&raw)
-                            .map_err(|e| format!("Line {}: {e}", line_num + 1))?,
-                    ),
-                    other => other,
-                };
-                commands.push(cmd);
-            }
-            Ok((_, None)) => {}
-            Err(nom::Err::Failure(e)) if e.code == nom::error::ErrorKind::Verify => {
-                return Err(format!(
-                    "Line {}: invalid directive value '{}': must be a non-negative number",
-                    line_num + 1,
-                    e.input
-                ));
-            }
-            Err(e) => {
-                return Err(format!("Line {}: Parse error: {}", line_num + 1, e));
-            }
-        }
+    #[test]
+    fn test_parse_hex_byte() {
+        assert_eq!(parse_type_content("<0x1e>"), Ok("\x1e".to_string()));
+        assert!(parse_type_content("<0xff>").is_err());
     }
 
-    Ok(Script { commands })
-}
+    #[test]
+    fn test_encode_type_content() {
+        assert_eq!(encode_type_content("ls -la\r"), "ls -la<ret>");
+        assert_eq!(encode_type_content("\x1b[A\x03"), "<up><C-c>");
+        assert_eq!(encode_type_content("a <b> c"), r"a \<b\> c");
+        assert_eq!(encode_type_content(" x "), "<space>x<space>");
+        assert_eq!(encode_type_content("\x1e\x1f"), "<C-^><C-/>");
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_display_keys() {
+        let text = "ls\t-l\x1b[A\x7f\r";
+        assert_eq!(
+            display_keys(text, KeyNotation::Names),
+            "ls<tab>-l<up><bs><ret>"
+        );
+        assert_eq!(display_keys(text, KeyNotation::Caret), "ls^I-l^[[A^?^M");
+        assert_eq!(display_keys("\x00\x1f é", KeyNotation::Caret), "^@^_ é");
+    }
 
     #[test]
-    fn test_parse_speed() {
-        let input = "@ speed:0.2";
-        let result = parse_speed(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::SetSpeed(0.2));
+    fn test_lenient_unknown_directives() {
+        let input = "@path/to/args.txt<ret>\n@ speed:0.2\n@ greet:you";
+        assert!(parse_script(input).is_err());
+
+        let mut custom = CustomDirectives::default();
+        custom.register(|name, value| {
+            (name == "greet").then(|| Ok(Command::Type(format!("hi {value}"))))
+        });
+        custom.set_lenient(true);
+        let script = parse_script_with(input, &custom).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("@path/to/args.txt\r".to_string()),
+                Command::SetSpeed(0.2.into()),
+                Command::Type("hi you".to_string()),
+            ]
+        );
+        // Known directives with bad values are still errors
+        assert!(parse_script_with("@ speed:fast", &custom).is_err());
     }
 
     #[test]
-    fn test_parse_jitter() {
-        let input = "@ jitter:0.02";
-        let result = parse_jitter(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::SetJitter(0.02));
+    fn test_remote_scripts() {
+        let mut custom = CustomDirectives::default();
+        custom.register(|name, value| {
+            (name == "check").then(|| Ok(Command::Verify(value.to_string())))
+        });
+        custom.set_remote(true);
+        let script = parse_script_with("@ speed:0.05\n$ ls<ret>\n@ echo:hi", &custom).unwrap();
+        assert_eq!(script.commands.len(), 3);
+
+        for (input, name) in [
+            ("@ shell:/bin/bash", "shell"),
+            ("@ secret:$HOME", "secret"),
+            ("@ capture:out.txt", "capture"),
+            ("@ verify:true", "verify"),
+            ("@ theme:dark.toml", "theme"),
+            ("@ keymap:keys.toml", "keymap"),
+            ("@ glob:*.txt", "glob"),
+            ("@ check:true", "verify"),
+        ] {
+            let err = parse_script_with(&format!("$ ls\n@ shuffle\n{input}\n@ end"), &custom)
+                .unwrap_err();
+            assert_eq!(
+                err,
+                format!("Line 3: @{name} is not allowed in a remote script")
+            );
+        }
     }
 
     #[test]
-    fn test_parse_wait() {
-        let input = "@ wait:2.0";
-        let result = parse_wait(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::Wait(Duration::from_secs_f64(2.0)));
+    fn test_parse_resize() {
+        let script = parse_script("@ resize:120:40\n@ resize:60:20:1.5").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Resize(120, 40, Duration::ZERO),
+                Command::Resize(60, 20, Duration::from_millis(1500)),
+            ]
+        );
+        for line in ["@ resize:0:24", "@ resize:80:10001", "@ resize:80:24:-1"] {
+            assert!(parse_script(line).is_err(), "{line}");
+        }
+        let err = parse_script("@ resize:80:0").unwrap_err();
+        assert!(
+            err.contains("between 1 and 10000"),
+            "unexpected error: {err}"
+        );
     }
 
     #[test]
-    fn test_parse_shell() {
-        let input = "@ shell:/bin/zsh";
-        let result = parse_shell(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::SetShell("/bin/zsh".to_string()));
+    fn test_parse_wait_key() {
+        let script = parse_script("@ wait_key\n@ wait_key:<ret>\n@ wait_key: q ").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::WaitKey(None),
+                Command::WaitKey(Some("\r".to_string())),
+                Command::WaitKey(Some("q".to_string())),
+            ]
+        );
+        assert!(parse_script("@ wait_key:<nope>").is_err());
     }
 
     #[test]
-    fn test_parse_type() {
-        let input = "$ echo hello";
-        let result = parse_type(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::Type("echo hello".to_string()));
+    fn test_parse_wait_enter() {
+        let script = parse_script("@ wait_enter\n@wait_enter # next slide").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::WaitKey(Some("\r".to_string())); 2]
+        );
+        assert!(parse_script("@ wait_enter:now").is_err());
     }
 
     #[test]
-    fn test_parse_type_with_special_keys() {
+    fn test_parse_wait_response() {
+        let script =
+            parse_script("@ wait_response:<esc>[c:1.5 # DA1\n@ wait_response:<esc>]11;?<0x07>:2")
+                .unwrap();
         assert_eq!(
-            parse_type_content("echo hello<ret>"),
-            Ok("echo hello\r".to_string())
+            script.commands,
+            vec![
+                Command::WaitResponse("\x1b[c".to_string(), Duration::from_millis(1500)),
+                Command::WaitResponse("\x1b]11;?\x07".to_string(), Duration::from_secs(2)),
+            ]
         );
+        for bad in [
+            "@ wait_response:<esc>[c",
+            "@ wait_response::1",
+            "@ wait_response:<esc>[c:-1",
+        ] {
+            assert!(parse_script(bad).is_err(), "{bad}");
+        }
     }
 
     #[test]
-    fn test_parse_type_with_ctrl() {
-        assert_eq!(parse_type_content("<C-c>"), Ok("\x03".to_string()));
+    fn test_parse_autoenter() {
+        let script = parse_script("@ autoenter:on\n$\n@ autoenter:off").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetAutoEnter(true),
+                Command::Type(String::new()),
+                Command::SetAutoEnter(false),
+            ]
+        );
+        assert!(parse_script("@ autoenter:yes").is_err());
     }
 
     #[test]
-    fn test_parse_type_with_escaped() {
+    fn test_parse_ready_sentinel() {
+        let script = parse_script("@ ready_sentinel:on\n@ ready_sentinel:off").unwrap();
         assert_eq!(
-            parse_type_content(r"\<not a key\>"),
-            Ok("<not a key>".to_string())
+            script.commands,
+            vec![
+                Command::SetReadySentinel(true),
+                Command::SetReadySentinel(false),
+            ]
         );
+        assert!(parse_script("@ ready_sentinel:yes").is_err());
+        assert!(parse_script("@ noecho\n@ ready_sentinel:on\n@ end").is_err());
     }
 
     #[test]
-    fn test_parse_type_unclosed_bracket_is_literal() {
-        // Shell redirection with no '>' on the line stays literal
+    fn test_parse_prewarm() {
+        let script =
+            parse_script("@ prewarm:on # absorb first-command lag\n@ prewarm:off").unwrap();
         assert_eq!(
-            parse_type_content("cat < input.txt"),
-            Ok("cat < input.txt".to_string())
+            script.commands,
+            vec![Command::SetPrewarm(true), Command::SetPrewarm(false)]
         );
+        assert!(parse_script("@ prewarm:1").is_err());
+        let err = parse_script("@ shuffle\n@ prewarm:on\n@ end").unwrap_err();
+        assert!(err.contains("@prewarm and @umask cannot appear"), "{err}");
     }
 
     #[test]
-    fn test_parse_type_unknown_key_is_error() {
-        assert!(parse_type_content("<Ret>").is_err());
-        assert!(parse_type_content("<D-x>").is_err());
-        assert!(parse_type_content("cat <file >out").is_err());
+    fn test_parse_umask() {
+        let script = parse_script(
+            "@ umask:022
+@ umask:0077 # private
+@ umask:0",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetUmask(0o022),
+                Command::SetUmask(0o077),
+                Command::SetUmask(0),
+            ]
+        );
+        for invalid in [
+            "@ umask:",
+            "@ umask:800",
+            "@ umask:1000",
+            "@ umask:u=rwx",
+            "@ umask:-1",
+        ] {
+            let err = parse_script(invalid).unwrap_err();
+            assert!(err.contains("octal mask"), "{err}");
+        }
+        assert!(
+            parse_script(
+                "@ tag:setup
+@ umask:022
+@ end"
+            )
+            .is_err()
+        );
     }
 
     #[test]
-    fn test_parse_shift_tab() {
-        assert_eq!(parse_type_content("<S-tab>"), Ok("\x1b[Z".to_string()));
+    fn test_parse_jitter_params() {
+        let script = parse_script("@ jitter_params:1:0.2 # human\n@ jitter_params:off").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetJitterParams(Some(JitterParams {
+                    mean: 1.0,
+                    stddev: 0.2,
+                })),
+                Command::SetJitterParams(None),
+            ]
+        );
+        for bad in ["1", "1:-0.2", "-1:0.2", "x:y", "1:0.2:3"] {
+            let err = parse_script(&format!("@ jitter_params:{bad}")).unwrap_err();
+            assert!(err.contains("MEAN:STDDEV"), "unexpected error: {err}");
+        }
     }
 
     #[test]
-    fn test_parse_capture() {
-        let input = "@ capture:out.txt";
-        let result = parse_capture(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::Capture("out.txt".into()));
+    fn test_parse_long_lines() {
+        let script =
+            parse_script("@ long_lines:40\n@ long_lines:60:2.5 # paste\n@ long_lines:off").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetLongLines(Some(LongLines {
+                    threshold: 40,
+                    max_speedup: LongLines::DEFAULT_MAX_SPEEDUP,
+                })),
+                Command::SetLongLines(Some(LongLines {
+                    threshold: 60,
+                    max_speedup: 2.5,
+                })),
+                Command::SetLongLines(None),
+            ]
+        );
+        for bad in ["0", "-1", "x", "40:0.5", "40:", "on"] {
+            let err = parse_script(&format!("@ long_lines:{bad}")).unwrap_err();
+            assert!(err.contains("MAX_SPEEDUP"), "unexpected error: {err}");
+        }
     }
 
     #[test]
-    fn test_parse_size() {
-        let input = "@ size:120:40";
-        let result = parse_size(input);
-        assert!(result.is_ok());
-        let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::SetSize(120, 40));
+    fn test_parse_seed() {
+        let script = parse_script("@ seed:12345\n@ seed:0 # fixed").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::SetSeed(12345), Command::SetSeed(0)]
+        );
+        let err = parse_script("@ seed:1.5").unwrap_err();
+        assert!(err.contains("whole number"), "unexpected error: {err}");
+        assert!(parse_script("@ seed:-1").is_err());
+        assert!(parse_script("@ shuffle\n@ seed:1\n@ end").is_err());
+        assert!(parse_script("$ ls\n@ seed:1").unwrap().partition().is_err());
     }
 
     #[test]
-    fn test_negative_directive_values_are_errors() {
-        let err = parse_script("@ wait:-1").unwrap_err();
-        assert!(err.contains("Line 1"), "unexpected error: {err}");
-        assert!(parse_script("@ speed:-0.1").is_err());
-        assert!(parse_script("@ jitter:-0.5").is_err());
+    fn test_parse_set_title() {
+        let script = parse_script("@ set_title: My Demo \n@set_title:").unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetTitle("My Demo".to_string()),
+                Command::SetTitle(String::new())
+            ]
+        );
     }
 
     #[test]
-    fn test_parse_script() {
-        let input = r"@ speed:0.2
+    fn test_to_source_round_trips() {
+        let input = r"@ speed:0.08
 @ jitter:0.02
-# This is a comment
-$ echo hello
-@ wait:1.0
-$ ls -la
+@ speed:0.05..0.1
+@ word_typos:0.1
+@ jitter_params:1:0.25
+@ jitter_params:off
+@ typo_chars:a-z0-9_-
+@ long_lines:40:2.5
+@ long_lines:off
+@ sync:on
+@ size:100:30
+@ shell:/bin/bash
+@ env:PS1=$ 
+@ seed:42
+@ ready_sentinel:on
+@ prewarm:on
+@ umask:027
+$ echo \<hi\> \\ there<ret>
+$ <C-x><C-s><esc>:wq<ret><S-tab><F5><0x1f>
+@ shuffle
+$ one<ret>
+@ wait:0.25
+@ end
+@ noecho
+$ hunter2<ret>
+@ end
+@ tag:intro|git
+$ git status<ret>
+@ end
+@ paste
+$ for f in *; do
+$
+$ echo $f; done
+@ end
+@ wait_until:09:30:00
+@ wait_until:23:59:59:next_day
+@ live
+$ make<ret>
+@ wait:5
+@ end
+@ capture:/tmp/out.txt
+@ capture_reset
+@ flush
+@ checkpoint
+@ expect_exit:0
+@ secret:$SUDO_PASSWORD
+$ git comit
+@ retype:git commit<ret>
+@ overwrite:5:commit
+@ suggestion_key:<end>
+@ accept_suggestion
+@ echo:{bold}Step 1{/}: install
+@ reveal:Now {green}watch{/} closely
+@ subtitle:Installing dependencies
+@ set_title:My Demo
+@ theme:themes/solarized.toml
+@ reset_theme
+@ resize:120:40
+@ resize:60:20:0.5
+@ wait_key
+@ wait_key:<ret>
+@ wait_response:<esc>[c:0.5
+@ verify:test -d /tmp
+@ word_pause:0.25
+@ backspace:bs
+$ a<bs><0x7f><C-h>
+@ retype:b<bs>
+@ backspace:del
+$ a<bs><C-h>
+@ push
+@ backspace:bs
+$ <bs>
+@ pop
+$ <bs>
+@ autoenter:on
+$
+@ autoenter:off
 ";
-        let result = parse_script(input);
-        if let Err(e) = &result {
-            eprintln!("Parse error: {e}");
-        }
-        assert!(result.is_ok());
-        let script = result.unwrap();
-        assert_eq!(script.commands.len(), 5);
+        let script = parse_script(input).unwrap();
+        let reparsed = parse_script(&script.to_source()).unwrap();
+        assert_eq!(script.commands, reparsed.commands);
+    }
+
+    #[test]
+    fn test_if_os_blocks() {
+        let here = std::env::consts::OS;
+        let elsewhere = if here == "linux" { "windows" } else { "linux" };
+        let script = parse_script(&format!(
+            "@ if_os:{elsewhere}|{here}\n$ a\n@ else\n$ b\n@ end\n\
+             @ if_os:{elsewhere}\n$ c\n@ else\n$ d\n@ end\n\
+             @ if_os:{elsewhere}\n$ e\n@ end\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("a".to_string()),
+                Command::Type("d".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_if_os_blocks() {
+        let here = std::env::consts::OS;
+        let script = parse_script(&format!(
+            "@ shuffle\n@ if_os:{here}\n$ a\n@ if_os:{here}\n$ b\n@ end\n@ end\n@ end\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Shuffle(vec![
+                Command::Type("a".to_string()),
+                Command::Type("b".to_string()),
+            ])]
+        );
+
+        // Setup directives may vary by OS, but not inside a shuffle
+        assert!(parse_script(&format!("@ if_os:{here}\n@ shell:/bin/sh\n@ end")).is_ok());
+        assert!(
+            parse_script(&format!(
+                "@ shuffle\n@ if_os:{here}\n@ size:80:24\n@ end\n@ end"
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_if_os_errors() {
+        let err = parse_script("@ if_os:amiga\n@ end").unwrap_err();
+        assert!(
+            err.contains("Line 1") && err.contains("OS names"),
+            "unexpected error: {err}"
+        );
+        let err = parse_script("@ shuffle\n@ else\n@ end").unwrap_err();
+        assert!(err.contains("Line 2"), "unexpected error: {err}");
+        let err = parse_script("@ if_os:linux\n@ else\n@ else\n@ end").unwrap_err();
+        assert!(err.contains("Line 3"), "unexpected error: {err}");
+        assert!(parse_script("@ if_os:linux\n$ a\n").is_err());
     }
 
     #[test]
@@ -484,6 +3549,126 @@ $ ls -la
         assert_eq!(parse_type_content("<C-A-c>"), Ok("\x1b\x03".to_string()));
     }
 
+    #[test]
+    fn test_parse_ctrl_symbols() {
+        assert_eq!(parse_type_content("<C-/>"), Ok("\x1f".to_string()));
+        assert_eq!(parse_type_content("<C-^>"), Ok("\x1e".to_string()));
+        assert_eq!(parse_type_content("<C-@>"), Ok("\x00".to_string()));
+        assert_eq!(parse_type_content("<C-]>"), Ok("\x1d".to_string()));
+        assert_eq!(parse_type_content("<A-C-/>"), Ok("\x1b\x1f".to_string()));
+    }
+
+    #[test]
+    fn test_keymap_overrides_built_in_keys() {
+        let input = "$ <home><A-home><menu><up>\n@ keymap:tests/fixtures/keymap.toml\n$ <home><A-home><menu><up>";
+        let script = parse_script(input);
+        assert!(script.unwrap_err().contains("unknown key <menu>"));
+
+        let input = input.split_once('\n').unwrap().1;
+        let script = parse_script(input).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Type(
+                "\x1b[1~\x1b\x1b[1~\x1b[29~\x1b[A".to_string()
+            )]
+        );
+
+        // Keys set by the caller win over the script's keymap
+        let mut custom = CustomDirectives::default();
+        custom.set_keymap(crate::keymap::Keymap::parse("[keys]\nhome = \"H\"").unwrap());
+        let script = parse_script_with(input, &custom).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Type("H\x1bH\x1b[29~\x1b[A".to_string())]
+        );
+
+        let err = parse_script("@ keymap:tests/fixtures/missing.toml").unwrap_err();
+        assert!(err.starts_with("Line 1: Failed to read keymap"), "{err}");
+        assert!(parse_script("@ keymap:").is_err());
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        let scripts = [
+            "$ echo hi",
+            "$ echo hi<ret> @after:1",
+            "$ docker run\n> alpine",
+            "@ wait:1",
+            "@ echo:off",
+            "@ shuffle\n$ a\n@ end",
+            "$ ls\n# done",
+        ];
+        for script in scripts {
+            let expected = parse_script(script).unwrap().commands;
+            for ending in ["\n", "\r\n", "\n\n", "\r\n\r\n"] {
+                let with_endings = script.replace('\n', ending) + ending;
+                assert_eq!(
+                    parse_script(&with_endings).unwrap().commands,
+                    expected,
+                    "{with_endings:?}"
+                );
+            }
+        }
+        // Trailing text is kept exactly whether or not a newline follows it
+        let expected = vec![Command::Type("echo \"hi\"".to_string())];
+        assert_eq!(parse_script("$ echo \"hi\"").unwrap().commands, expected);
+        assert_eq!(
+            parse_script("$ echo \"hi\"\r\n").unwrap().commands,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_terminfo() {
+        let err = parse_script("@ terminfo:").unwrap_err();
+        assert!(err.contains("on, off or a terminal type"), "{err}");
+        assert_eq!(
+            parse_script("@ terminfo:off\n$ <up>").unwrap().commands,
+            vec![Command::Type("\x1b[A".to_string())]
+        );
+        #[cfg(not(feature = "terminfo"))]
+        {
+            let err = parse_script("@ terminfo:on").unwrap_err();
+            assert!(err.contains("terminfo feature"), "{err}");
+        }
+    }
+
+    #[cfg(feature = "terminfo")]
+    #[test]
+    fn test_terminfo_keys() {
+        let up = |term: &str| {
+            let keymap = crate::terminfo::keymap(term).unwrap();
+            keymap.get("up").unwrap().to_string()
+        };
+        // The script's keymap wins over terminfo, and off goes back to the
+        // built-in keys
+        let script = parse_script(
+            "$ <up>\n@ terminfo:on\n$ <up><C-c>\n@ env:TERM=vt100\n@ terminfo:on\n$ <up>\n\
+             @ keymap:tests/fixtures/keymap.toml\n$ <home>\n@ terminfo:off\n$ <up>",
+        )
+        .unwrap();
+        let typed: Vec<_> = script
+            .commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::Type(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            typed,
+            [
+                "\x1b[A".to_string(),
+                up(SESSION_TERM) + "\x03",
+                up("vt100"),
+                "\x1b[1~".to_string(),
+                "\x1b[A".to_string(),
+            ]
+        );
+        let err = parse_script("@ terminfo:no-such-terminal").unwrap_err();
+        assert!(err.starts_with("Line 1: no terminfo entry"), "{err}");
+    }
+
     #[test]
     fn test_example_scripts_parse() {
         let examples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
@@ -491,9 +3676,11 @@ $ ls -la
             let path = entry.unwrap().path();
             if path.extension().is_some_and(|e| e == "qp") {
                 let content = std::fs::read_to_string(&path).unwrap();
+                let script = parse_script(&content);
+                assert!(script.is_ok(), "failed to parse {}", path.display());
                 assert!(
-                    parse_script(&content).is_ok(),
-                    "failed to parse {}",
+                    script.unwrap().partition().is_ok(),
+                    "misplaced setup in {}",
                     path.display()
                 );
             }
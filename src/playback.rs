@@ -17,22 +17,620 @@
 //!
 //! Executes parsed commands with proper timing and jitter
 
-use anyhow::Result;
-use rand::RngExt;
+use anyhow::{Context, Result};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{RngExt, SeedableRng};
+use std::borrow::Cow;
+use std::future::Future;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
 };
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{Instant, sleep, sleep_until};
 
-use crate::pty::PtyManager;
-use crate::types::{Command, PlaybackConfig, Script};
+use crate::captions::{self, Cue};
+use crate::pty::{EXIT_TIMEOUT, PtyManager, WriteError};
+use crate::theme::{self, Theme};
+use crate::types::{
+    Command, Ending, Keepalive, KeyNotation, PlaybackConfig, Script, SyncMode, ValueRange,
+    WriteErrorPolicy,
+};
+
+// Stands in for each key of a secret in the keystroke log
+const SECRET_KEY: &str = "<secret>";
+// Bracketed paste markers around a `@ paste` block's text
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+// Minimum pause before accepting an autosuggestion, so the shell has a chance
+// to start rendering it
+const SUGGESTION_PAUSE: Duration = Duration::from_millis(200);
+// Output is considered settled after this long without any
+const OUTPUT_QUIET: Duration = Duration::from_millis(100);
+// Upper bound on waiting for output to settle
+const OUTPUT_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+const IDLE_POLL: Duration = Duration::from_millis(10);
+// Longest wait for the next prompt in SyncMode::Prompt. If a shell never
+// prints a prompt marker in this time, it is taken not to support them.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(5);
+// Time for the rest of a terminal's answer to arrive once it starts
+const RESPONSE_SETTLE: Duration = Duration::from_millis(50);
+// Printed by the shell to show it is ready for input, possibly after a prompt
+// drawn late. The command that prints it splits the word with an empty quote,
+// so its own echo doesn't match, and starts with a space to keep it out of
+// shell history where that is ignored.
+const READY_SENTINEL: &str = "__QUIPU_READY__";
+const READY_COMMAND: &str = " echo __QUIPU_\"\"READY__\r";
+// Longest wait for the shell to print the ready sentinel
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+// Sent by @prewarm: a no-op, with a leading space to keep it out of shell
+// history where that is ignored
+const PREWARM_COMMAND: &str = " :\r";
+// After the shell answers the prewarm command, how long it must stay quiet to
+// be taken as done, and the longest the whole prewarm may take
+const PREWARM_QUIET: Duration = Duration::from_millis(500);
+const PREWARM_TIMEOUT: Duration = Duration::from_secs(10);
+// Words shorter than this are never mistyped
+const MIN_TYPO_WORD: usize = 3;
+// Keystrokes' worth of pause before a mistyped word is noticed and erased
+const TYPO_NOTICE_KEYSTROKES: u32 = 4;
+// Pause before clearing the screen for Ending::Clear, so the last command's
+// output is seen
+const CLEAR_HOLD: Duration = Duration::from_secs(1);
+
+// What a command hook asks the engine to do once it completes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HookAction {
+    #[default]
+    Continue,
+    // Hold playback for this long before carrying on
+    Pause(Duration),
+    // Stop playback as if interrupted
+    Stop,
+}
+
+type HookFuture = Pin<Box<dyn Future<Output = HookAction> + Send>>;
+type CommandHook = Box<dyn FnMut(&Command) -> HookFuture + Send>;
+type TypeTransform = Box<dyn FnMut(&str) -> String + Send>;
+
+fn boxed_hook<F, Fut>(mut hook: F) -> CommandHook
+where
+    F: FnMut(&Command) -> Fut + Send + 'static,
+    Fut: Future<Output = HookAction> + Send + 'static,
+{
+    Box::new(move |command| Box::pin(hook(command)))
+}
+
+// SGR parameters for the {tag} markup accepted by @echo
+fn markup_sgr(tag: &str) -> Option<&'static str> {
+    Some(match tag {
+        "/" => "0",
+        "bold" => "1",
+        "dim" => "2",
+        "italic" => "3",
+        "underline" => "4",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        _ => return None,
+    })
+}
+
+// Translate {colour} markup into SGR escape codes. Unknown tags are left as
+// written, and a caption that styles anything is reset at its end.
+fn render_markup(text: &str) -> String {
+    let mut result = String::new();
+    let mut styled = false;
+    let mut remaining = text;
+
+    while let Some(open) = remaining.find('{') {
+        result.push_str(&remaining[..open]);
+        let rest = &remaining[open..];
+        let sgr = rest
+            .find('}')
+            .and_then(|close| markup_sgr(&rest[1..close]).map(|sgr| (sgr, close)));
+        if let Some((sgr, close)) = sgr {
+            result.push_str(&format!("\x1b[{sgr}m"));
+            styled = sgr != "0";
+            remaining = &rest[close + 1..];
+        } else {
+            result.push('{');
+            remaining = &rest[1..];
+        }
+    }
+    result.push_str(remaining);
+
+    if styled {
+        result.push_str("\x1b[0m");
+    }
+    result
+}
+
+// Length of the line being typed after sending `c`
+fn next_line_len(len: usize, c: char) -> usize {
+    match c {
+        // Enter, Ctrl-C and Ctrl-U all leave an empty line
+        '\r' | '\n' | '\x03' | '\x15' => 0,
+        '\x7f' | '\x08' => len.saturating_sub(1),
+        c if c.is_control() => len,
+        _ => len + 1,
+    }
+}
+
+// Keys that move from the end of a `line_len` character line back to `col`
+// (counting from 1), type `text` over what is there and return to the end,
+// with the line's length afterwards. Each character typed over is deleted
+// first, so it reads as overwriting in a shell's insert mode.
+fn overwrite_keys(line_len: usize, col: usize, text: &str) -> (String, usize) {
+    let start = (col - 1).min(line_len);
+    let end = start + text.chars().count();
+    let mut keys = "\x1b[D".repeat(line_len - start);
+    for (pos, c) in (start..).zip(text.chars()) {
+        if pos < line_len {
+            keys.push_str("\x1b[3~");
+        }
+        keys.push(c);
+    }
+    let new_len = line_len.max(end);
+    keys.push_str(&"\x1b[C".repeat(new_len - end));
+    (keys, new_len)
+}
+
+// A sample from the standard normal distribution, by the Box-Muller transform
+fn standard_normal(rng: &mut StdRng) -> f64 {
+    // In (0, 1], so the logarithm is finite
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+}
+
+// A plausible slip on `word` (at least two characters): a pair of neighbouring
+// letters swapped, or one of them dropped
+fn mistyped(word: &str, rng: &mut StdRng) -> String {
+    let mut chars: Vec<char> = word.chars().collect();
+    let pos = rng.random_range(0..chars.len() - 1);
+    if chars[pos] != chars[pos + 1] && rng.random_bool(0.5) {
+        chars.swap(pos, pos + 1);
+    } else {
+        chars.remove(pos + 1);
+    }
+    chars.into_iter().collect()
+}
+
+// A key typed during playback, as kept for the keystroke log and
+// PlaybackEngine::keystrokes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keystroke {
+    // Since playback started
+    pub at: Duration,
+    pub key: String,
+    // Typing delay that followed it
+    pub delay: Duration,
+}
+
+// Where to pick up an interrupted run: the next top-level command to play,
+// and the setting directives that restore the config then in effect (with
+// any configs saved by `@ push`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    pub next: usize,
+    pub settings: Vec<Command>,
+}
+
+impl Checkpoint {
+    // A `next:N` line followed by the settings as script source
+    pub fn to_text(&self) -> String {
+        let settings = Script {
+            commands: self.settings.clone(),
+        };
+        format!("next:{}\n{}", self.next, settings.to_source())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+        let next = first
+            .trim()
+            .strip_prefix("next:")
+            .and_then(|next| next.parse().ok())
+            .ok_or("a checkpoint must start with next:N")?;
+        let settings = crate::parser::parse_script(rest)?.commands;
+        if let Some(command) = settings.iter().find(|command| !command.is_setting()) {
+            return Err(format!(
+                "{} is not a setting, so can't be in a checkpoint",
+                command.source_line()
+            ));
+        }
+        Ok(Self { next, settings })
+    }
+}
+
+// The commands in `commands`, blocks included, whose effect outlasts them
+// beyond the config a checkpoint restores: the exit code and checks for
+// the end of the run, and the host terminal's theme and title. Replayed
+// from the part of a script a resumed run skips, so it ends the same way.
+fn lasting_effects<'a>(commands: &'a [Command], out: &mut Vec<&'a Command>) {
+    for command in commands {
+        match command {
+            Command::ExpectExit(_)
+            | Command::Verify(_)
+            | Command::Theme(_)
+            | Command::ResetTheme
+            | Command::SetTitle(_) => out.push(command),
+            Command::Shuffle(inner)
+            | Command::NoEcho(inner)
+            | Command::Tagged(_, inner)
+            | Command::Live(inner) => lasting_effects(inner, out),
+            _ => {}
+        }
+    }
+}
+
+// A spot flagged while rehearsing
+#[derive(Debug, Clone, PartialEq)]
+struct RehearsalNote {
+    // Index of the top-level command playing, counting from 0
+    command: usize,
+    // Since playback started
+    at: Duration,
+    note: &'static str,
+}
+
+// How long from `now` until the clock next reads `time`: later today, or
+// with `next_day` tomorrow once today's has passed. None if it has passed
+// without `next_day`. Local times are compared as read, so a clock change
+// in between (e.g. for daylight saving) shifts the wait.
+fn time_until(
+    now: chrono::NaiveDateTime,
+    time: chrono::NaiveTime,
+    next_day: bool,
+) -> Option<Duration> {
+    let mut target = now.date().and_time(time);
+    if target < now {
+        if !next_day {
+            return None;
+        }
+        target = target.checked_add_days(chrono::Days::new(1))?;
+    }
+    (target - now).to_std().ok()
+}
+
+// Render rehearsal notes one per line, each with the command's number
+// (counting from 1), when it was flagged and the command's source
+fn rehearsal_notes_to_text(notes: &[RehearsalNote], sources: &[String]) -> String {
+    notes
+        .iter()
+        .map(|note| {
+            format!(
+                "#{} {:.3}s {}: {}\n",
+                note.command + 1,
+                note.at.as_secs_f64(),
+                note.note,
+                sources.get(note.command).map_or("", String::as_str)
+            )
+        })
+        .collect()
+}
+
+// Render the keystroke log as CSV with a header row. Keys are written in the
+// given notation (e.g. `<ret>` or `^M`), quoted where CSV needs it.
+fn keystrokes_to_csv(keystrokes: &[Keystroke], notation: KeyNotation) -> String {
+    let mut out = String::from("elapsed_ms,key,delay_ms\n");
+    for keystroke in keystrokes {
+        let key = if keystroke.key == SECRET_KEY {
+            SECRET_KEY.to_string()
+        } else {
+            crate::parser::display_keys(&keystroke.key, notation)
+        };
+        let key = if key.contains([',', '"']) {
+            format!("\"{}\"", key.replace('"', "\"\""))
+        } else {
+            key
+        };
+        out.push_str(&format!(
+            "{:.3},{key},{:.3}\n",
+            keystroke.at.as_secs_f64() * 1000.0,
+            keystroke.delay.as_secs_f64() * 1000.0
+        ));
+    }
+    out
+}
+
+// Expected playback time of a script, split into time spent typing (which
+// scales with speed) and fixed pauses. Jitter averages out; time spent
+// waiting for output or for the viewer, secrets and word typos are not
+// counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DurationEstimate {
+    pub typing: Duration,
+    pub waits: Duration,
+    // Keys sent to the session, counting an escape sequence as one
+    pub keystrokes: u32,
+}
+
+impl DurationEstimate {
+    pub fn total(&self) -> Duration {
+        self.typing + self.waits
+    }
+
+    // Typing speed in words per minute, taking a word as five keystrokes.
+    // `@ reveal` captions count towards the time. None if nothing is typed.
+    pub fn wpm(&self) -> Option<f64> {
+        if self.keystrokes == 0 || self.typing.is_zero() {
+            return None;
+        }
+        Some(f64::from(self.keystrokes) / 5.0 / (self.typing.as_secs_f64() / 60.0))
+    }
+
+    // The estimate with typing and waits scaled as by set_time_scale
+    pub fn scaled(&self, typing_scale: f64, wait_scale: f64) -> Self {
+        Self {
+            typing: self.typing.mul_f64(typing_scale),
+            waits: self.waits.mul_f64(wait_scale),
+            keystrokes: self.keystrokes,
+        }
+    }
+
+    fn add_keystrokes(&mut self, count: u32, each: Duration) {
+        self.keystrokes = self.keystrokes.saturating_add(count);
+        self.typing += each * count;
+    }
+
+    // Factors for typing delays and waits that make playback take `target`.
+    // Waits keep their length unless `scale_waits` is set.
+    pub fn scales_for(&self, target: Duration, scale_waits: bool) -> Result<(f64, f64), String> {
+        if scale_waits {
+            if self.total().is_zero() {
+                return Err("the script has no typing or waits to scale".to_string());
+            }
+            let scale = target.as_secs_f64() / self.total().as_secs_f64();
+            return Ok((scale, scale));
+        }
+        if self.typing.is_zero() {
+            return Err("the script has no typing to scale".to_string());
+        }
+        if target <= self.waits {
+            return Err(format!(
+                "its waits alone take {:.1}s; scale them too to fit",
+                self.waits.as_secs_f64()
+            ));
+        }
+        Ok((
+            (target - self.waits).as_secs_f64() / self.typing.as_secs_f64(),
+            1.0,
+        ))
+    }
+}
+
+// Estimate how long `commands` take to play, starting from `config`
+pub fn estimate_duration(commands: &[Command], config: &PlaybackConfig) -> DurationEstimate {
+    // Keystrokes type_text sends for `text`, tracking the line as it goes
+    fn count(text: &str, line_len: &mut usize) -> u32 {
+        let mut count = 0;
+        let mut i = 0;
+        while i < text.len() {
+            if text.as_bytes()[i] == 0x1b {
+                i += PlaybackEngine::escape_sequence_length(&text.as_bytes()[i..]);
+            } else {
+                let c = text[i..].chars().next().unwrap();
+                *line_len = next_line_len(*line_len, c);
+                i += c.len_utf8();
+            }
+            count += 1;
+        }
+        count
+    }
+
+    fn spaces(text: &str) -> u32 {
+        u32::try_from(text.matches(' ').count()).unwrap_or(u32::MAX)
+    }
+
+    // `saved` holds the settings `@ push` saved, for `@ pop` to restore
+    fn walk(
+        commands: &[Command],
+        config: &mut PlaybackConfig,
+        saved: &mut Vec<PlaybackConfig>,
+        line_len: &mut usize,
+        estimate: &mut DurationEstimate,
+    ) {
+        for command in commands {
+            let mean = config.jitter_params.map_or(1.0, |params| params.mean);
+            let keystroke = Duration::from_secs_f64(config.speed.mid() * mean);
+            match command {
+                Command::SetSpeed(speed) => config.speed = *speed,
+                Command::SetJitter(_) => config.jitter_params = None,
+                Command::SetJitterParams(params) => config.jitter_params = *params,
+                Command::SetAutoEnter(on) => config.auto_enter = *on,
+                Command::SetWordPause(pause) => config.word_pause = *pause,
+                Command::SetLongLines(long_lines) => config.long_lines = *long_lines,
+                Command::PushConfig => saved.push(config.clone()),
+                Command::PopConfig => {
+                    if let Some(restored) = saved.pop() {
+                        *config = restored;
+                    }
+                }
+                Command::Type(text) if text.is_empty() && config.auto_enter => {
+                    estimate.add_keystrokes(count("\r", line_len), keystroke);
+                }
+                Command::Type(text) => {
+                    let scale = config.long_lines.map_or(1.0, |long_lines| {
+                        long_lines.delay_scale(text.chars().count())
+                    });
+                    estimate.add_keystrokes(count(text, line_len), keystroke.mul_f64(scale));
+                    estimate.typing += config.word_pause * spaces(text);
+                }
+                // Animated at typing speed, but printed rather than sent
+                Command::Reveal(text) => {
+                    estimate.typing += keystroke * count(&render_markup(text), &mut 0);
+                }
+                Command::Retype(text) => {
+                    let erase = u32::try_from(*line_len).unwrap_or(u32::MAX);
+                    *line_len = 0;
+                    estimate.add_keystrokes(erase.saturating_add(count(text, line_len)), keystroke);
+                    estimate.typing += config.word_pause * spaces(text);
+                }
+                Command::Overwrite(col, text) => {
+                    let (keys, new_len) = overwrite_keys(*line_len, *col, text);
+                    estimate.add_keystrokes(count(&keys, &mut 0), keystroke);
+                    *line_len = new_len;
+                }
+                Command::Paste(lines) => {
+                    *line_len = lines.last().map_or(0, |line| line.chars().count());
+                    estimate.add_keystrokes(1, keystroke);
+                }
+                Command::AcceptSuggestion => {
+                    estimate.waits += SUGGESTION_PAUSE;
+                    estimate.add_keystrokes(1, keystroke);
+                }
+                Command::Wait(duration) | Command::Resize(_, _, duration) => {
+                    estimate.waits += *duration;
+                }
+                Command::Shuffle(inner) | Command::Tagged(_, inner) | Command::Live(inner) => {
+                    walk(inner, config, saved, line_len, estimate);
+                }
+                Command::NoEcho(inner) => {
+                    walk(inner, config, saved, &mut line_len.clone(), estimate);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut estimate = DurationEstimate::default();
+    walk(
+        commands,
+        &mut config.clone(),
+        &mut Vec::new(),
+        &mut 0,
+        &mut estimate,
+    );
+    estimate
+}
+
+// Remove the Enter that ends the last typed line, if it has one
+fn strip_final_enter(commands: &mut [Command]) {
+    if let ControlFlow::Break(Some(text)) = last_typed(commands)
+        && text.ends_with('\r')
+    {
+        text.pop();
+    }
+}
+
+// The last command that sends input, looking inside blocks. Breaks with its
+// text if it's a typed line, or with None if it's anything else, such as a
+// retype or a shuffle whose last line isn't known until it plays. Continues
+// if nothing in `commands` sends input.
+fn last_typed(commands: &mut [Command]) -> ControlFlow<Option<&mut String>> {
+    for command in commands.iter_mut().rev() {
+        match command {
+            Command::Type(text) => return ControlFlow::Break(Some(text)),
+            Command::Tagged(_, inner) | Command::NoEcho(inner) | Command::Live(inner) => {
+                last_typed(inner)?
+            }
+            other => {
+                if other.sends_input() {
+                    return ControlFlow::Break(None);
+                }
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
 
 pub struct PlaybackEngine {
     pty: PtyManager,
     config: PlaybackConfig,
+    // Settings saved by `@ push`, innermost last
+    saved_configs: Vec<PlaybackConfig>,
     running: Arc<AtomicBool>,
+    // Source of all randomness (jitter, shuffle), seedable for reproducible runs
+    rng: StdRng,
+    ending: Ending,
+    write_errors: WriteErrorPolicy,
+    // Start of the current run and the pause time scheduled since, for
+    // measuring drift
+    timeline_start: Instant,
+    scheduled: Duration,
+    // Top-level command being played, counting from 0
+    command_index: usize,
+    // Subtitle cues reached so far, written as WebVTT or SRT if a path is set
+    cues: Vec<Cue>,
+    subtitle_path: Option<PathBuf>,
+    // Top-level commands that send input, labelled with their source, written
+    // as asciicast markers if a path is set
+    markers: Vec<Cue>,
+    marker_path: Option<PathBuf>,
+    // Each keystroke typed and the delay after it, written as CSV if a path
+    // is set
+    keystrokes: Vec<Keystroke>,
+    record_keystrokes: bool,
+    keystroke_log_path: Option<PathBuf>,
+    key_notation: KeyNotation,
+    // Spots the viewer flagged as too fast or too slow while rehearsing,
+    // written as notes if a path is set
+    rehearsal_notes: Vec<RehearsalNote>,
+    rehearsal_path: Option<PathBuf>,
+    // Hold the viewer's keystrokes while a command types, throughout or only
+    // inside a @live block, and pass them to the session once it's done
+    typethrough: bool,
+    live: bool,
+    // Where `@ checkpoint` saves progress, and how many top-level commands
+    // to play between checkpoints saved regardless
+    checkpoint_path: Option<PathBuf>,
+    checkpoint_every: Option<usize>,
+    // Typing a secret, whose keys are masked in the keystroke log
+    secret: bool,
+    // Characters typed on the current line, for @retype
+    line_len: usize,
+    // Exit code the shell must end with, checked once playback finishes
+    expected_exit: Option<u32>,
+    // How long the shell may take to exit once playback finishes, and how
+    // long it took
+    exit_budget: Option<Duration>,
+    exit_time: Option<Duration>,
+    // @verify commands reached, run on the host once playback finishes
+    verifications: Vec<String>,
+    // Where @echo, @reveal, @set_title and @theme write: stdout unless set
+    host: Box<dyn Write + Send>,
+    // Inside a @noecho block, where typed characters don't show on the line
+    hidden: bool,
+    // Prompt markers seen when Enter was last pressed in SyncMode::Prompt,
+    // until the next prompt has been waited for
+    awaiting_prompt: Option<u64>,
+    keepalive: Option<Keepalive>,
+    // Speed and jitter for the current command, picked from the configured
+    // ranges as it starts
+    speed: f64,
+    jitter: f64,
+    // Factors applied to typing delays and to waits, e.g. to fit a target
+    // duration
+    typing_scale: f64,
+    // Factor for the current `$` line's typing delays, from @long_lines
+    length_scale: f64,
+    wait_scale: f64,
+    // Whether a @theme has changed the host palette, and whether to leave it
+    // changed when playback ends
+    theme_applied: bool,
+    keep_theme: bool,
+    // Whether to wait for the shell to print the ready sentinel before
+    // playback starts
+    ready_sentinel: bool,
+    // Whether to run a no-op command before playback starts
+    prewarm: bool,
+    // Embedder hooks run around each top-level command
+    before_command: Option<CommandHook>,
+    after_command: Option<CommandHook>,
+    // Embedder rewrite of each `$` line's text before it is typed
+    type_transform: Option<TypeTransform>,
 }
 
 impl PlaybackEngine {
@@ -40,16 +638,305 @@ impl PlaybackEngine {
         // In raw mode Ctrl-C never raises SIGINT (the PTY stdin forwarder
         // handles it instead); this covers non-TTY runs and external signals
         let r = running.clone();
-        ctrlc::set_handler(move || {
+        match ctrlc::set_handler(move || {
             eprintln!("\nReceived Ctrl-C, stopping playback...");
             r.store(false, Ordering::SeqCst);
-        })?;
+        }) {
+            // An earlier engine or the embedding program already handles it
+            Ok(()) | Err(ctrlc::Error::MultipleHandlers) => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self::without_signal_handler(pty, running))
+    }
 
-        Ok(Self {
+    // An engine that leaves SIGINT alone, for tests and for embedders that
+    // handle signals themselves. Playback still stops when `running` is
+    // cleared.
+    pub fn without_signal_handler(pty: PtyManager, running: Arc<AtomicBool>) -> Self {
+        let config = PlaybackConfig::default();
+        Self {
             pty,
-            config: PlaybackConfig::default(),
             running,
-        })
+            rng: rand::make_rng(),
+            ending: Ending::default(),
+            write_errors: WriteErrorPolicy::default(),
+            timeline_start: Instant::now(),
+            scheduled: Duration::ZERO,
+            command_index: 0,
+            cues: Vec::new(),
+            subtitle_path: None,
+            markers: Vec::new(),
+            marker_path: None,
+            keystrokes: Vec::new(),
+            record_keystrokes: false,
+            keystroke_log_path: None,
+            key_notation: KeyNotation::Names,
+            rehearsal_notes: Vec::new(),
+            rehearsal_path: None,
+            typethrough: false,
+            live: false,
+            checkpoint_path: None,
+            checkpoint_every: None,
+            secret: false,
+            line_len: 0,
+            expected_exit: None,
+            exit_budget: None,
+            exit_time: None,
+            verifications: Vec::new(),
+            host: Box::new(std::io::stdout()),
+            hidden: false,
+            awaiting_prompt: None,
+            keepalive: None,
+            speed: config.speed.min,
+            jitter: config.jitter.min,
+            config,
+            saved_configs: Vec::new(),
+            typing_scale: 1.0,
+            length_scale: 1.0,
+            wait_scale: 1.0,
+            theme_applied: false,
+            keep_theme: false,
+            ready_sentinel: false,
+            prewarm: false,
+            before_command: None,
+            after_command: None,
+            type_transform: None,
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_ending(&mut self, ending: Ending) {
+        self.ending = ending;
+    }
+
+    pub fn set_write_error_policy(&mut self, policy: WriteErrorPolicy) {
+        self.write_errors = policy;
+    }
+
+    // Write `@ subtitle` cues to `path` once playback finishes: as SRT if it
+    // ends in `.srt`, otherwise as WebVTT
+    pub fn set_subtitle_path(&mut self, path: PathBuf) {
+        self.subtitle_path = Some(path);
+    }
+
+    // Send what would go to the host terminal (captions, titles, palette
+    // changes) to `output` instead, e.g. the sink a headless session writes to
+    pub fn set_host_output(&mut self, output: impl Write + Send + 'static) {
+        self.host = Box::new(output);
+    }
+
+    // Write a marker for each top-level command that sends input to `path`
+    // as asciicast v2 events once playback finishes, for chapter navigation
+    pub fn set_marker_path(&mut self, path: PathBuf) {
+        self.marker_path = Some(path);
+    }
+
+    // Write the time, key and following delay of every keystroke typed to
+    // `path` as CSV once playback finishes, for studying the timing model
+    pub fn set_keystroke_log_path(&mut self, path: PathBuf) {
+        self.keystroke_log_path = Some(path);
+        self.record_keystrokes = true;
+    }
+
+    // Keep every keystroke typed, to read back with keystrokes()
+    pub fn set_record_keystrokes(&mut self, on: bool) {
+        self.record_keystrokes = on;
+    }
+
+    // The keystrokes typed so far, if recording them, with when each was typed
+    // and the delay after it. These are the times playback intended, by
+    // tokio's clock, so a paused clock gives exact values.
+    pub fn keystrokes(&self) -> &[Keystroke] {
+        &self.keystrokes
+    }
+
+    // How control characters are written in the keystroke log
+    pub fn set_key_notation(&mut self, notation: KeyNotation) {
+        self.key_notation = notation;
+    }
+
+    // Rehearse: while playing, the viewer's keys are kept from the session
+    // and `f` or `s` flags the current command as too fast or too slow. The
+    // flags are written to `path` as notes once playback ends.
+    pub fn set_rehearsal_path(&mut self, path: PathBuf) {
+        self.rehearsal_path = Some(path);
+    }
+
+    // Let the viewer type into the session between commands, as in a @live
+    // block: keystrokes made while a command types are held back until it
+    // finishes, so they never land in the middle of a scripted line
+    pub fn set_typethrough(&mut self, typethrough: bool) {
+        self.typethrough = typethrough;
+    }
+
+    // Save progress to `path` at each `@ checkpoint`, and also after every
+    // `every` top-level commands if given, so an interrupted run can be
+    // resumed with execute_from
+    pub fn set_checkpoint_path(&mut self, path: PathBuf, every: Option<usize>) {
+        self.checkpoint_path = Some(path);
+        self.checkpoint_every = every.filter(|&every| every > 0);
+    }
+
+    // Progress so far, resuming at top-level command `next`
+    fn checkpoint(&self, next: usize) -> Checkpoint {
+        let mut settings = Vec::new();
+        for saved in &self.saved_configs {
+            settings.extend(saved.to_commands());
+            settings.push(Command::PushConfig);
+        }
+        settings.extend(self.config.to_commands());
+        Checkpoint { next, settings }
+    }
+
+    fn write_checkpoint(&self, next: usize) -> Result<()> {
+        let Some(path) = &self.checkpoint_path else {
+            return Ok(());
+        };
+        std::fs::write(path, self.checkpoint(next).to_text())
+            .with_context(|| format!("Failed to write the checkpoint to {}", path.display()))
+    }
+
+    // Once playback finishes, close the shell's input and fail unless it exits
+    // within `budget`, killing it if it doesn't
+    pub fn set_exit_budget(&mut self, budget: Option<Duration>) {
+        self.exit_budget = budget;
+    }
+
+    // How long the shell took to exit after playback, if an exit budget was
+    // checked
+    pub fn exit_time(&self) -> Option<Duration> {
+        self.exit_time
+    }
+
+    // Send keepalive keys at intervals during waits longer than the interval
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    // Stretch or shrink typing delays and waits by these factors
+    pub fn set_time_scale(&mut self, typing: f64, waits: f64) {
+        self.typing_scale = typing;
+        self.wait_scale = waits;
+    }
+
+    // Leave the palette set by @theme in place when playback ends, rather
+    // than restoring the terminal's defaults
+    pub fn set_keep_theme(&mut self, keep: bool) {
+        self.keep_theme = keep;
+    }
+
+    pub fn set_ready_sentinel(&mut self, on: bool) {
+        self.ready_sentinel = on;
+    }
+
+    pub fn set_prewarm(&mut self, on: bool) {
+        self.prewarm = on;
+    }
+
+    // Run `hook` before each top-level command. The returned future may do
+    // async work; playback waits for it, unless Ctrl-C is pressed meanwhile
+    pub fn set_before_command<F, Fut>(&mut self, hook: F)
+    where
+        F: FnMut(&Command) -> Fut + Send + 'static,
+        Fut: Future<Output = HookAction> + Send + 'static,
+    {
+        self.before_command = Some(boxed_hook(hook));
+    }
+
+    // Run `hook` after each top-level command, as for set_before_command
+    pub fn set_after_command<F, Fut>(&mut self, hook: F)
+    where
+        F: FnMut(&Command) -> Fut + Send + 'static,
+        Fut: Future<Output = HookAction> + Send + 'static,
+    {
+        self.after_command = Some(boxed_hook(hook));
+    }
+
+    // Pass the text of each `$` line through `transform` and type what it
+    // returns instead, e.g. to redact or translate it. It runs after parsing,
+    // so special keys are already the bytes they send (`<ret>` is "\r").
+    // Other commands that type, such as @retype and @secret, are left as is.
+    pub fn set_type_transform<F>(&mut self, transform: F)
+    where
+        F: FnMut(&str) -> String + Send + 'static,
+    {
+        self.type_transform = Some(Box::new(transform));
+    }
+
+    // Current speed, jitter and other settings, as left by any directives
+    // that have run
+    pub fn config(&self) -> &PlaybackConfig {
+        &self.config
+    }
+
+    // Take back the session after playback, e.g. to shut it down with a
+    // timeout of your choosing rather than on drop
+    pub fn into_pty(self) -> PtyManager {
+        self.pty
+    }
+
+    // Time since playback started
+    pub fn elapsed(&self) -> Duration {
+        self.timeline_start.elapsed()
+    }
+
+    // How far playback has fallen behind its intended schedule: time spent
+    // beyond the scheduled pauses, e.g. scheduler latency and PTY writes
+    pub fn timing_drift(&self) -> Duration {
+        self.timeline_start.elapsed().saturating_sub(self.scheduled)
+    }
+
+    // Pause for `duration` of scheduled time. In sync mode, sleep until the
+    // pause's place on the schedule instead, absorbing accumulated drift.
+    async fn pause(&mut self, duration: Duration) {
+        self.scheduled += duration;
+        if self.config.sync == SyncMode::Schedule {
+            sleep_until(self.timeline_start + self.scheduled).await;
+        } else {
+            sleep(duration).await;
+        }
+        self.take_rehearsal_keys();
+    }
+
+    // Note the rehearsal flags pressed since last checked
+    fn take_rehearsal_keys(&mut self) {
+        if self.rehearsal_path.is_none() {
+            return;
+        }
+        let at = self.timeline_start.elapsed();
+        for key in self.pty.viewer_input() {
+            let note = match key.to_ascii_lowercase() {
+                b'f' => "too fast",
+                b's' => "too slow",
+                _ => continue,
+            };
+            self.rehearsal_notes.push(RehearsalNote {
+                command: self.command_index,
+                at,
+                note,
+            });
+        }
+    }
+
+    // Stop diverting the viewer's keystrokes after a wait, dropping whatever
+    // was left over, unless rehearsing still needs them
+    fn resume_viewer_input(&mut self) {
+        self.pty.divert_viewer_input(false);
+        if self.rehearsal_path.is_some() {
+            self.pty.divert_viewer_input(true);
+        }
+    }
+
+    // Stop if the session's output is no longer reaching the terminal, rather
+    // than play on with nobody able to see it
+    fn check_output(&self) -> Result<()> {
+        match self.pty.output_failure() {
+            Some(reason) => anyhow::bail!("Stopped playback: {reason}"),
+            None => Ok(()),
+        }
     }
 
     fn should_continue(&self) -> bool {
@@ -61,13 +948,16 @@ impl PlaybackEngine {
         clippy::cast_sign_loss,
         clippy::cast_precision_loss
     )]
-    fn calculate_delay(&self) -> Duration {
-        let mut rng = rand::rng();
-        let base_ms = (self.config.speed * 1000.0) as u64;
-        let jitter_ms = (base_ms as f64 * self.config.jitter) as u64;
+    fn calculate_delay(&mut self) -> Duration {
+        let base_ms = (self.speed * self.typing_scale * self.length_scale * 1000.0) as u64;
+        if let Some(params) = self.config.jitter_params {
+            let factor = params.mean + params.stddev * standard_normal(&mut self.rng);
+            return Duration::from_millis((base_ms as f64 * factor.max(0.0)) as u64);
+        }
+        let jitter_ms = (base_ms as f64 * self.jitter) as u64;
 
         if jitter_ms > 0 {
-            let variation = rng.random_range(0..=jitter_ms * 2);
+            let variation = self.rng.random_range(0..=jitter_ms * 2);
             let delay = base_ms.saturating_add(variation).saturating_sub(jitter_ms);
             Duration::from_millis(delay)
         } else {
@@ -75,6 +965,176 @@ impl PlaybackEngine {
         }
     }
 
+    // A value from `range` for the next command. Fixed values leave the RNG
+    // untouched, so seeded runs without ranges play as before.
+    fn pick(&mut self, range: ValueRange) -> f64 {
+        if range.is_fixed() {
+            range.min
+        } else {
+            self.rng.random_range(range.min..=range.max)
+        }
+    }
+
+    // A @wait, split up by keepalives if they are enabled
+    async fn wait(&mut self, duration: Duration) -> Result<()> {
+        let mut remaining = duration;
+        if let Some(keepalive) = self.keepalive.clone()
+            && !keepalive.interval.is_zero()
+        {
+            while remaining > keepalive.interval && self.should_continue() {
+                self.pause(keepalive.interval).await;
+                remaining -= keepalive.interval;
+                self.pty.send_keystroke(&keepalive.keys)?;
+            }
+        }
+        self.pause(remaining).await;
+        Ok(())
+    }
+
+    // Wait `duration` of real time for @wait_until, unscaled and cut short by
+    // Ctrl-C, as it may run for hours. It counts as scheduled, not drift.
+    async fn wait_until(&mut self, duration: Duration) -> Result<()> {
+        let start = Instant::now();
+        let end = start + duration;
+        let mut kept_alive = start;
+        while self.should_continue() {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            if let Some(keepalive) = &self.keepalive
+                && !keepalive.interval.is_zero()
+                && now - kept_alive >= keepalive.interval
+            {
+                self.pty.send_keystroke(&keepalive.keys)?;
+                kept_alive = now;
+            }
+            sleep((end - now).min(IDLE_POLL)).await;
+        }
+        self.scheduled += start.elapsed();
+        Ok(())
+    }
+
+    // Wait for the viewer to press `key` (or any key), without passing their
+    // keystrokes to the session. Skipped if there is no viewer to ask.
+    // Waiting on the viewer counts as scheduled, not drift.
+    async fn wait_for_key(&mut self, key: Option<&str>) {
+        self.take_rehearsal_keys();
+        if !self.pty.divert_viewer_input(true) {
+            return;
+        }
+        let start = Instant::now();
+        let mut pressed = Vec::new();
+        while self.should_continue() {
+            pressed.extend(self.pty.viewer_input());
+            let done = match key {
+                Some(key) => pressed
+                    .windows(key.len())
+                    .any(|window| window == key.as_bytes()),
+                None => !pressed.is_empty(),
+            };
+            if done {
+                break;
+            }
+            sleep(IDLE_POLL).await;
+        }
+        self.resume_viewer_input();
+        self.scheduled += start.elapsed();
+    }
+
+    // Send `query` to the host terminal and wait up to `timeout` for it to
+    // answer, without passing the answer on to the session. Skipped if there
+    // is no viewer's terminal to ask. Waiting counts as scheduled, not drift.
+    async fn wait_for_response(&mut self, query: &str, timeout: Duration) -> Result<()> {
+        self.take_rehearsal_keys();
+        if !self.pty.divert_viewer_input(true) {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let sent = self.write_host(query.as_bytes());
+        while sent.is_ok() && self.should_continue() && start.elapsed() < timeout {
+            if !self.pty.viewer_input().is_empty() {
+                sleep(RESPONSE_SETTLE).await;
+                break;
+            }
+            sleep(IDLE_POLL).await;
+        }
+        // Also drops the rest of the answer
+        self.resume_viewer_input();
+        self.scheduled += start.elapsed();
+        sent
+    }
+
+    // Have the shell print the ready sentinel and wait until it shows on
+    // screen, so playback starts once the shell is reading input whatever its
+    // prompt looks like
+    async fn wait_until_ready(&mut self) -> Result<()> {
+        self.pty.send_keystroke(READY_COMMAND)?;
+        let start = Instant::now();
+        while self.should_continue() && !self.pty.screen_has_line_ending(READY_SENTINEL) {
+            if start.elapsed() >= READY_TIMEOUT {
+                anyhow::bail!(
+                    "The shell didn't print the ready sentinel {READY_SENTINEL} within {}s",
+                    READY_TIMEOUT.as_secs()
+                );
+            }
+            sleep(IDLE_POLL).await;
+        }
+        Ok(())
+    }
+
+    // Run a no-op command and wait for the shell to answer and go quiet, so
+    // work a shell does around its first command (loading completions,
+    // prompt hooks) happens before the script starts. Best effort: a shell
+    // that hasn't settled by the timeout is left to it.
+    async fn prewarm(&mut self) -> Result<()> {
+        let sent = Instant::now();
+        self.pty.send_keystroke(PREWARM_COMMAND)?;
+        while self.should_continue()
+            && sent.elapsed() < PREWARM_TIMEOUT
+            && self.pty.output_idle_for() >= sent.elapsed()
+        {
+            sleep(IDLE_POLL).await;
+        }
+        let remaining = PREWARM_TIMEOUT.saturating_sub(sent.elapsed());
+        self.wait_for_output_idle(PREWARM_QUIET, remaining).await;
+        Ok(())
+    }
+
+    // Wait until the PTY output has been quiet for `quiet`, giving up after
+    // `timeout` so a continuously redrawing program can't stall playback.
+    // Time spent waiting counts as scheduled, not drift.
+    async fn wait_for_output_idle(&mut self, quiet: Duration, timeout: Duration) {
+        let start = Instant::now();
+        while self.should_continue()
+            && start.elapsed() < timeout
+            && self.pty.output_idle_for() < quiet
+        {
+            sleep(IDLE_POLL).await;
+        }
+        self.scheduled += start.elapsed();
+    }
+
+    // Wait for the shell to print a prompt marker after `seen`, meaning the
+    // command just entered has finished. A shell that has never printed one
+    // doesn't support them, so after a timeout playback falls back to its
+    // own timing for the rest of the script. Counts as scheduled, not drift.
+    async fn wait_for_prompt(&mut self, seen: u64) {
+        let start = Instant::now();
+        while self.should_continue()
+            && start.elapsed() < PROMPT_TIMEOUT
+            && self.pty.prompts_seen() == seen
+        {
+            sleep(IDLE_POLL).await;
+        }
+        self.scheduled += start.elapsed();
+
+        if self.pty.prompts_seen() == 0 && self.should_continue() {
+            eprintln!("\nNo OSC 133 prompt markers from the shell, turning @sync:osc133 off");
+            self.config.sync = SyncMode::Off;
+        }
+    }
+
     // The returned length is used to slice the UTF-8 text by byte offset, so it
     // must never claim a partial multibyte character
     fn escape_sequence_length(bytes: &[u8]) -> usize {
@@ -110,6 +1170,140 @@ impl PlaybackEngine {
         }
     }
 
+    async fn type_text(&mut self, text: &str) -> Result<()> {
+        // Escape sequences must be sent atomically without delays between bytes
+        let mut i = 0;
+        let bytes = text.as_bytes();
+
+        while i < bytes.len() {
+            if !self.should_continue() {
+                return Ok(());
+            }
+            self.check_output()?;
+
+            if bytes[i] == 0x1b {
+                let seq_len = Self::escape_sequence_length(&bytes[i..]);
+                let sequence = &text[i..i + seq_len];
+
+                self.pty.send_keystroke(sequence)?;
+                i += seq_len;
+                // Escape sequences (arrows, Alt-keys) move or edit the line in
+                // ways that can't be tracked, so they leave line_len alone
+
+                self.keystroke_pause(sequence).await;
+            } else {
+                let c = text[i..].chars().next().unwrap();
+                let typo_chars = &self.config.typo_chars;
+                let word_start =
+                    !text[..i].ends_with(|c: char| c.is_alphanumeric() || typo_chars.contains(c));
+                if word_start && !self.hidden && self.config.word_typos > 0.0 {
+                    let word = text[i..]
+                        .split(|c: char| !typo_chars.contains(c))
+                        .next()
+                        .unwrap_or_default();
+                    if word.chars().count() >= MIN_TYPO_WORD
+                        && self.rng.random_bool(self.config.word_typos)
+                    {
+                        self.mistype(word).await?;
+                    }
+                }
+                if c == '\r' && self.config.sync == SyncMode::Prompt {
+                    // Counted before sending, so a quick prompt isn't missed
+                    self.awaiting_prompt = Some(self.pty.prompts_seen());
+                }
+                self.pty.send_char(c)?;
+                i += c.len_utf8();
+                // Hidden characters don't lengthen the line, but Enter still
+                // ends it
+                let line_len = next_line_len(self.line_len, c);
+                if !self.hidden || line_len == 0 {
+                    self.line_len = line_len;
+                }
+
+                self.keystroke_pause(&c.to_string()).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Pause for the typing delay after sending `key`, logging both
+    async fn keystroke_pause(&mut self, key: &str) {
+        let mut delay = self.calculate_delay();
+        if key == " " {
+            delay += self.config.word_pause.mul_f64(self.typing_scale);
+        }
+        if self.record_keystrokes {
+            self.keystrokes.push(Keystroke {
+                at: self.timeline_start.elapsed(),
+                key: if self.secret {
+                    SECRET_KEY.to_string()
+                } else {
+                    key.to_string()
+                },
+                delay,
+            });
+        }
+        self.pause(delay).await;
+    }
+
+    // Type a slip on `word`, pause as if noticing it, then backspace over it,
+    // leaving the line as it was for the word to be typed properly
+    async fn mistype(&mut self, word: &str) -> Result<()> {
+        let wrong = mistyped(word, &mut self.rng);
+        for c in wrong.chars() {
+            if !self.should_continue() {
+                return Ok(());
+            }
+            self.pty.send_char(c)?;
+            self.keystroke_pause(&c.to_string()).await;
+        }
+        let notice = self.calculate_delay() * TYPO_NOTICE_KEYSTROKES;
+        self.pause(notice).await;
+        for _ in wrong.chars() {
+            if !self.should_continue() {
+                return Ok(());
+            }
+            let backspace = self.config.backspace.bytes();
+            self.pty.send_keystroke(backspace)?;
+            self.keystroke_pause(backspace).await;
+        }
+        Ok(())
+    }
+
+    // Write a caption to the host terminal a character at a time, with the
+    // same delays as typing; style changes are written whole
+    async fn reveal(&mut self, text: &str) -> Result<()> {
+        let rendered = render_markup(text);
+        let bytes = rendered.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if !self.should_continue() {
+                break;
+            }
+            let len = if bytes[i] == 0x1b {
+                Self::escape_sequence_length(&bytes[i..])
+            } else {
+                rendered[i..].chars().next().map_or(1, char::len_utf8)
+            };
+            self.write_host(&bytes[i..i + len])?;
+            if bytes[i] != 0x1b {
+                let delay = self.calculate_delay();
+                self.pause(delay).await;
+            }
+            i += len;
+        }
+        // Raw mode is on, so line endings need an explicit CR
+        self.write_host(b"\r\n")
+    }
+
+    // Write straight to the host terminal (or whatever stands in for it),
+    // bypassing the session
+    fn write_host(&mut self, bytes: &[u8]) -> Result<()> {
+        self.host.write_all(bytes)?;
+        self.host.flush()?;
+        Ok(())
+    }
+
     async fn execute_command(&mut self, command: &Command) -> Result<()> {
         match command {
             Command::SetSpeed(speed) => {
@@ -117,65 +1311,486 @@ impl PlaybackEngine {
             }
             Command::SetJitter(jitter) => {
                 self.config.jitter = *jitter;
+                self.config.jitter_params = None;
+            }
+            Command::SetJitterParams(params) => {
+                self.config.jitter_params = *params;
+            }
+            Command::SetSync(sync) => {
+                self.config.sync = *sync;
+            }
+            Command::SetAutoEnter(on) => {
+                self.config.auto_enter = *on;
+            }
+            Command::SetWordTypos(rate) => {
+                self.config.word_typos = *rate;
+            }
+            Command::SetTypoChars(chars) => {
+                self.config.typo_chars = chars.clone();
+            }
+            Command::SetBackspace(key) => {
+                self.config.backspace = *key;
+            }
+            Command::SetWordPause(pause) => {
+                self.config.word_pause = *pause;
+            }
+            Command::SetLongLines(long_lines) => {
+                self.config.long_lines = *long_lines;
+            }
+            Command::PushConfig => self.saved_configs.push(self.config.clone()),
+            Command::PopConfig => {
+                self.config = self
+                    .saved_configs
+                    .pop()
+                    .context("@pop without a matching @push")?;
             }
             Command::Wait(duration) => {
-                sleep(*duration).await;
+                self.wait(duration.mul_f64(self.wait_scale)).await?;
             }
-            Command::SetShell(_) | Command::SetSize(_, _) => {
-                // Shell and size are applied before playback starts, ignore during execution
+            Command::WaitUntil(time, next_day) => {
+                let now = chrono::Local::now().naive_local();
+                let Some(duration) = time_until(now, *time, *next_day) else {
+                    anyhow::bail!(
+                        "@wait_until:{0}: {0} has already passed today \
+                         (add :next_day to wait for tomorrow's)",
+                        time.format("%H:%M:%S")
+                    );
+                };
+                self.wait_until(duration).await?;
+            }
+            Command::SetShell(_)
+            | Command::SetSize(_, _)
+            | Command::SetEnv(_, _)
+            | Command::SetSeed(_)
+            | Command::SetReadySentinel(_)
+            | Command::SetPrewarm(_)
+            | Command::SetUmask(_) => {
+                // Setup is applied before playback starts, ignore during
+                // execution
             }
             Command::Capture(path) => {
                 self.pty.capture(path)?;
             }
-            Command::Type(text) => {
-                // Escape sequences must be sent atomically without delays between bytes
-                let mut i = 0;
-                let bytes = text.as_bytes();
-
-                while i < bytes.len() {
+            Command::ResetCapture => self.pty.reset_capture(),
+            Command::Flush => self.pty.flush()?,
+            Command::Checkpoint => self.write_checkpoint(self.command_index + 1)?,
+            Command::ExpectExit(code) => self.expected_exit = Some(*code),
+            Command::Verify(command) => self.verifications.push(command.clone()),
+            Command::SetSuggestionKey(key) => {
+                self.config.suggestion_key.clone_from(key);
+            }
+            Command::AcceptSuggestion => {
+                self.pause(SUGGESTION_PAUSE).await;
+                self.wait_for_output_idle(OUTPUT_QUIET, OUTPUT_IDLE_TIMEOUT)
+                    .await;
+                if self.should_continue() {
+                    self.pty.send_keystroke(&self.config.suggestion_key)?;
+                }
+            }
+            Command::Subtitle(text) => {
+                self.cues.push(Cue {
+                    start: self.timeline_start.elapsed(),
+                    text: text.clone(),
+                });
+            }
+            Command::SetTitle(text) => {
+                // Written to the host terminal, where the recording sees it,
+                // in one piece; control characters would end the OSC early
+                let title: String = text.chars().filter(|c| !c.is_control()).collect();
+                self.write_host(format!("\x1b]0;{title}\x07").as_bytes())?;
+            }
+            Command::WaitKey(key) => self.wait_for_key(key.as_deref()).await,
+            Command::WaitResponse(query, timeout) => {
+                self.wait_for_response(query, *timeout).await?;
+            }
+            Command::Resize(cols, rows, pause) => {
+                self.pty.resize(*cols, *rows)?;
+                self.pause(pause.mul_f64(self.wait_scale)).await;
+            }
+            Command::Theme(path) => {
+                let sequences = Theme::load(path)?.osc_sequences();
+                self.write_host(sequences.as_bytes())?;
+                self.theme_applied = true;
+            }
+            Command::ResetTheme => {
+                self.write_host(theme::RESET_PALETTE.as_bytes())?;
+                self.theme_applied = false;
+            }
+            Command::Reveal(text) => self.reveal(text).await?,
+            Command::Echo(text) => {
+                // Raw mode is on, so line endings need an explicit CR
+                self.write_host(format!("{}\r\n", render_markup(text)).as_bytes())?;
+            }
+            Command::Shuffle(commands) => {
+                let mut commands = commands.clone();
+                commands.shuffle(&mut self.rng);
+                for command in &commands {
                     if !self.should_continue() {
-                        return Ok(());
+                        break;
                     }
-
-                    if bytes[i] == 0x1b {
-                        let seq_len = Self::escape_sequence_length(&bytes[i..]);
-                        let sequence = &text[i..i + seq_len];
-
-                        self.pty.send_keystroke(sequence)?;
-                        i += seq_len;
-
-                        let delay = self.calculate_delay();
-                        sleep(delay).await;
-                    } else {
-                        let c = text[i..].chars().next().unwrap();
-                        self.pty.send_char(c)?;
-                        i += c.len_utf8();
-
-                        let delay = self.calculate_delay();
-                        sleep(delay).await;
+                    Box::pin(self.run_command(command)).await?;
+                }
+            }
+            Command::Tagged(_, commands) => {
+                for command in commands {
+                    if !self.should_continue() {
+                        break;
+                    }
+                    Box::pin(self.run_command(command)).await?;
+                }
+            }
+            Command::Live(commands) => {
+                let live = std::mem::replace(&mut self.live, true);
+                let mut result = Ok(());
+                for command in commands {
+                    if !self.should_continue() || result.is_err() {
+                        break;
                     }
+                    result = Box::pin(self.run_command(command)).await;
                 }
+                self.live = live;
+                result?;
+            }
+            Command::NoEcho(commands) => {
+                let hidden = std::mem::replace(&mut self.hidden, true);
+                let mut result = Ok(());
+                for command in commands {
+                    if !self.should_continue() || result.is_err() {
+                        break;
+                    }
+                    result = Box::pin(self.run_command(command)).await;
+                }
+                self.hidden = hidden;
+                result?;
+            }
+            Command::Type(text) if text.is_empty() && self.config.auto_enter => {
+                self.type_text("\r").await?;
+            }
+            Command::Type(text) => {
+                let text = match &mut self.type_transform {
+                    Some(transform) => Cow::Owned(transform(text)),
+                    None => Cow::Borrowed(text.as_str()),
+                };
+                self.length_scale = self.config.long_lines.map_or(1.0, |long_lines| {
+                    long_lines.delay_scale(text.chars().count())
+                });
+                let typed = self.type_text(&text).await;
+                self.length_scale = 1.0;
+                typed?;
+            }
+            Command::Retype(text) => {
+                let erase = self.config.backspace.bytes().repeat(self.line_len);
+                self.type_text(&erase).await?;
+                self.type_text(text).await?;
+            }
+            Command::Overwrite(col, text) => {
+                let (keys, new_len) = overwrite_keys(self.line_len, *col, text);
+                // A slip and its correction would undo the overwrite
+                let word_typos = std::mem::replace(&mut self.config.word_typos, 0.0);
+                let typed = self.type_text(&keys).await;
+                self.config.word_typos = word_typos;
+                typed?;
+                self.line_len = new_len;
+            }
+            Command::Paste(lines) => {
+                let text = lines.join("\n");
+                self.pty
+                    .send_keystroke(&format!("{PASTE_START}{text}{PASTE_END}"))?;
+                self.line_len = lines.last().map_or(0, |line| line.chars().count());
+                let delay = self.calculate_delay();
+                self.pause(delay).await;
+            }
+            Command::TypeSecret(var) => {
+                let secret = std::env::var(var)
+                    .with_context(|| format!("Secret ${var} is not set in the environment"))?;
+                // Secrets are typically not echoed, so leave them out of the
+                // visible line
+                let line_len = self.line_len;
+                self.secret = true;
+                let typed = self.type_text(&secret).await;
+                self.secret = false;
+                typed?;
+                self.line_len = line_len;
             }
         }
         Ok(())
     }
 
+    // Execute a command, applying the write error policy to its failures
+    async fn run_command(&mut self, command: &Command) -> Result<()> {
+        self.check_output()?;
+        self.speed = self.pick(self.config.speed);
+        self.jitter = self.pick(self.config.jitter);
+        // Blocks hold the viewer's keys command by command, leaving the
+        // waits between them free for typing
+        let hold = (self.typethrough || self.live)
+            && self.rehearsal_path.is_none()
+            && command.sends_input()
+            && !matches!(
+                command,
+                Command::Shuffle(_) | Command::NoEcho(_) | Command::Tagged(_, _) | Command::Live(_)
+            )
+            && self.pty.divert_viewer_input(true);
+        let result = self.play_command(command).await;
+        let released = if hold {
+            self.pty.release_viewer_input()
+        } else {
+            Ok(())
+        };
+        result.and(released)
+    }
+
+    // Play `command`, then wait for the prompt if it pressed Enter
+    async fn play_command(&mut self, command: &Command) -> Result<()> {
+        match self.execute_command(command).await {
+            Err(e)
+                if self.write_errors == WriteErrorPolicy::Continue
+                    && e.downcast_ref::<WriteError>().is_some() =>
+            {
+                eprintln!("\n{e:#}, skipping the rest of the command");
+            }
+            result => result?,
+        }
+        if let Some(seen) = self.awaiting_prompt.take() {
+            self.wait_for_prompt(seen).await;
+        }
+        Ok(())
+    }
+
+    // Await a hook's future, abandoning it if playback is interrupted, then
+    // act on its result. Time spent in hooks counts as scheduled, not drift.
+    async fn run_hook(&mut self, future: HookFuture) {
+        let start = Instant::now();
+        let running = self.running.clone();
+        let action = tokio::select! {
+            action = future => action,
+            () = async {
+                while running.load(Ordering::SeqCst) {
+                    sleep(IDLE_POLL).await;
+                }
+            } => HookAction::Stop,
+        };
+        self.scheduled += start.elapsed();
+
+        match action {
+            HookAction::Continue => {}
+            HookAction::Pause(duration) => self.pause(duration).await,
+            HookAction::Stop => self.running.store(false, Ordering::SeqCst),
+        }
+    }
+
     pub async fn execute(&mut self, script: Script) -> Result<()> {
-        for command in script.commands {
+        self.execute_from(script, &Checkpoint::default()).await
+    }
+
+    // Pick up a run where `checkpoint` left it: restore its settings, then
+    // play from its next top-level command. The script must be the same one,
+    // down to the --tags and other options that shape it.
+    pub async fn execute_from(
+        &mut self,
+        mut script: Script,
+        checkpoint: &Checkpoint,
+    ) -> Result<()> {
+        if self.ending == Ending::NoFinalEnter {
+            strip_final_enter(&mut script.commands);
+        }
+        // Before the timeline starts, so timings are from the script's start
+        if self.ready_sentinel {
+            self.wait_until_ready().await?;
+        }
+        if self.prewarm {
+            self.prewarm().await?;
+        }
+        self.timeline_start = Instant::now();
+        self.scheduled = Duration::ZERO;
+        self.cues.clear();
+        self.markers.clear();
+        self.keystrokes.clear();
+        self.verifications.clear();
+        self.rehearsal_notes.clear();
+        let mut sources = Vec::new();
+        if self.rehearsal_path.is_some() {
+            if !self.pty.divert_viewer_input(true) {
+                eprintln!("Warning: no terminal to read rehearsal keys from");
+            }
+            sources = script.commands.iter().map(Command::source_line).collect();
+        }
+
+        let skip = checkpoint.next;
+        let mut skipped = Vec::new();
+        lasting_effects(
+            &script.commands[..skip.min(script.commands.len())],
+            &mut skipped,
+        );
+        for command in skipped.into_iter().chain(&checkpoint.settings) {
+            self.execute_command(command).await?;
+        }
+
+        for (index, command) in script.commands.into_iter().enumerate().skip(skip) {
             if !self.should_continue() {
                 break;
             }
+            self.command_index = index;
+
+            if let Some(hook) = &mut self.before_command {
+                let future = hook(&command);
+                self.run_hook(future).await;
+                if !self.should_continue() {
+                    break;
+                }
+            }
+            if command.sends_input() {
+                self.markers.push(Cue {
+                    start: self.timeline_start.elapsed(),
+                    text: command.source_line(),
+                });
+            }
+            self.run_command(&command).await?;
+            self.take_rehearsal_keys();
+            if let Some(hook) = &mut self.after_command {
+                let future = hook(&command);
+                self.run_hook(future).await;
+            }
+            if let Some(every) = self.checkpoint_every
+                && (index + 1) % every == 0
+                && self.should_continue()
+            {
+                self.write_checkpoint(index + 1)?;
+            }
+        }
+
+        if self.ending == Ending::Clear && self.should_continue() {
+            self.pause(CLEAR_HOLD).await;
+            self.pty.send_keystroke("\x0c")?;
+        }
+
+        if self.theme_applied && !self.keep_theme {
+            self.run_command(&Command::ResetTheme).await?;
+        }
 
-            self.execute_command(&command).await?;
+        if let Some(path) = &self.subtitle_path {
+            let end = self.timeline_start.elapsed();
+            let srt = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("srt"));
+            let track = if srt {
+                captions::to_srt(&self.cues, end)
+            } else {
+                captions::to_webvtt(&self.cues, end)
+            };
+            std::fs::write(path, track)
+                .with_context(|| format!("Failed to write subtitles to {}", path.display()))?;
+        }
+        if let Some(path) = &self.marker_path {
+            std::fs::write(path, captions::to_asciicast_markers(&self.markers))
+                .with_context(|| format!("Failed to write markers to {}", path.display()))?;
+        }
+        if let Some(path) = &self.rehearsal_path {
+            self.pty.divert_viewer_input(false);
+            std::fs::write(
+                path,
+                rehearsal_notes_to_text(&self.rehearsal_notes, &sources),
+            )
+            .with_context(|| format!("Failed to write rehearsal notes to {}", path.display()))?;
+        }
+        if let Some(path) = &self.keystroke_log_path {
+            std::fs::write(path, keystrokes_to_csv(&self.keystrokes, self.key_notation))
+                .with_context(|| {
+                    format!("Failed to write the keystroke log to {}", path.display())
+                })?;
+        }
+
+        self.exit_time = None;
+        if let Some(budget) = self.exit_budget
+            && self.should_continue()
+        {
+            // Real time, even under a paused clock, as this blocks on the shell
+            let start = std::time::Instant::now();
+            self.pty.wait_for_exit(budget).with_context(|| {
+                format!(
+                    "The shell didn't exit within {}s of playback ending",
+                    budget.as_secs_f64()
+                )
+            })?;
+            self.exit_time = Some(start.elapsed());
+        }
+
+        if let Some(expected) = self.expected_exit
+            && self.should_continue()
+        {
+            let status = self
+                .pty
+                .wait_for_exit(EXIT_TIMEOUT)
+                .with_context(|| format!("Can't check for exit code {expected}"))?;
+            if let Some(signal) = status.signal() {
+                anyhow::bail!(
+                    "The shell was terminated by {signal}, expected exit code {expected}"
+                );
+            }
+            if status.exit_code() != expected {
+                anyhow::bail!(
+                    "The shell exited with code {}, expected {expected}",
+                    status.exit_code()
+                );
+            }
+        }
+
+        if self.should_continue() {
+            for command in &self.verifications {
+                verify(command)?;
+            }
         }
         Ok(())
     }
 }
 
+// Run a @verify command on the host with the system shell, failing unless it
+// exits successfully. Its output is only shown if it fails, since the host
+// terminal may still be in raw mode.
+fn verify(command: &str) -> Result<()> {
+    #[cfg(windows)]
+    let mut process = std::process::Command::new("cmd");
+    #[cfg(windows)]
+    process.arg("/C");
+    #[cfg(not(windows))]
+    let mut process = std::process::Command::new("sh");
+    #[cfg(not(windows))]
+    process.arg("-c");
+
+    let output = process
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run @verify command `{command}`"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let status = match output.status.code() {
+        Some(code) => format!("exited with code {code}"),
+        None => "was terminated by a signal".to_string(),
+    };
+    let mut message = format!("Verification failed: `{command}` {status}");
+    for stream in [&output.stdout, &output.stderr] {
+        let text = String::from_utf8_lossy(stream);
+        if !text.trim().is_empty() {
+            message.push_str(&format!("\n{}", text.trim_end()));
+        }
+    }
+    anyhow::bail!(message)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // An engine writing to memory, with the sink to read back what it typed
+    fn sink_engine() -> (PlaybackEngine, crate::pty::MemorySink) {
+        let sink = crate::pty::MemorySink::default();
+        let pty = PtyManager::with_sink(sink.clone(), 80, 24);
+        let engine = PlaybackEngine::without_signal_handler(pty, Arc::new(AtomicBool::new(true)));
+        (engine, sink)
+    }
+
     #[test]
     fn test_escape_sequence_lengths() {
         assert_eq!(PlaybackEngine::escape_sequence_length(b"\x1b[A"), 3);
@@ -186,6 +1801,632 @@ mod tests {
         assert_eq!(PlaybackEngine::escape_sequence_length(b"a"), 1);
     }
 
+    #[tokio::test]
+    async fn test_directives_update_config() {
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script(
+            "@ speed:0.001\n@ jitter:0.5\n@ sync:on\n@ suggestion_key:<end>\n$ hi",
+        )
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let config = engine.config();
+        assert_eq!(config.speed, 0.001.into());
+        assert_eq!(config.jitter, 0.5.into());
+        assert_eq!(config.sync, SyncMode::Schedule);
+        assert_eq!(config.suggestion_key, "\x1b[F");
+        assert_eq!(sink.contents(), b"hi");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_noecho_text_is_not_retyped() {
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script(
+            "$ pw: \n@ noecho\n$ abc\n@ end\n@ retype:x\n@ noecho\n$ y<ret>\n@ end\n@ retype:z",
+        )
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        // Only the visible "pw:" is erased, and the hidden Enter still ends the line
+        assert_eq!(sink.contents(), b"pw:abc\x7f\x7f\x7fxy\rz");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_from_checkpoint() {
+        let path =
+            std::env::temp_dir().join(format!("quipu-checkpoint-{}.txt", std::process::id()));
+        let source = "@ speed:0.05\n$ a\n@ push\n@ speed:0.2\n@ backspace:bs\n@ checkpoint\n\
+                      $ b\n@ pop\n$ c";
+        let script = crate::parser::parse_script(source).unwrap();
+        let (mut engine, _) = sink_engine();
+        engine.set_checkpoint_path(path.clone(), None);
+        engine.execute(script).await.unwrap();
+        let checkpoint = Checkpoint::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.next, 6);
+
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script(source).unwrap();
+        engine.execute_from(script, &checkpoint).await.unwrap();
+        assert_eq!(sink.contents(), b"bc");
+        // The pop restored the config saved before the checkpoint
+        assert_eq!(engine.config().speed, 0.05.into());
+        assert_eq!(engine.config().backspace, crate::types::BackspaceKey::Del);
+
+        // Checks set by skipped commands still run at the end
+        let resume = Checkpoint {
+            next: 3,
+            settings: Vec::new(),
+        };
+        let source = "@ expect_exit:3\n@ tag:intro\n@ verify:exit 7\n@ end\n$ a\n@ checkpoint\n$ b";
+        let (mut engine, _) = sink_engine();
+        let script = crate::parser::parse_script(source).unwrap();
+        let err = engine.execute_from(script, &resume).await.unwrap_err();
+        assert!(format!("{err:#}").contains("exit code 3"), "{err:#}");
+        assert_eq!(engine.verifications, vec!["exit 7".to_string()]);
+
+        assert!(Checkpoint::parse("$ ls").is_err());
+        assert!(Checkpoint::parse("next:1\n$ ls").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_checkpoint_every() {
+        let path =
+            std::env::temp_dir().join(format!("quipu-checkpoint-every-{}.txt", std::process::id()));
+        let script = crate::parser::parse_script("$ a\n$ b\n@ speed:0.3\n$ c\n$ d").unwrap();
+        let (mut engine, _) = sink_engine();
+        engine.set_checkpoint_path(path.clone(), Some(3));
+        engine.execute(script).await.unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.starts_with("next:3\n@ speed:0.3\n"), "{text}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paste_block_sends_newlines_between_markers() {
+        let (mut engine, sink) = sink_engine();
+        let script =
+            crate::parser::parse_script("$ x\n@ paste\n$ one\n$\n$ two\n@ end\n$ <ret>").unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(sink.contents(), b"x\x1b[200~one\n\ntwo\x1b[201~\r");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(start_paused = true)]
+    async fn test_verify_runs_after_playback() {
+        let (mut engine, sink) = sink_engine();
+        let script =
+            crate::parser::parse_script("@ verify:echo oops >&2; exit 3\n@ verify:true\n$ x")
+                .unwrap();
+        let err = engine.execute(script).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Verification failed: `echo oops >&2; exit 3` exited with code 3\noops"
+        );
+        // Run only once everything has been typed
+        assert_eq!(sink.contents(), b"x");
+
+        let script = crate::parser::parse_script("$ y\n@ verify:test -n \"$HOME\"").unwrap();
+        engine.execute(script).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ready_sentinel_times_out() {
+        let (mut engine, sink) = sink_engine();
+        engine.set_ready_sentinel(true);
+        let start = Instant::now();
+        let err = engine
+            .execute(crate::parser::parse_script("$ x").unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("ready sentinel"), "{err}");
+        assert_eq!(start.elapsed(), READY_TIMEOUT);
+        // Nothing from the script is typed into a session that isn't ready
+        assert_eq!(sink.contents(), READY_COMMAND.as_bytes());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keystroke_log() {
+        let path =
+            std::env::temp_dir().join(format!("quipu-keystrokes-{}.csv", std::process::id()));
+        let (mut engine, _) = sink_engine();
+        engine.set_keystroke_log_path(path.clone());
+        let script = crate::parser::parse_script(
+            "$ a,<up>\n@ wait:1\n@ speed:0.05\n$ \"<ret>\n@ secret:$PATH",
+        )
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (typed, secret) = log.split_at(log.find("1400.000").unwrap());
+        assert_eq!(
+            typed,
+            "elapsed_ms,key,delay_ms\n\
+             0.000,a,100.000\n\
+             100.000,\",\",100.000\n\
+             200.000,<up>,100.000\n\
+             1300.000,\"\"\"\",50.000\n\
+             1350.000,<ret>,50.000\n"
+        );
+        let path_len = std::env::var("PATH").unwrap().chars().count();
+        assert_eq!(secret.lines().count(), path_len);
+        assert!(
+            secret.lines().all(|row| row.contains(",<secret>,")),
+            "{secret}"
+        );
+    }
+
+    #[test]
+    fn test_time_until() {
+        let at = |h, m, s| chrono::NaiveTime::from_hms_opt(h, m, s).unwrap();
+        let now = chrono::NaiveDate::from_ymd_opt(2024, 12, 31)
+            .unwrap()
+            .and_time(at(23, 0, 0));
+        assert_eq!(
+            time_until(now, at(23, 30, 15), false),
+            Some(Duration::from_secs(30 * 60 + 15))
+        );
+        assert_eq!(time_until(now, at(23, 0, 0), false), Some(Duration::ZERO));
+        assert_eq!(time_until(now, at(9, 0, 0), false), None);
+        // Tomorrow's, across the end of the year
+        assert_eq!(
+            time_until(now, at(9, 0, 0), true),
+            Some(Duration::from_secs(10 * 60 * 60))
+        );
+        assert_eq!(
+            time_until(now, at(23, 30, 0), true),
+            Some(Duration::from_secs(30 * 60))
+        );
+    }
+
+    #[test]
+    fn test_rehearsal_notes_to_text() {
+        let notes = [
+            RehearsalNote {
+                command: 0,
+                at: Duration::from_millis(1250),
+                note: "too fast",
+            },
+            RehearsalNote {
+                command: 2,
+                at: Duration::from_secs(4),
+                note: "too slow",
+            },
+        ];
+        let sources = ["$ ls<ret>", "@ wait:1", "$ git status<ret>"].map(String::from);
+        assert_eq!(
+            rehearsal_notes_to_text(&notes, &sources),
+            "#1 1.250s too fast: $ ls<ret>\n#3 4.000s too slow: $ git status<ret>\n"
+        );
+    }
+
+    #[test]
+    fn test_keystroke_log_caret_notation() {
+        let keystroke = |key: &str| Keystroke {
+            at: Duration::ZERO,
+            key: key.to_string(),
+            delay: Duration::ZERO,
+        };
+        let keystrokes = [keystroke("\x1b[A"), keystroke(","), keystroke("\r")];
+        assert_eq!(
+            keystrokes_to_csv(&keystrokes, KeyNotation::Caret),
+            "elapsed_ms,key,delay_ms\n\
+             0.000,^[[A,0.000\n\
+             0.000,\",\",0.000\n\
+             0.000,^M,0.000\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backspace_key_is_used_to_erase() {
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script("$ ab\n@ backspace:bs\n@ retype:c<bs>").unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(sink.contents(), b"ab\x08\x08c\x08");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prompt_sync_falls_back_without_markers() {
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script("@ sync:osc133\n$ a<ret>\n$ b<ret>").unwrap();
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // Only the first Enter waits for a prompt that never comes
+        assert_eq!(sink.contents(), b"a\rb\r");
+        assert_eq!(engine.config().sync, SyncMode::Off);
+        assert!(start.elapsed() >= PROMPT_TIMEOUT);
+        assert!(start.elapsed() < PROMPT_TIMEOUT * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_word_typos_are_erased_and_reproducible() {
+        async fn play(seed: u64) -> Vec<u8> {
+            let (mut engine, sink) = sink_engine();
+            engine.set_seed(seed);
+            let script =
+                crate::parser::parse_script("@ word_typos:1\n$ git commit -m ok<ret>").unwrap();
+            engine.execute(script).await.unwrap();
+            sink.contents()
+        }
+
+        let typed = play(1).await;
+        assert_eq!(typed, play(1).await);
+        // "git" and "commit" are mistyped, losing at most a letter each, and
+        // erased before being typed properly
+        assert!(typed.iter().filter(|&&b| b == 0x7f).count() >= 2 + 5);
+        let mut line = Vec::new();
+        for &b in &typed {
+            if b == 0x7f {
+                line.pop();
+            } else {
+                line.push(b);
+            }
+        }
+        assert_eq!(line, b"git commit -m ok\r");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_typo_chars() {
+        let (mut engine, sink) = sink_engine();
+        engine.set_seed(3);
+        let script = crate::parser::parse_script(
+            "@ word_typos:1\n@ typo_chars:a-z\n$ echo \"Hello\" | grep XYZ_def<ret>",
+        )
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        // Only the lowercase words are mistyped: not "Hello" from its second
+        // letter, nor anything around them
+        let mut line = Vec::new();
+        let mut erased = Vec::new();
+        for b in sink.contents() {
+            if b == 0x7f {
+                erased.extend(line.pop());
+            } else {
+                line.push(b);
+            }
+        }
+        assert_eq!(line, b"echo \"Hello\" | grep XYZ_def\r");
+        assert!(
+            (3 + 3 + 2..=4 + 4 + 3).contains(&erased.len()),
+            "{erased:?}"
+        );
+        assert!(erased.iter().all(u8::is_ascii_lowercase), "{erased:?}");
+    }
+
+    #[test]
+    fn test_mistyped() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let wrong = mistyped("hello", &mut rng);
+            assert_ne!(wrong, "hello");
+            assert!((4..=5).contains(&wrong.len()), "{wrong}");
+        }
+        assert_eq!(mistyped("aa", &mut rng), "a");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expect_exit_needs_a_shell() {
+        let (mut engine, _) = sink_engine();
+        let script = crate::parser::parse_script("$ true<ret>\n@ expect_exit:0").unwrap();
+        let err = engine.execute(script).await.unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Can't check for exit code 0: there is no shell started by quipu to wait for"
+        );
+    }
+
+    #[test]
+    fn test_estimate_duration() {
+        let script = crate::parser::parse_script(
+            "@ speed:0.1\n$ ab<up><ret>\n@ wait:2\n@ speed:0.5\n@ retype:x\n\
+             @ shuffle\n$ c\n@ wait:1\n@ end\n@ resize:80:24:0.5\n$\n@ autoenter:on\n$",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        // Four keystrokes at 0.1s, then none to erase, "x", "c" and Enter at 0.5s
+        assert_eq!(
+            estimate,
+            DurationEstimate {
+                typing: Duration::from_secs_f64(0.4 + 1.5),
+                waits: Duration::from_secs_f64(3.5),
+                keystrokes: 7,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_word_pause_follows_spaces() {
+        let (mut engine, _) = sink_engine();
+        let script = crate::parser::parse_script("@ word_pause:0.3\n$ a b c").unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // Five keystrokes at 0.1s, plus 0.3s after each of the two spaces
+        assert_eq!(start.elapsed(), Duration::from_millis(1100));
+        assert_eq!(estimate.typing, Duration::from_millis(1100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prewarm() {
+        let (mut engine, sink) = sink_engine();
+        engine.set_prewarm(true);
+        let start = Instant::now();
+        engine
+            .execute(crate::parser::parse_script("$ x").unwrap())
+            .await
+            .unwrap();
+        // The no-op goes first; nothing answers it here, so playback goes
+        // ahead by the time the prewarm times out
+        assert_eq!(sink.contents(), b" :\rx");
+        assert!(start.elapsed() <= PREWARM_TIMEOUT + Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_type_transform() {
+        let (mut engine, sink) = sink_engine();
+        engine.set_type_transform(|text| text.replace("hunter2", "*******"));
+        let script =
+            crate::parser::parse_script("$ login hunter2\n@ retype:hunter2<ret>\n$ <up>").unwrap();
+        engine.execute(script).await.unwrap();
+        // The retype erases what was typed, but isn't transformed itself
+        let mut expected = b"login *******".to_vec();
+        expected.extend([0x7f; 13]);
+        expected.extend(b"hunter2\r\x1b[A");
+        assert_eq!(sink.contents(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_srt_subtitles() {
+        let path = std::env::temp_dir().join(format!("quipu-captions-{}.srt", std::process::id()));
+        let (mut engine, _) = sink_engine();
+        engine.set_subtitle_path(path.clone());
+        let script = crate::parser::parse_script(
+            "$ ab\n@ caption:Typing\n$ cd\n@ wait:1\n@ subtitle:\n$ e\n@ caption:Done",
+        )
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        // Each caption starts when playback reaches it; the empty one only
+        // ends the first, and the last has no time left to show
+        let track = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(track, "1\n00:00:00,200 --> 00:00:01,400\nTyping\n\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overwrite() {
+        let (mut engine, sink) = sink_engine();
+        let script = crate::parser::parse_script(
+            "$ ls -la\n@ overwrite:4:-lh\n@ overwrite:6:hZ\n@ retype:x",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // Back to the column, deleting each character before typing over it;
+        // past the end of the line it just types, and the line grows
+        let mut expected = b"ls -la\x1b[D\x1b[D\x1b[D".to_vec();
+        expected.extend(b"\x1b[3~-\x1b[3~l\x1b[3~h");
+        expected.extend(b"\x1b[D\x1b[3~hZ");
+        expected.extend([0x7f; 7]);
+        expected.push(b'x');
+        assert_eq!(sink.contents(), expected);
+        assert_eq!(start.elapsed(), Duration::from_millis(2700));
+        assert_eq!(estimate.typing, Duration::from_millis(2700));
+
+        assert_eq!(
+            overwrite_keys(5, 1, "ab"),
+            (
+                "\x1b[D".repeat(5) + "\x1b[3~a\x1b[3~b" + &"\x1b[C".repeat(3),
+                5
+            )
+        );
+        assert_eq!(overwrite_keys(2, 9, "ab"), ("ab".to_string(), 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_lines_type_faster() {
+        let (mut engine, _) = sink_engine();
+        let script = crate::parser::parse_script(
+            "@ long_lines:4:2\n$ abcd\n$ abcdefgh\n$ abcdefghijklmnop\n@ long_lines:off\n$ abcdefgh",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // 4 keystrokes at 0.1s, 8 at half speed, 16 at the 2x cap, then 8
+        // at full speed again
+        let expected = Duration::from_millis(400 + 400 + 800 + 800);
+        assert_eq!(start.elapsed(), expected);
+        assert_eq!(estimate.typing, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_push_pop() {
+        let (mut engine, _) = sink_engine();
+        let script = crate::parser::parse_script(
+            "@ speed:0.2\n@ push\n@ speed:0.05\n@ push\n@ speed:0.5\n@ pop\n$ ab\n@ pop\n$ ab",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // Two keystrokes at the speed restored by the inner @pop, then two at
+        // the one restored by the outer
+        let expected = Duration::from_millis(100 + 400);
+        assert_eq!(start.elapsed(), expected);
+        assert_eq!(estimate.typing, expected);
+
+        let unbalanced = Script {
+            commands: vec![Command::PopConfig],
+        };
+        let err = engine.execute(unbalanced).await.unwrap_err();
+        assert!(
+            err.to_string().contains("@pop without a matching @push"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_jitter_params() {
+        let (mut engine, _) = sink_engine();
+        let script =
+            crate::parser::parse_script("@ jitter_params:2:0\n$ ab\n@ jitter_params:off\n$ ab")
+                .unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+
+        // Two keystrokes at twice the speed, then two back at the speed
+        let expected = Duration::from_millis(400 + 200);
+        assert_eq!(start.elapsed(), expected);
+        assert_eq!(estimate.typing, expected);
+
+        engine.set_seed(1);
+        engine.config.jitter_params = Some(crate::types::JitterParams {
+            mean: 1.0,
+            stddev: 0.5,
+        });
+        let delays: Vec<_> = (0..100).map(|_| engine.calculate_delay()).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_estimate_wpm() {
+        let script = crate::parser::parse_script("@ speed:0.2\n$ hello<ret>\n@ reveal:hi").unwrap();
+        let estimate = estimate_duration(&script.commands, &PlaybackConfig::default());
+        assert_eq!(estimate.keystrokes, 6);
+        // Six keystrokes in 1.6s, counting the reveal's time
+        assert!((estimate.wpm().unwrap() - 45.0).abs() < 1e-9);
+        let faster = estimate.scaled(0.5, 1.0);
+        assert!((faster.wpm().unwrap() - 90.0).abs() < 1e-9);
+        assert_eq!(DurationEstimate::default().wpm(), None);
+    }
+
+    #[test]
+    fn test_duration_scales() {
+        let estimate = DurationEstimate {
+            typing: Duration::from_secs(10),
+            waits: Duration::from_secs(10),
+            keystrokes: 100,
+        };
+        assert_eq!(
+            estimate.scales_for(Duration::from_secs(30), false),
+            Ok((2.0, 1.0))
+        );
+        assert_eq!(
+            estimate.scales_for(Duration::from_secs(10), true),
+            Ok((0.5, 0.5))
+        );
+        assert!(estimate.scales_for(Duration::from_secs(10), false).is_err());
+        assert!(
+            DurationEstimate::default()
+                .scales_for(Duration::from_secs(10), true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_render_markup() {
+        assert_eq!(render_markup("plain"), "plain");
+        assert_eq!(
+            render_markup("{red}Error{/}: bad"),
+            "\x1b[31mError\x1b[0m: bad"
+        );
+        // Unterminated styles are reset at the end of the caption
+        assert_eq!(render_markup("{bold}{dim}x"), "\x1b[1m\x1b[2mx\x1b[0m");
+        // Unknown and unclosed tags pass through
+        assert_eq!(render_markup("{nope} {red"), "{nope} {red");
+    }
+
+    #[test]
+    fn test_next_line_len() {
+        let typed = |text: &str| text.chars().fold(0, next_line_len);
+        assert_eq!(typed("echo h\u{e9}llo"), 10);
+        assert_eq!(typed("ls\rcd /tmp"), 7);
+        assert_eq!(typed("abc\x7f\x7f"), 1);
+        assert_eq!(typed("\x7f\x7fab\t"), 2);
+        assert_eq!(typed("oops\x15ok"), 2);
+    }
+
+    #[test]
+    fn test_strip_final_enter() {
+        let mut commands = vec![
+            Command::Type("ls\r".to_string()),
+            Command::Type("exit\r".to_string()),
+            Command::Wait(Duration::from_secs(1)),
+        ];
+        strip_final_enter(&mut commands);
+        assert_eq!(commands[0], Command::Type("ls\r".to_string()));
+        assert_eq!(commands[1], Command::Type("exit".to_string()));
+
+        // A last line without Enter is left alone
+        let mut commands = vec![
+            Command::Type("vim\r".to_string()),
+            Command::Type(":q".to_string()),
+        ];
+        strip_final_enter(&mut commands);
+        assert_eq!(commands[1], Command::Type(":q".to_string()));
+
+        // The last line may be inside a block
+        let mut commands = crate::parser::parse_script(
+            "$ ls<ret>\n@ tag:outro\n@ live\n$ exit<ret>\n@ end\n@ wait:1\n@ end\n@ noecho\n@ end",
+        )
+        .unwrap()
+        .commands;
+        strip_final_enter(&mut commands);
+        assert_eq!(
+            commands[1],
+            Command::Tagged(
+                vec!["outro".to_string()],
+                vec![
+                    Command::Live(vec![Command::Type("exit".to_string())]),
+                    Command::Wait(Duration::from_secs(1)),
+                ]
+            )
+        );
+        assert_eq!(commands[0], Command::Type("ls\r".to_string()));
+
+        // Or in a shuffle, where it isn't known which it is
+        let mut commands =
+            crate::parser::parse_script("$ ls<ret>\n@ shuffle\n$ a<ret>\n$ b<ret>\n@ end")
+                .unwrap()
+                .commands;
+        let before = commands.clone();
+        strip_final_enter(&mut commands);
+        assert_eq!(commands, before);
+
+        // Even when the shuffle is inside a block
+        let mut commands = crate::parser::parse_script(
+            "$ ls<ret>\n@ tag:a\n@ shuffle\n$ x<ret>\n$ y<ret>\n@ end\n@ end",
+        )
+        .unwrap()
+        .commands;
+        let before = commands.clone();
+        strip_final_enter(&mut commands);
+        assert_eq!(commands, before);
+
+        // Or when something other than a typed line sends input last
+        for last in ["@ retype:pwd<ret>", "@ paste\n$ pwd\n@ end"] {
+            let mut commands = crate::parser::parse_script(&format!("$ ls<ret>\n{last}"))
+                .unwrap()
+                .commands;
+            let before = commands.clone();
+            strip_final_enter(&mut commands);
+            assert_eq!(commands, before, "{last}");
+        }
+    }
+
     #[test]
     fn test_escape_sequence_length_stays_on_char_boundary() {
         // ESC directly followed by a multibyte char
@@ -18,16 +18,24 @@
 //! Handles spawning processes in a PTY and sending keystrokes to them
 
 use anyhow::{Context, Result};
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use portable_pty::{CommandBuilder, PtySize, native_pty_system};
+use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode};
+use portable_pty::{
+    Child, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, PtySystem, native_pty_system,
+};
 use std::io::{IsTerminal, Read, Write};
 use std::path::Path;
 use std::sync::{
-    Arc, Mutex,
-    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, OnceLock,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::types::CtrlCPolicy;
+
+// The terminal type the shell is told it runs in, unless a script sets TERM
+pub const SESSION_TERM: &str = "xterm-256color";
 
 // RAII guard for terminal raw mode - only enables if stdout is a TTY
 struct RawModeGuard {
@@ -64,33 +72,790 @@ type SharedWriter = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
 // state to escape codes.
 type SharedParser = Arc<Mutex<vt100::Parser>>;
 
+// In-memory sink for PtyManager::with_sink, recording every byte written.
+// Clones share the same buffer, so one can be kept to read it back.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().map(|bytes| bytes.clone()).unwrap_or_default()
+    }
+}
+
+impl Write for MemorySink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::other("sink lock poisoned"))?
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Tunables for PTY creation
+#[derive(Debug, Clone)]
+pub struct PtyOptions {
+    // Extra attempts at opening the PTY after a transient failure
+    pub open_retries: u32,
+    // Delay before the first retry, doubled for each one after it
+    pub open_backoff: Duration,
+    // Extra environment variables for the shell, applied after TERM
+    pub env: Vec<(String, String)>,
+    // Remove window title changes from the output relayed to stdout
+    pub strip_titles: bool,
+    // Input written to the shell as soon as it starts, ahead of anything
+    // typed by playback or forwarded from stdin
+    pub prime: Vec<u8>,
+    // Keep a timeline of the session's output, read back with
+    // PtyManager::recording
+    pub record: bool,
+    // What a Ctrl-C typed by the viewer does to playback
+    pub ctrl_c: CtrlCPolicy,
+    // File mode creation mask for the shell, on Unix
+    pub umask: Option<u32>,
+    // Hold each screenful of fast-scrolling output on screen for at least
+    // this long
+    pub min_frame: Option<Duration>,
+    // Longest the session's output is held back to be written to stdout
+    // together with what follows it; zero writes each read as it comes
+    pub flush_window: Duration,
+    // Write held-back output once this many bytes are waiting
+    pub flush_bytes: usize,
+}
+
+impl Default for PtyOptions {
+    fn default() -> Self {
+        Self {
+            open_retries: 3,
+            open_backoff: Duration::from_millis(100),
+            env: Vec::new(),
+            strip_titles: false,
+            prime: Vec::new(),
+            record: false,
+            ctrl_c: CtrlCPolicy::Abort,
+            umask: None,
+            min_frame: None,
+            flush_window: DEFAULT_FLUSH_WINDOW,
+            flush_bytes: DEFAULT_FLUSH_BYTES,
+        }
+    }
+}
+
+// Short enough not to be noticed when typing, long enough to gather the
+// bursts of small writes chatty programs make
+pub const DEFAULT_FLUSH_WINDOW: Duration = Duration::from_millis(4);
+pub const DEFAULT_FLUSH_BYTES: usize = 64 * 1024;
+
+// How close together two Ctrl-Cs must be to stop playback under
+// CtrlCPolicy::Forward
+const DOUBLE_CTRL_C: Duration = Duration::from_millis(500);
+
+// Tracks the viewer's Ctrl-Cs to decide when one should stop playback
+struct CtrlCTracker {
+    policy: CtrlCPolicy,
+    last: Option<Instant>,
+}
+
+impl CtrlCTracker {
+    fn new(policy: CtrlCPolicy) -> Self {
+        Self { policy, last: None }
+    }
+
+    // Whether a Ctrl-C pressed at `now` stops playback
+    fn press(&mut self, now: Instant) -> bool {
+        match self.policy {
+            CtrlCPolicy::Abort => true,
+            CtrlCPolicy::Forward => {
+                let double = self
+                    .last
+                    .is_some_and(|last| now.duration_since(last) <= DOUBLE_CTRL_C);
+                self.last = Some(now);
+                double
+            }
+        }
+    }
+}
+
+// Something that happened to a recorded session
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Output(Vec<u8>),
+    Resize(u16, u16),
+}
+
+// Timeline of a session's output and resizes, timed from when it started.
+// Clones share the same timeline, so one can be kept to read it back after
+// the session has ended.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    start: Instant,
+    size: (u16, u16),
+    events: Arc<Mutex<Vec<(Duration, SessionEvent)>>>,
+}
+
+impl Recording {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            start: Instant::now(),
+            size: (cols, rows),
+            events: Arc::default(),
+        }
+    }
+
+    pub fn push(&self, event: SessionEvent) {
+        if let Ok(mut events) = self.events.lock() {
+            events.push((self.start.elapsed(), event));
+        }
+    }
+
+    // Columns and rows the session started with
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    pub fn events(&self) -> Vec<(Duration, SessionEvent)> {
+        self.events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default()
+    }
+
+    // Everything the session printed, in order
+    pub fn output(&self) -> Vec<u8> {
+        self.events()
+            .into_iter()
+            .filter_map(|(_, event)| match event {
+                SessionEvent::Output(bytes) => Some(bytes),
+                SessionEvent::Resize(_, _) => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    // A copy with the dead air at either end cut: events before the screen
+    // first changes and after it last changes are dropped, and the rest are
+    // moved earlier so the first change comes at most `lead` after the start
+    pub fn trimmed(&self, lead: Duration) -> Recording {
+        let events = self.events();
+        let mut parser = vt100::Parser::new(self.size.1, self.size.0, 0);
+        let mut state = screen_state(parser.screen());
+        let mut changes = Vec::new();
+        for (index, (_, event)) in events.iter().enumerate() {
+            match event {
+                SessionEvent::Output(bytes) => parser.process(bytes),
+                SessionEvent::Resize(cols, rows) => parser.screen_mut().set_size(*rows, *cols),
+            }
+            let next = screen_state(parser.screen());
+            if next != state {
+                changes.push(index);
+                state = next;
+            }
+        }
+
+        let kept = match (changes.first(), changes.last()) {
+            (Some(&first), Some(&last)) => {
+                let shift = events[first].0.saturating_sub(lead);
+                events[first..=last]
+                    .iter()
+                    .map(|(at, event)| (*at - shift, event.clone()))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Recording {
+            start: self.start,
+            size: self.size,
+            events: Arc::new(Mutex::new(kept)),
+        }
+    }
+}
+
+// What a viewer of the screen would see
+fn screen_state(screen: &vt100::Screen) -> (Vec<u8>, (u16, u16), bool) {
+    (
+        screen.contents_formatted(),
+        screen.cursor_position(),
+        screen.hide_cursor(),
+    )
+}
+
+// Shell to use when neither the command line nor the script names one: the
+// user's login shell from $SHELL, or /bin/sh if that is unset or empty
+pub fn default_shell() -> String {
+    std::env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.trim().is_empty())
+        .unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+// Size of the session when the script doesn't set one and it isn't shown in
+// a terminal to match
+pub const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+// Size of the host terminal for a session to fill: that of stdout if it is a
+// terminal, or DEFAULT_SIZE if it isn't or its size can't be read
+pub fn host_size() -> (u16, u16) {
+    let size = std::io::stdout()
+        .is_terminal()
+        .then(|| terminal::size().ok())
+        .flatten();
+    size_or_default(size)
+}
+
+// Some terminals (e.g. a serial console) report 0x0
+fn size_or_default(size: Option<(u16, u16)>) -> (u16, u16) {
+    size.filter(|&(cols, rows)| cols > 0 && rows > 0)
+        .unwrap_or(DEFAULT_SIZE)
+}
+
+// The OS error behind a failed openpty. portable-pty formats the io::Error
+// into its message rather than chaining it, so fall back to recovering the
+// raw code from the Debug output.
+fn open_error_kind(err: &anyhow::Error) -> Option<std::io::ErrorKind> {
+    if let Some(io) = err.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) {
+        return Some(io.kind());
+    }
+    let message = err.to_string();
+    let (_, rest) = message.split_once("code: ")?;
+    let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    let code = digits.parse().ok()?;
+    Some(std::io::Error::from_raw_os_error(code).kind())
+}
+
+// Resource exhaustion (EMFILE, ENOSPC on /dev/ptmx, EAGAIN) clears up on busy
+// CI runners; a bad size or missing permission never will.
+fn is_transient_open_error(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    match open_error_kind(err) {
+        Some(
+            ErrorKind::PermissionDenied
+            | ErrorKind::NotFound
+            | ErrorKind::InvalidInput
+            | ErrorKind::Unsupported,
+        )
+        | None => false,
+        Some(_) => true,
+    }
+}
+
+fn open_pty(pty_system: &dyn PtySystem, size: PtySize, options: &PtyOptions) -> Result<PtyPair> {
+    let mut backoff = options.open_backoff;
+    let mut attempt = 0;
+    loop {
+        match pty_system.openpty(size) {
+            Ok(pair) => return Ok(pair),
+            Err(e) if attempt < options.open_retries && is_transient_open_error(&e) => {
+                attempt += 1;
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => return Err(e).context("Failed to create PTY"),
+        }
+    }
+}
+
+// A failed write to the PTY, kept distinct from other playback errors so the
+// engine can choose to carry on past it
+#[derive(Debug)]
+pub struct WriteError(std::io::Error);
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to write to PTY")
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
 pub struct PtyManager {
     writer: SharedWriter,
-    parser: SharedParser,
+    // Kept for resizing a spawned session; None when attached or using a sink
+    master: Option<Box<dyn MasterPty + Send>>,
+    // The viewer's keystrokes, when stdin is a terminal being forwarded
+    viewer_input: Option<ViewerInput>,
+    relay: Relay,
     reader_thread: Option<thread::JoinHandle<()>>,
-    _raw_mode_guard: RawModeGuard,
+    // The spawned shell, killed if it outlives a shutdown
+    child: Option<Box<dyn Child + Send + Sync>>,
+    // How the shell exited, once a shutdown has seen it exit on its own
+    exit_status: Option<ExitStatus>,
+    // Enabled when the session is drawn straight onto the host terminal
+    raw_mode_guard: RawModeGuard,
+}
+
+// How long dropping a PtyManager waits for the session's last output
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+// How long to wait for the shell to exit once its input is closed, when its
+// exit matters
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Route for the viewer's keystrokes away from the session, so playback can
+// wait for a key press without the program in the PTY seeing it
+struct ViewerInput {
+    diverted: Arc<AtomicBool>,
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ViewerInput {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            diverted: Arc::new(AtomicBool::new(false)),
+            tx,
+            rx,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TitleFilterState {
+    Text,
+    // After ESC, held back until we know whether it starts a title
+    Escape,
+    // After ESC ] and any digits of the OSC number, also held back
+    OscNumber,
+    // Inside a title, dropped up to its BEL or ESC \ terminator
+    Title,
+    TitleEscape,
+}
+
+// Removes window title changes (OSC 0, 1 and 2) from a byte stream, including
+// ones split across reads. Other escape sequences pass through unchanged.
+struct TitleFilter {
+    state: TitleFilterState,
+    held: Vec<u8>,
+}
+
+impl TitleFilter {
+    fn new() -> Self {
+        Self {
+            state: TitleFilterState::Text,
+            held: Vec::new(),
+        }
+    }
+
+    fn filter(&mut self, input: &[u8]) -> Vec<u8> {
+        use TitleFilterState::*;
+        let mut output = Vec::with_capacity(input.len());
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (Text | Escape | OscNumber, 0x1b) => {
+                    output.append(&mut self.held);
+                    self.held.push(byte);
+                    Escape
+                }
+                (Text, _) => {
+                    output.push(byte);
+                    Text
+                }
+                (Escape, b']') => {
+                    self.held.push(byte);
+                    OscNumber
+                }
+                (OscNumber, b'0'..=b'9') if self.held.len() < 5 => {
+                    self.held.push(byte);
+                    OscNumber
+                }
+                (OscNumber, b';') if matches!(&self.held[2..], b"0" | b"1" | b"2") => {
+                    self.held.clear();
+                    Title
+                }
+                (Escape | OscNumber, _) => {
+                    output.append(&mut self.held);
+                    output.push(byte);
+                    Text
+                }
+                (Title, 0x07) => Text,
+                (Title, 0x1b) => TitleEscape,
+                (Title, _) => Title,
+                (TitleEscape, b'\\') => Text,
+                // An unterminated title cut short by another sequence
+                (TitleEscape, 0x1b) => {
+                    self.held.push(byte);
+                    Escape
+                }
+                (TitleEscape, b']') => {
+                    self.held.extend([0x1b, byte]);
+                    OscNumber
+                }
+                (TitleEscape, _) => {
+                    output.extend([0x1b, byte]);
+                    Text
+                }
+            };
+        }
+        output
+    }
+}
+
+// Why the reader thread stopped relaying output before the session ended
+type ReaderFailure = Arc<OnceLock<String>>;
+
+// Records a panic in the reader thread as it unwinds
+struct PanicGuard(ReaderFailure);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.set("the output relay thread panicked".to_string());
+        }
+    }
+}
+
+// Start of an OSC 133 "prompt start" marker, emitted by shells with semantic
+// prompt integration as each prompt is drawn
+const PROMPT_MARKER: &[u8] = b"\x1b]133;A";
+
+// Counts prompt markers in a byte stream, including ones split across reads
+#[derive(Default)]
+struct PromptCounter {
+    // The end of the previous read, too short to hold a whole marker
+    tail: Vec<u8>,
+}
+
+impl PromptCounter {
+    fn count(&mut self, input: &[u8]) -> u64 {
+        self.tail.extend_from_slice(input);
+        let count = self
+            .tail
+            .windows(PROMPT_MARKER.len())
+            .filter(|window| *window == PROMPT_MARKER)
+            .count();
+        let keep = self.tail.len().min(PROMPT_MARKER.len() - 1);
+        self.tail.drain(..self.tail.len() - keep);
+        count as u64
+    }
+}
+
+// What the reader thread shares with its PtyManager
+#[derive(Clone)]
+struct Relay {
+    parser: SharedParser,
+    // When the reader thread last relayed PTY output
+    last_output: Arc<Mutex<Instant>>,
+    failure: ReaderFailure,
+    // Set to make the reader stop relaying output and end at its next read
+    stop: Arc<AtomicBool>,
+    // Prompt markers seen in the output so far
+    prompts: Arc<AtomicU64>,
+    recording: Option<Recording>,
+    // Shortest time each screenful of output stays on screen
+    min_frame: Option<Duration>,
+}
+
+impl Relay {
+    fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            // Mirror the visible screen at the PTY's dimensions; no scrollback,
+            // as a capture only serialises the visible grid.
+            parser: Arc::new(Mutex::new(vt100::Parser::new(rows, cols, 0))),
+            last_output: Arc::new(Mutex::new(Instant::now())),
+            failure: ReaderFailure::default(),
+            stop: Arc::new(AtomicBool::new(false)),
+            prompts: Arc::new(AtomicU64::new(0)),
+            recording: None,
+            min_frame: None,
+        }
+    }
+}
+
+// Holds each screenful of fast-scrolling output on screen for at least
+// `min_frame`, so it can be read before the next one scrolls it away. Output
+// slower than that passes straight through.
+struct FramePacer {
+    min_frame: Duration,
+    // Lines since the current screenful started, and when it started
+    lines: usize,
+    started: Instant,
+}
+
+impl FramePacer {
+    fn new(min_frame: Duration) -> Self {
+        Self {
+            min_frame,
+            lines: 0,
+            started: Instant::now(),
+        }
+    }
+
+    // Where in `data` the current screenful of `rows` lines is complete, just
+    // past the newline that fills it. None if `data` doesn't fill it.
+    fn screen_end(&mut self, data: &[u8], rows: usize) -> Option<usize> {
+        for (i, _) in data.iter().enumerate().filter(|(_, b)| **b == b'\n') {
+            self.lines += 1;
+            if self.lines >= rows {
+                self.lines = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    // How much longer the screenful just completed should stay on screen. The
+    // next one starts once that is over.
+    fn hold(&mut self, now: Instant) -> Duration {
+        let hold = self
+            .min_frame
+            .saturating_sub(now.duration_since(self.started));
+        self.started = now + hold;
+        hold
+    }
+}
+
+// Relay output from the session to `output` (stdout), mirroring it into the
+// screen parser. If relaying fails, the reason is recorded in the relay's
+// `failure` so playback can stop rather than carry on with nobody seeing the
+// output. Once `stop` is set, whatever the next read returns is dropped and
+// the thread ends. With a minimum frame time, output that scrolls a screenful
+// faster than that is held back a screenful at a time.
+fn spawn_reader(
+    mut reader: Box<dyn Read + Send>,
+    mut output: impl Write + Send + 'static,
+    mut title_filter: Option<TitleFilter>,
+    relay: Relay,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let _guard = PanicGuard(relay.failure.clone());
+        let mut buffer = [0u8; 8192];
+        let mut prompts = PromptCounter::default();
+        let mut pacer = relay.min_frame.map(FramePacer::new);
+
+        'read: loop {
+            let read = reader.read(&mut buffer);
+            if relay.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let n = match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let mut data = &buffer[..n];
+            while !data.is_empty() {
+                let screen_end = pacer.as_mut().and_then(|pacer| {
+                    let rows = relay
+                        .parser
+                        .lock()
+                        .map_or(24, |parser| parser.screen().size().0);
+                    pacer.screen_end(data, usize::from(rows))
+                });
+                let (chunk, rest) = data.split_at(screen_end.unwrap_or(data.len()));
+                data = rest;
+
+                let relayed = match &mut title_filter {
+                    Some(filter) => filter.filter(chunk),
+                    None => chunk.to_vec(),
+                };
+                // stdout is the primary path: never let a locked or
+                // poisoned parser block live output.
+                if let Err(e) = output.write_all(&relayed).and_then(|()| output.flush()) {
+                    let _ = relay
+                        .failure
+                        .set(format!("writing the session's output failed: {e}"));
+                    break 'read;
+                }
+                if let Ok(mut parser) = relay.parser.lock() {
+                    parser.process(chunk);
+                }
+                if let Some(recording) = &relay.recording {
+                    recording.push(SessionEvent::Output(chunk.to_vec()));
+                }
+                relay
+                    .prompts
+                    .fetch_add(prompts.count(chunk), Ordering::SeqCst);
+                if let Ok(mut last) = relay.last_output.lock() {
+                    *last = Instant::now();
+                }
+
+                if screen_end.is_some()
+                    && let Some(pacer) = &mut pacer
+                {
+                    thread::sleep(pacer.hold(Instant::now()));
+                    if relay.stop.load(Ordering::SeqCst) {
+                        break 'read;
+                    }
+                }
+            }
+        }
+    })
+}
+
+// Writes to `output` from a thread of its own, in batches: bytes are held
+// for at most `window` from the first one waiting, or until `max_bytes` are
+// waiting, then written with a single flush. A program making many small
+// writes then costs a write to stdout per batch rather than one per read.
+// Dropping it writes whatever is still held and waits for the thread.
+struct BatchWriter {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BatchWriter {
+    fn new(
+        mut output: impl Write + Send + 'static,
+        window: Duration,
+        max_bytes: usize,
+        failure: ReaderFailure,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::spawn(move || {
+            let _guard = PanicGuard(failure.clone());
+            let mut pending = Vec::new();
+            let mut deadline: Option<Instant> = None;
+            loop {
+                let received = match deadline {
+                    Some(deadline) => {
+                        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                let ended = received == Err(mpsc::RecvTimeoutError::Disconnected);
+                if let Ok(bytes) = received {
+                    pending.extend_from_slice(&bytes);
+                    let due = *deadline.get_or_insert_with(|| Instant::now() + window);
+                    if pending.len() < max_bytes && Instant::now() < due {
+                        continue;
+                    }
+                }
+                if !pending.is_empty() {
+                    if let Err(e) = output.write_all(&pending).and_then(|()| output.flush()) {
+                        let _ = failure.set(format!("writing the session's output failed: {e}"));
+                        return;
+                    }
+                    pending.clear();
+                }
+                deadline = None;
+                if ended {
+                    return;
+                }
+            }
+        });
+        Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Write for BatchWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // The thread only ends early after failing to write
+        match &self.tx {
+            Some(tx) if tx.send(buf.to_vec()).is_ok() => Ok(buf.len()),
+            _ => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BatchWriter {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Programs in a session larger than the host terminal draw past its edges, so
+// output wraps or scrolls out of place
+fn oversize_warning(cols: u16, rows: u16, (host_cols, host_rows): (u16, u16)) -> Option<String> {
+    (cols > host_cols || rows > host_rows).then(|| {
+        format!(
+            "Warning: the session is {cols}x{rows} but your terminal is only \
+             {host_cols}x{host_rows}, so output may not display correctly"
+        )
+    })
 }
 
 impl PtyManager {
-    pub fn new(shell: &str, cols: u16, rows: u16, running: Arc<AtomicBool>) -> Result<Self> {
+    pub fn new(
+        shell: &str,
+        cols: u16,
+        rows: u16,
+        options: &PtyOptions,
+        running: Arc<AtomicBool>,
+    ) -> Result<Self> {
         // Enable raw mode before PTY creation for proper escape sequence handling
         let raw_mode_guard = RawModeGuard::new()?;
+        let manager = Self::spawn(
+            shell,
+            cols,
+            rows,
+            options,
+            Some(running),
+            std::io::stdout(),
+            raw_mode_guard,
+        )?;
+        manager.warn_if_oversized(cols, rows);
+        Ok(manager)
+    }
+
+    // Spawn a shell with no terminal of our own involved: output goes only to
+    // `output` (and the capture screen), raw mode is left alone and stdin is
+    // not forwarded. For running scripts in CI or from tests.
+    pub fn headless(
+        shell: &str,
+        cols: u16,
+        rows: u16,
+        options: &PtyOptions,
+        output: impl Write + Send + 'static,
+    ) -> Result<Self> {
+        Self::spawn(
+            shell,
+            cols,
+            rows,
+            options,
+            None,
+            output,
+            RawModeGuard { enabled: false },
+        )
+    }
 
+    // Forwards stdin when given `running`, stopping it on Ctrl-C
+    fn spawn(
+        shell: &str,
+        cols: u16,
+        rows: u16,
+        options: &PtyOptions,
+        running: Option<Arc<AtomicBool>>,
+        output: impl Write + Send + 'static,
+        raw_mode_guard: RawModeGuard,
+    ) -> Result<Self> {
         let pty_system = native_pty_system();
 
-        let pair = pty_system
-            .openpty(PtySize {
+        let pair = open_pty(
+            pty_system.as_ref(),
+            PtySize {
                 rows,
                 cols,
                 pixel_width: 0,
                 pixel_height: 0,
-            })
-            .context("Failed to create PTY")?;
+            },
+            options,
+        )?;
 
         let mut cmd = CommandBuilder::new(shell);
-        cmd.env("TERM", "xterm-256color");
+        cmd.env("TERM", SESSION_TERM);
+        for (name, value) in &options.env {
+            cmd.env(name, value);
+        }
+        #[cfg(unix)]
+        cmd.umask(options.umask.map(|mask| mask as _));
 
-        let _child = pair
+        let child = pair
             .slave
             .spawn_command(cmd)
             .context("Failed to spawn shell in PTY")?;
@@ -106,6 +871,37 @@ impl PtyManager {
             .context("Failed to get PTY writer")?;
         let writer: SharedWriter = Arc::new(Mutex::new(Some(writer)));
 
+        // Relay output before priming, so a shell echoing a large primed input
+        // can't fill the PTY and block the write
+        let mut relay = Relay::new(cols, rows);
+        relay.recording = options.record.then(|| Recording::new(cols, rows));
+        relay.min_frame = options.min_frame;
+        let title_filter = options.strip_titles.then(TitleFilter::new);
+        let output: Box<dyn Write + Send> = if options.flush_window.is_zero() {
+            Box::new(output)
+        } else {
+            Box::new(BatchWriter::new(
+                output,
+                options.flush_window,
+                options.flush_bytes,
+                relay.failure.clone(),
+            ))
+        };
+        let reader_thread = spawn_reader(reader, output, title_filter, relay.clone());
+
+        // Written in full before the stdin forwarder starts and before this
+        // returns, so primed input always comes first
+        if !options.prime.is_empty() {
+            let mut guard = writer
+                .lock()
+                .map_err(|_| anyhow::anyhow!("PTY writer lock poisoned"))?;
+            if let Some(w) = guard.as_mut() {
+                w.write_all(&options.prime)
+                    .and_then(|()| w.flush())
+                    .context("Failed to write primed input to the PTY")?;
+            }
+        }
+
         // Forward the real terminal's stdin into the PTY master. Without this,
         // terminal query/response protocols break: a program in the PTY sends a
         // query (e.g. ESC[6n for cursor position), the real terminal replies on
@@ -114,81 +910,140 @@ impl PtyManager {
         // queries (e.g. after a `cd`) are answered. Raw mode is already enabled,
         // so stdin bytes arrive verbatim. The thread is detached; it may block in
         // read at shutdown, which is fine since the process exits after playback.
-        let stdin_writer = writer.clone();
-        thread::spawn(move || {
-            let mut stdin = std::io::stdin();
-            let mut buffer = [0u8; 1024];
-
-            loop {
-                match stdin.read(&mut buffer) {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        // Raw mode disables ISIG, so Ctrl-C arrives here as a
-                        // byte instead of raising SIGINT. Stop playback and
-                        // still forward it so the inner program is interrupted.
-                        if buffer[..n].contains(&0x03) {
-                            running.store(false, Ordering::SeqCst);
-                        }
-                        let Ok(mut guard) = stdin_writer.lock() else {
-                            break;
-                        };
-                        let Some(w) = guard.as_mut() else { break };
-                        if w.write_all(&buffer[..n]).is_err() || w.flush().is_err() {
-                            break;
-                        }
-                    }
-                }
-            }
-        });
+        let mut viewer_input = None;
+        if let Some(running) = running {
+            let stdin_writer = writer.clone();
+            viewer_input = std::io::stdin().is_terminal().then(ViewerInput::new);
+            let diverted = viewer_input.as_ref().map(|input| input.diverted.clone());
+            let divert_tx = viewer_input.as_ref().map(|input| input.tx.clone());
+            let mut ctrl_c = CtrlCTracker::new(options.ctrl_c);
+            thread::spawn(move || {
+                let mut stdin = std::io::stdin();
+                let mut buffer = [0u8; 1024];
 
-        // Mirror the visible screen at the PTY's dimensions; no scrollback, as a
-        // capture only serialises the visible grid.
-        let parser: SharedParser = Arc::new(Mutex::new(vt100::Parser::new(rows, cols, 0)));
-        let reader_parser = parser.clone();
-
-        let reader_thread = thread::spawn(move || {
-            let mut reader = reader;
-            let mut stdout = std::io::stdout();
-            let mut buffer = [0u8; 8192];
-
-            loop {
-                match reader.read(&mut buffer) {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        // stdout is the primary path: never let a locked or
-                        // poisoned parser block live output.
-                        if stdout.write_all(&buffer[..n]).is_err() {
-                            break;
-                        }
-                        if stdout.flush().is_err() {
-                            break;
-                        }
-                        if let Ok(mut parser) = reader_parser.lock() {
-                            parser.process(&buffer[..n]);
+                loop {
+                    match stdin.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            // Raw mode disables ISIG, so Ctrl-C arrives here as a
+                            // byte instead of raising SIGINT. Stop playback if the
+                            // policy says so, and pass it on so the inner program
+                            // is interrupted. Input diverted to a
+                            // `@ wait_key` never reaches the shell, so there a
+                            // single Ctrl-C always stops.
+                            let is_diverted = diverted
+                                .as_ref()
+                                .is_some_and(|diverted| diverted.load(Ordering::SeqCst));
+                            let now = Instant::now();
+                            for _ in buffer[..n].iter().filter(|&&b| b == 0x03) {
+                                if ctrl_c.press(now) || is_diverted {
+                                    running.store(false, Ordering::SeqCst);
+                                }
+                            }
+                            if let Some(tx) = &divert_tx
+                                && is_diverted
+                            {
+                                let _ = tx.send(buffer[..n].to_vec());
+                                continue;
+                            }
+                            let Ok(mut guard) = stdin_writer.lock() else {
+                                break;
+                            };
+                            let Some(w) = guard.as_mut() else { break };
+                            if w.write_all(&buffer[..n]).is_err() || w.flush().is_err() {
+                                break;
+                            }
                         }
                     }
                 }
-            }
-        });
+            });
+        }
 
         Ok(Self {
             writer,
-            parser,
+            master: Some(pair.master),
+            viewer_input,
+            relay,
             reader_thread: Some(reader_thread),
-            _raw_mode_guard: raw_mode_guard,
+            child: Some(child),
+            exit_status: None,
+            raw_mode_guard,
         })
     }
 
+    // Drive an existing session instead of spawning a shell. Keystrokes are
+    // written to `path`, which must deliver them to the session as input: on
+    // Unix, typically a PTY master fd inherited from a parent (`/dev/fd/N`) or a
+    // FIFO the target reads. Writing to a terminal's slave device (e.g. a tmux
+    // pane's /dev/pts/N) only prints to its screen. Anything the session's own
+    // user types is interleaved with playback. With `read`, output read back
+    // from `path` is relayed to stdout and mirrored for captures; the reader
+    // is detached as the session may never reach EOF. Raw mode and stdin
+    // forwarding are left off, since the session has its own terminal.
+    pub fn attach(path: &Path, cols: u16, rows: u16, read: bool) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(read)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to attach to {}", path.display()))?;
+
+        let reader = if read {
+            Some(
+                file.try_clone()
+                    .with_context(|| format!("Failed to read from {}", path.display()))?,
+            )
+        } else {
+            None
+        };
+        let manager = Self::with_sink(file, cols, rows);
+        if let Some(reader) = reader {
+            spawn_reader(
+                Box::new(reader),
+                std::io::stdout(),
+                None,
+                manager.relay.clone(),
+            );
+        }
+        Ok(manager)
+    }
+
+    // Send keystrokes to any writer, with no session behind it: nothing is
+    // read back, so captures are blank and output always counts as idle.
+    // Used with a MemorySink to record exactly what playback would type.
+    pub fn with_sink(sink: impl Write + Send + 'static, cols: u16, rows: u16) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Some(Box::new(sink)))),
+            master: None,
+            viewer_input: None,
+            relay: Relay::new(cols, rows),
+            reader_thread: None,
+            child: None,
+            exit_status: None,
+            raw_mode_guard: RawModeGuard { enabled: false },
+        }
+    }
+
     pub fn send_keystroke(&mut self, data: &str) -> Result<()> {
         let mut guard = self
             .writer
             .lock()
             .map_err(|_| anyhow::anyhow!("PTY writer lock poisoned"))?;
         let writer = guard.as_mut().context("PTY writer has been closed")?;
-        writer
-            .write_all(data.as_bytes())
-            .context("Failed to write to PTY")?;
-        writer.flush().context("Failed to flush PTY")?;
+        writer.write_all(data.as_bytes()).map_err(WriteError)?;
+        writer.flush().map_err(WriteError)?;
+        Ok(())
+    }
+
+    // Push anything written but not yet delivered through to the session.
+    // send_keystroke already flushes, so this only has work to do if writes
+    // are ever batched.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut guard = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow::anyhow!("PTY writer lock poisoned"))?;
+        let writer = guard.as_mut().context("PTY writer has been closed")?;
+        writer.flush().map_err(WriteError)?;
         Ok(())
     }
 
@@ -198,12 +1053,201 @@ impl PtyManager {
         self.send_keystroke(s)
     }
 
+    // Change the session's size, which signals the program in it (SIGWINCH)
+    // to redraw. Attached sessions and sinks only resize the capture screen.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        if let Some(master) = &self.master {
+            master
+                .resize(PtySize {
+                    rows,
+                    cols,
+                    pixel_width: 0,
+                    pixel_height: 0,
+                })
+                .context("Failed to resize PTY")?;
+        }
+        self.warn_if_oversized(cols, rows);
+        if let Ok(mut parser) = self.relay.parser.lock() {
+            parser.screen_mut().set_size(rows, cols);
+        }
+        if let Some(recording) = &self.relay.recording {
+            recording.push(SessionEvent::Resize(cols, rows));
+        }
+        Ok(())
+    }
+
+    // Warn on stderr if the session is drawn onto a host terminal smaller than
+    // `cols` x `rows`. A diagnostic only: the session keeps the size asked for.
+    fn warn_if_oversized(&self, cols: u16, rows: u16) {
+        if self.raw_mode_guard.enabled
+            && let Ok(host) = terminal::size()
+            && let Some(warning) = oversize_warning(cols, rows, host)
+        {
+            // Raw mode turns off the newline to CRLF translation
+            eprint!("{warning}\r\n");
+        }
+    }
+
+    // Divert the viewer's keystrokes from the session to viewer_input, or
+    // resume forwarding them. Returns false if there is no viewer to read
+    // from (stdin isn't a terminal, or the session is attached or a sink).
+    pub fn divert_viewer_input(&mut self, divert: bool) -> bool {
+        let Some(input) = &self.viewer_input else {
+            return false;
+        };
+        input.diverted.store(divert, Ordering::SeqCst);
+        if !divert {
+            // Drop anything typed before diverting was switched off
+            while input.rx.try_recv().is_ok() {}
+        }
+        true
+    }
+
+    // Stop diverting the viewer's keystrokes and pass those held back in the
+    // meantime on to the session, instead of dropping them
+    pub fn release_viewer_input(&mut self) -> Result<()> {
+        let Some(input) = &self.viewer_input else {
+            return Ok(());
+        };
+        // Switched back under the writer lock, so keystrokes forwarded
+        // directly from now on queue up behind the held ones
+        let mut guard = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow::anyhow!("PTY writer lock poisoned"))?;
+        input.diverted.store(false, Ordering::SeqCst);
+        let held: Vec<u8> = input.rx.try_iter().flatten().collect();
+        if held.is_empty() {
+            return Ok(());
+        }
+        let writer = guard.as_mut().context("PTY writer has been closed")?;
+        writer.write_all(&held).map_err(WriteError)?;
+        writer.flush().map_err(WriteError)?;
+        Ok(())
+    }
+
+    // Keystrokes received from the viewer while diverted, since last called
+    pub fn viewer_input(&mut self) -> Vec<u8> {
+        self.viewer_input
+            .as_ref()
+            .map(|input| input.rx.try_iter().flatten().collect())
+            .unwrap_or_default()
+    }
+
+    // Why the session's output stopped being shown, if it has
+    pub fn output_failure(&self) -> Option<&str> {
+        self.relay.failure.get().map(String::as_str)
+    }
+
+    // How long the PTY has gone without producing output
+    pub fn output_idle_for(&self) -> Duration {
+        self.relay
+            .last_output
+            .lock()
+            .map_or(Duration::ZERO, |last| last.elapsed())
+    }
+
+    // Make the output relay stop at its next read, without waiting for the
+    // session to end. Output read after this is dropped. The flag can be set
+    // from another thread through reader_stop_flag.
+    pub fn stop_reader(&self) {
+        self.relay.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reader_stop_flag(&self) -> Arc<AtomicBool> {
+        self.relay.stop.clone()
+    }
+
+    // The session's timeline, if it was spawned with PtyOptions::record
+    pub fn recording(&self) -> Option<Recording> {
+        self.relay.recording.clone()
+    }
+
+    // How many OSC 133 prompt markers the session has printed so far. Stays
+    // at zero for shells without semantic prompt integration.
+    pub fn prompts_seen(&self) -> u64 {
+        self.relay.prompts.load(Ordering::SeqCst)
+    }
+
+    // Whether a row of the screen ends with `text`, ignoring trailing
+    // whitespace
+    pub fn screen_has_line_ending(&self, text: &str) -> bool {
+        self.relay.parser.lock().is_ok_and(|parser| {
+            parser
+                .screen()
+                .contents()
+                .lines()
+                .any(|line| line.trim_end().ends_with(text))
+        })
+    }
+
+    // Flush and stop: close the session's input so the shell exits, and wait
+    // up to `timeout` for the output it is still producing to be relayed.
+    // After that the relay is stopped and a shell still running is killed.
+    // Returns true if the relay finished on its own, so no output was lost.
+    // Dropping a PtyManager does this with a short timeout; calling it first
+    // lets an embedder tear a session down on its own schedule.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        // Close the writer to signal EOF. This drops the writer Box regardless of
+        // the detached stdin-forwarding thread's surviving Arc clone.
+        if let Ok(mut guard) = self.writer.lock() {
+            let _ = guard.take();
+        }
+
+        let mut flushed = true;
+        if let Some(handle) = self.reader_thread.take() {
+            let deadline = Instant::now() + timeout;
+            while !handle.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            flushed = handle.is_finished();
+            if flushed {
+                let _ = handle.join();
+            }
+        }
+        self.stop_reader();
+        if let Some(mut child) = self.child.take() {
+            if flushed {
+                self.exit_status = child.wait().ok();
+            } else {
+                // Ending the session wakes the relay from its read
+                let _ = child.kill();
+            }
+        }
+        flushed
+    }
+
+    // Shut the session down and report how the shell exited. Fails if quipu
+    // didn't spawn the shell, or it is still running after `timeout`.
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Result<ExitStatus> {
+        if self.child.is_none() && self.exit_status.is_none() {
+            anyhow::bail!("there is no shell started by quipu to wait for");
+        }
+        self.shutdown(timeout);
+        self.exit_status.clone().with_context(|| {
+            format!(
+                "the shell was still running {}s after its input was closed",
+                timeout.as_secs_f64()
+            )
+        })
+    }
+
+    // Start the capture screen afresh, blank with the cursor at the top left,
+    // so later captures only show output from here on
+    pub fn reset_capture(&self) {
+        if let Ok(mut parser) = self.relay.parser.lock() {
+            let (rows, cols) = parser.screen().size();
+            *parser = vt100::Parser::new(rows, cols, 0);
+        }
+    }
+
     // Serialise the current screen to a file as terminal escape codes. The
     // resulting file, when written to a raw terminal (e.g. `cat`), reproduces
     // the visible state at this point in playback.
     pub fn capture(&self, path: &Path) -> Result<()> {
         let contents = {
             let parser = self
+                .relay
                 .parser
                 .lock()
                 .map_err(|_| anyhow::anyhow!("PTY parser lock poisoned"))?;
@@ -217,16 +1261,9 @@ impl PtyManager {
 
 impl Drop for PtyManager {
     fn drop(&mut self) {
-        // Close the writer to signal EOF. This drops the writer Box regardless of
-        // the detached stdin-forwarding thread's surviving Arc clone.
-        if let Ok(mut guard) = self.writer.lock() {
-            let _ = guard.take();
-        }
-
-        // Wait for reader thread to ensure all output is flushed before raw mode is disabled
-        if let Some(handle) = self.reader_thread.take() {
-            let _ = handle.join();
-        }
+        // Flush output before raw mode is disabled. Does nothing if the
+        // embedder already shut the session down.
+        self.shutdown(SHUTDOWN_TIMEOUT);
 
         // Allow time for parent terminal to respond to any terminal queries
         thread::sleep(Duration::from_millis(100));
@@ -234,6 +1271,413 @@ impl Drop for PtyManager {
         // Note: stdin is owned by the forwarding thread, which relays terminal
         // query responses into the PTY live, so there is no backlog to drain here.
 
-        // _raw_mode_guard drops here, restoring terminal state
+        // raw_mode_guard drops here, restoring terminal state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ctrl_c_tracker() {
+        let start = Instant::now();
+        let mut abort = CtrlCTracker::new(CtrlCPolicy::Abort);
+        assert!(abort.press(start));
+
+        let mut forward = CtrlCTracker::new(CtrlCPolicy::Forward);
+        assert!(!forward.press(start));
+        // Too slow to count as a double press, but starts a new window
+        assert!(!forward.press(start + Duration::from_secs(1)));
+        assert!(forward.press(start + Duration::from_millis(1400)));
+        // Two in the same read arrive at the same instant
+        let mut forward = CtrlCTracker::new(CtrlCPolicy::Forward);
+        assert!(!forward.press(start));
+        assert!(forward.press(start));
+    }
+
+    #[test]
+    fn test_transient_open_errors() {
+        // portable-pty's own formatting of an exhausted fd table
+        let err = anyhow::anyhow!(
+            "failed to openpty: {:?}",
+            std::io::Error::from_raw_os_error(24)
+        );
+        assert!(is_transient_open_error(&err));
+
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+        assert!(is_transient_open_error(&err));
+    }
+
+    #[test]
+    fn test_attach_writes_keystrokes_to_path() {
+        let path = std::env::temp_dir().join(format!("quipu-attach-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        {
+            let mut pty = PtyManager::attach(&path, 80, 24, false).unwrap();
+            pty.send_keystroke("ls\r").unwrap();
+            pty.send_char('\u{e9}').unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ls\r\u{e9}");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_sink_records_keystrokes() {
+        let sink = MemorySink::default();
+        let mut pty = PtyManager::with_sink(sink.clone(), 80, 24);
+        pty.send_keystroke("\x1b[A").unwrap();
+        pty.send_char('x').unwrap();
+        assert_eq!(sink.contents(), b"\x1b[Ax");
+    }
+
+    #[test]
+    fn test_release_viewer_input() {
+        let sink = MemorySink::default();
+        let mut pty = PtyManager::with_sink(sink.clone(), 80, 24);
+        // No viewer to release
+        pty.release_viewer_input().unwrap();
+
+        pty.viewer_input = Some(ViewerInput::new());
+        assert!(pty.divert_viewer_input(true));
+        let tx = pty.viewer_input.as_ref().unwrap().tx.clone();
+        tx.send(b"ls".to_vec()).unwrap();
+        tx.send(vec![0xff]).unwrap();
+        pty.send_keystroke("x").unwrap();
+        pty.release_viewer_input().unwrap();
+        assert_eq!(sink.contents(), b"xls\xff");
+        let diverted = &pty.viewer_input.as_ref().unwrap().diverted;
+        assert!(!diverted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_title_filter() {
+        let mut filter = TitleFilter::new();
+        assert_eq!(
+            filter.filter(b"a\x1b]0;title\x07b\x1b]2;other\x1b\\c"),
+            b"abc"
+        );
+        // Other escape sequences, including other OSCs, pass through
+        let kept = b"\x1b[31mred\x1b]8;;http://x\x1b\\link\x1b]10;?\x07\x1b";
+        assert_eq!(filter.filter(kept), &kept[..kept.len() - 1]);
+        assert_eq!(filter.filter(b"x"), b"\x1bx");
+    }
+
+    #[test]
+    fn test_title_filter_across_reads() {
+        let mut filter = TitleFilter::new();
+        let stream = b"ls\r\n\x1b]1;icon\x07$ \x1b]0;ti\x1b\x1b[Kx";
+        let filtered: Vec<u8> = stream.chunks(1).flat_map(|c| filter.filter(c)).collect();
+        assert_eq!(filtered, b"ls\r\n$ \x1b[Kx");
+    }
+
+    // Output that fails like a closed stdout, or panics
+    struct BrokenOutput {
+        panic: bool,
+    }
+
+    impl Write for BrokenOutput {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            assert!(!self.panic, "simulated relay panic");
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn relay_failure(panic: bool) -> Option<String> {
+        let relay = Relay::new(80, 24);
+        let handle = spawn_reader(
+            Box::new(std::io::Cursor::new(b"prompt$ ".to_vec())),
+            BrokenOutput { panic },
+            None,
+            relay.clone(),
+        );
+        let _ = handle.join();
+        relay.failure.get().cloned()
+    }
+
+    // Keeps each write separately, to see how output was batched
+    #[derive(Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_writer() {
+        let writes = Writes::default();
+        let failure = ReaderFailure::default();
+        let mut writer = BatchWriter::new(
+            writes.clone(),
+            Duration::from_millis(50),
+            8,
+            failure.clone(),
+        );
+        writer.write_all(b"ab").unwrap();
+        writer.write_all(b"cd").unwrap();
+        thread::sleep(Duration::from_millis(150));
+        // Written as soon as enough is waiting
+        writer.write_all(b"0123").unwrap();
+        writer.write_all(b"456789").unwrap();
+        // Written when dropped, without waiting out the window
+        writer.write_all(b"end").unwrap();
+        let start = Instant::now();
+        drop(writer);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(
+            *writes.0.lock().unwrap(),
+            [b"abcd".to_vec(), b"0123456789".to_vec(), b"end".to_vec()]
+        );
+        assert!(failure.get().is_none());
+
+        let mut writer = BatchWriter::new(
+            BrokenOutput { panic: false },
+            Duration::ZERO,
+            1,
+            failure.clone(),
+        );
+        writer.write_all(b"x").unwrap();
+        while writer.write_all(b"y").is_ok() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(
+            failure
+                .get()
+                .unwrap()
+                .contains("writing the session's output failed")
+        );
+    }
+
+    #[test]
+    fn test_reader_failure_is_recorded() {
+        let closed = relay_failure(false).unwrap();
+        assert!(
+            closed.contains("writing the session's output failed"),
+            "{closed}"
+        );
+        assert_eq!(
+            relay_failure(true).unwrap(),
+            "the output relay thread panicked"
+        );
+    }
+
+    #[test]
+    fn test_reader_eof_is_not_a_failure() {
+        let relay = Relay::new(80, 24);
+        let handle = spawn_reader(
+            Box::new(std::io::Cursor::new(b"bye".to_vec())),
+            MemorySink::default(),
+            None,
+            relay.clone(),
+        );
+        handle.join().unwrap();
+        assert!(relay.failure.get().is_none());
+    }
+
+    #[test]
+    fn test_stopped_reader_drops_later_output() {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let sink = MemorySink::default();
+        let relay = Relay::new(80, 24);
+        let handle = spawn_reader(Box::new(reader), sink.clone(), None, relay.clone());
+
+        writer.write_all(b"shown").unwrap();
+        while sink.contents().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        relay.stop.store(true, Ordering::SeqCst);
+        // Wakes the reader, which drops this and ends without waiting for EOF
+        writer.write_all(b"hidden").unwrap();
+        handle.join().unwrap();
+        assert_eq!(sink.contents(), b"shown");
+    }
+
+    #[test]
+    fn test_frame_pacer() {
+        let mut pacer = FramePacer::new(Duration::from_millis(200));
+        let start = pacer.started;
+        assert_eq!(pacer.screen_end(b"1\n2\n", 3), None);
+        assert_eq!(pacer.screen_end(b"3\n4\n5\n6\n7", 3), Some(2));
+        // A screenful shown for only 50ms is held for the rest of the 200ms
+        let done = start + Duration::from_millis(50);
+        assert_eq!(pacer.hold(done), Duration::from_millis(150));
+        assert_eq!(pacer.screen_end(b"4\n5\n6\n7", 3), Some(6));
+        // One that took longer than the minimum to arrive isn't held
+        let done = start + Duration::from_millis(500);
+        assert_eq!(pacer.hold(done), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_reader_holds_fast_screens() {
+        let mut relay = Relay::new(80, 2);
+        relay.min_frame = Some(Duration::from_millis(50));
+        relay.recording = Some(Recording::new(80, 2));
+        let start = Instant::now();
+        let handle = spawn_reader(
+            Box::new(std::io::Cursor::new(b"a\nb\nc\nd\ne\nf\ng".to_vec())),
+            MemorySink::default(),
+            None,
+            relay.clone(),
+        );
+        handle.join().unwrap();
+
+        // Three screenfuls, each held for 50ms before the next is relayed
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let events = relay.recording.unwrap().events();
+        let outputs: Vec<_> = events
+            .iter()
+            .map(|(_, event)| match event {
+                SessionEvent::Output(bytes) => bytes.as_slice(),
+                SessionEvent::Resize(..) => &[],
+            })
+            .collect();
+        assert_eq!(outputs, [&b"a\nb\n"[..], b"c\nd\n", b"e\nf\n", b"g"]);
+        for (i, (at, _)) in events.iter().enumerate() {
+            assert!(*at >= Duration::from_millis(50) * i as u32, "{events:?}");
+        }
+    }
+
+    #[test]
+    fn test_prompt_markers_are_counted_across_reads() {
+        let mut counter = PromptCounter::default();
+        assert_eq!(counter.count(b"\x1b]133;A\x07$ ls\r\n\x1b]133;C\x07"), 1);
+        assert_eq!(counter.count(b"file\r\n\x1b]13"), 0);
+        assert_eq!(counter.count(b"3;A\x07$ \x1b]133;A"), 2);
+        assert_eq!(counter.count(b"\x1b]133;B"), 0);
+    }
+
+    #[test]
+    fn test_reset_capture() {
+        let pty = PtyManager::with_sink(MemorySink::default(), 40, 10);
+        pty.relay.parser.lock().unwrap().process(b"old output\r\n");
+        pty.reset_capture();
+        pty.relay.parser.lock().unwrap().process(b"new");
+
+        let parser = pty.relay.parser.lock().unwrap();
+        assert_eq!(parser.screen().size(), (10, 40));
+        assert_eq!(parser.screen().contents(), "new");
+    }
+
+    #[test]
+    fn test_screen_has_line() {
+        let pty = PtyManager::with_sink(MemorySink::default(), 40, 10);
+        pty.relay
+            .parser
+            .lock()
+            .unwrap()
+            .process(b"echo READ\"\"Y\r\n$ READY  \r\n$ ");
+        assert!(pty.screen_has_line_ending("READY"));
+        assert!(!pty.screen_has_line_ending("echo"));
+    }
+
+    #[test]
+    fn test_trimmed_recording() {
+        let recording = Recording::new(20, 2);
+        let events = vec![
+            // Bracketed paste mode only: nothing to see
+            (
+                Duration::from_secs(1),
+                SessionEvent::Output(b"\x1b[?2004h".to_vec()),
+            ),
+            (Duration::from_secs(5), SessionEvent::Output(b"$ ".to_vec())),
+            (Duration::from_secs(6), SessionEvent::Output(b"ls".to_vec())),
+            (
+                Duration::from_secs(9),
+                SessionEvent::Output(b"\x07".to_vec()),
+            ),
+        ];
+        *recording.events.lock().unwrap() = events;
+
+        let trimmed = recording.trimmed(Duration::from_millis(500));
+        assert_eq!(
+            trimmed.events(),
+            vec![
+                (
+                    Duration::from_millis(500),
+                    SessionEvent::Output(b"$ ".to_vec())
+                ),
+                (
+                    Duration::from_millis(1500),
+                    SessionEvent::Output(b"ls".to_vec())
+                ),
+            ]
+        );
+        assert_eq!(trimmed.size(), (20, 2));
+        assert_eq!(trimmed.output(), b"$ ls");
+        // A lead longer than the quiet start keeps it as it was
+        assert_eq!(
+            recording.trimmed(Duration::MAX).events()[0].0,
+            Duration::from_secs(5)
+        );
+        assert!(
+            Recording::new(20, 2)
+                .trimmed(Duration::ZERO)
+                .events()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_default_shell() {
+        match std::env::var("SHELL") {
+            Ok(shell) if !shell.trim().is_empty() => assert_eq!(default_shell(), shell),
+            _ => assert_eq!(default_shell(), "/bin/sh"),
+        }
+    }
+
+    #[test]
+    fn test_size_or_default() {
+        assert_eq!(size_or_default(Some((120, 40))), (120, 40));
+        assert_eq!(size_or_default(Some((0, 0))), DEFAULT_SIZE);
+        assert_eq!(size_or_default(Some((120, 0))), DEFAULT_SIZE);
+        assert_eq!(size_or_default(None), (80, 24));
+    }
+
+    #[test]
+    fn test_oversize_warning() {
+        assert_eq!(oversize_warning(80, 24, (80, 24)), None);
+        assert_eq!(oversize_warning(80, 24, (200, 60)), None);
+        assert_eq!(
+            oversize_warning(200, 60, (120, 40)).unwrap(),
+            "Warning: the session is 200x60 but your terminal is only 120x40, \
+             so output may not display correctly"
+        );
+        assert!(oversize_warning(81, 24, (80, 50)).is_some());
+        assert!(oversize_warning(80, 25, (132, 24)).is_some());
+    }
+
+    #[test]
+    fn test_shutdown_without_a_session() {
+        let mut pty = PtyManager::with_sink(MemorySink::default(), 80, 24);
+        assert!(pty.shutdown(Duration::ZERO));
+        assert!(pty.reader_stop_flag().load(Ordering::SeqCst));
+        assert!(pty.send_keystroke("x").is_err());
+    }
+
+    #[test]
+    fn test_permanent_open_errors() {
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(!is_transient_open_error(&err));
+
+        let err = anyhow::anyhow!(
+            "failed to openpty: {:?}",
+            std::io::Error::from(std::io::ErrorKind::InvalidInput)
+        );
+        assert!(!is_transient_open_error(&err));
+
+        assert!(!is_transient_open_error(&anyhow::anyhow!(
+            "no os error here"
+        )));
     }
 }
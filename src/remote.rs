@@ -0,0 +1,81 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Scripts fetched over HTTP(S)
+//!
+//! `quipu --allow-remote URL` runs a script from a URL. The text is parsed
+//! like any other script, but with `CustomDirectives::set_remote` on, so it
+//! can't act on this machine beyond typing into the session.
+
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+// Larger responses are refused rather than read into memory
+const MAX_SCRIPT_BYTES: u64 = 1024 * 1024;
+// Covers connecting and reading the whole response
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// The text of the script at `url`. Fails on anything but a successful
+// response.
+pub fn fetch(url: &str) -> Result<String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    let mut response = agent
+        .get(url)
+        .call()
+        .with_context(|| format!("Failed to fetch script from {url}"))?;
+    response
+        .body_mut()
+        .with_config()
+        .limit(MAX_SCRIPT_BYTES)
+        .read_to_string()
+        .with_context(|| format!("Failed to read script from {url}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // Answer one request on a local port with `status` and `body`
+    fn serve(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        });
+        format!("http://{addr}/demo.qp")
+    }
+
+    #[test]
+    fn test_fetch() {
+        let url = serve("200 OK", "$ ls<ret>\n");
+        assert_eq!(fetch(&url).unwrap(), "$ ls<ret>\n");
+
+        let url = serve("404 Not Found", "");
+        let err = fetch(&url).unwrap_err();
+        assert!(format!("{err:#}").contains("404"), "{err:#}");
+    }
+}
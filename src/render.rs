@@ -0,0 +1,290 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Rendering recorded sessions to animated GIFs
+//!
+//! The session's output is replayed through a screen model and sampled at a
+//! fixed frame rate. Each frame is drawn with an 8x8 bitmap font stretched to
+//! 8x16 cells, in the 16 standard terminal colours (other colours are mapped
+//! to the nearest of them). Consecutive identical frames are merged into one
+//! longer frame, so idle stretches cost next to nothing.
+
+use anyhow::{Context, Result};
+use font8x8::UnicodeFonts;
+use gif::{Encoder, Frame, Repeat};
+use std::io::Write;
+use std::time::Duration;
+
+use crate::pty::{Recording, SessionEvent};
+
+const CELL_WIDTH: usize = 8;
+const CELL_HEIGHT: usize = 16;
+// How long the last frame shows before the GIF loops, unless told otherwise
+pub const DEFAULT_HOLD: Duration = Duration::from_secs(2);
+
+// xterm's default colours, indexed as in SGR
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xcd, 0x00, 0x00],
+    [0x00, 0xcd, 0x00],
+    [0xcd, 0xcd, 0x00],
+    [0x00, 0x00, 0xee],
+    [0xcd, 0x00, 0xcd],
+    [0x00, 0xcd, 0xcd],
+    [0xe5, 0xe5, 0xe5],
+    [0x7f, 0x7f, 0x7f],
+    [0xff, 0x00, 0x00],
+    [0x00, 0xff, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x5c, 0x5c, 0xff],
+    [0xff, 0x00, 0xff],
+    [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+// Render `recording` as a looping GIF at `fps` frames per second, showing
+// the last frame for `hold`. The GIF is the size the session started at;
+// after a resize, cells outside it are cut off and any it doesn't cover are
+// left blank.
+pub fn render_gif(recording: &Recording, fps: u32, hold: Duration, out: impl Write) -> Result<()> {
+    render(&recording.events(), recording.size(), fps, hold, out)
+}
+
+fn render(
+    events: &[(Duration, SessionEvent)],
+    (cols, rows): (u16, u16),
+    fps: u32,
+    hold: Duration,
+    out: impl Write,
+) -> Result<()> {
+    anyhow::ensure!(fps > 0, "the frame rate must be above 0");
+    let too_big = || anyhow::anyhow!("a {cols}x{rows} session is too big for a GIF");
+    let width = u16::try_from(usize::from(cols) * CELL_WIDTH).map_err(|_| too_big())?;
+    let height = u16::try_from(usize::from(rows) * CELL_HEIGHT).map_err(|_| too_big())?;
+
+    let mut encoder = Encoder::new(out, width, height, PALETTE.as_flattened())
+        .context("Failed to start the GIF")?;
+    encoder.set_repeat(Repeat::Infinite)?;
+
+    let mut parser = vt100::Parser::new(rows, cols, 0);
+    let step = Duration::from_secs(1) / fps;
+    let end = events.last().map_or(Duration::ZERO, |(at, _)| *at);
+    let mut events = events.iter().peekable();
+    // The frame being shown and when it started
+    let mut shown: Option<(Vec<u8>, Duration)> = None;
+    let mut at = Duration::ZERO;
+    loop {
+        while let Some((_, event)) = events.next_if(|(event_at, _)| *event_at <= at) {
+            match event {
+                SessionEvent::Output(bytes) => parser.process(bytes),
+                SessionEvent::Resize(cols, rows) => parser.screen_mut().set_size(*rows, *cols),
+            }
+        }
+        let pixels = rasterize(parser.screen(), cols, rows);
+        match shown.take() {
+            Some((previous, since)) if previous == pixels => shown = Some((previous, since)),
+            previous => {
+                if let Some((previous, since)) = previous {
+                    write_frame(
+                        &mut encoder,
+                        width,
+                        height,
+                        previous,
+                        centis(at) - centis(since),
+                    )?;
+                }
+                shown = Some((pixels, at));
+            }
+        }
+        if at >= end {
+            break;
+        }
+        at += step;
+    }
+    if let Some((pixels, since)) = shown {
+        let delay = centis(at + hold) - centis(since);
+        write_frame(&mut encoder, width, height, pixels, delay)?;
+    }
+    encoder.into_inner().context("Failed to finish the GIF")?;
+    Ok(())
+}
+
+// GIF frame delays are in hundredths of a second. Rounding each frame's start
+// rather than its length keeps rounding errors from adding up.
+fn centis(at: Duration) -> u64 {
+    (at.as_millis() as u64 + 5) / 10
+}
+
+fn write_frame(
+    encoder: &mut Encoder<impl Write>,
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+    delay: u64,
+) -> Result<()> {
+    let mut frame = Frame::from_indexed_pixels(width, height, pixels, None);
+    frame.delay = u16::try_from(delay).unwrap_or(u16::MAX);
+    encoder
+        .write_frame(&frame)
+        .context("Failed to write a GIF frame")
+}
+
+// Draw the screen's `cols` x `rows` cells as palette indices, with the
+// cursor shown as an inverted cell
+fn rasterize(screen: &vt100::Screen, cols: u16, rows: u16) -> Vec<u8> {
+    let width = usize::from(cols) * CELL_WIDTH;
+    let mut pixels = vec![DEFAULT_BG; width * usize::from(rows) * CELL_HEIGHT];
+    let cursor = (!screen.hide_cursor()).then(|| screen.cursor_position());
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            let mut fg = colour(cell.fgcolor(), DEFAULT_FG);
+            let mut bg = colour(cell.bgcolor(), DEFAULT_BG);
+            if cell.bold() && fg < 8 {
+                fg += 8;
+            }
+            if cell.inverse() != (cursor == Some((row, col))) {
+                std::mem::swap(&mut fg, &mut bg);
+            }
+            let glyph = glyph(cell.contents());
+            for y in 0..CELL_HEIGHT {
+                let bits = glyph[y / 2];
+                let underline = cell.underline() && y == CELL_HEIGHT - 1;
+                let start =
+                    (usize::from(row) * CELL_HEIGHT + y) * width + usize::from(col) * CELL_WIDTH;
+                for (x, pixel) in pixels[start..start + CELL_WIDTH].iter_mut().enumerate() {
+                    *pixel = if underline || bits & (1 << x) != 0 {
+                        fg
+                    } else {
+                        bg
+                    };
+                }
+            }
+        }
+    }
+    pixels
+}
+
+// Rows of the cell's first character, least significant bit leftmost, or a
+// question mark for characters the font doesn't have
+fn glyph(contents: &str) -> [u8; 8] {
+    let Some(c) = contents.chars().next() else {
+        return [0; 8];
+    };
+    font8x8::BASIC_FONTS
+        .get(c)
+        .or_else(|| font8x8::LATIN_FONTS.get(c))
+        .or_else(|| font8x8::BOX_FONTS.get(c))
+        .or_else(|| font8x8::BLOCK_FONTS.get(c))
+        .or_else(|| font8x8::BASIC_FONTS.get('?'))
+        .unwrap_or_default()
+}
+
+fn colour(colour: vt100::Color, default: u8) -> u8 {
+    match colour {
+        vt100::Color::Default => default,
+        vt100::Color::Idx(index) if index < 16 => index,
+        vt100::Color::Idx(index) => nearest(xterm_rgb(index)),
+        vt100::Color::Rgb(r, g, b) => nearest([r, g, b]),
+    }
+}
+
+// The colour of an entry in xterm's 6x6x6 cube (16-231) or grey ramp
+// (232-255)
+fn xterm_rgb(index: u8) -> [u8; 3] {
+    if index >= 232 {
+        let level = 8 + (index - 232) * 10;
+        return [level; 3];
+    }
+    let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+    let index = index - 16;
+    [level(index / 36), level(index / 6 % 6), level(index % 6)]
+}
+
+fn nearest(rgb: [u8; 3]) -> u8 {
+    let distance = |entry: &[u8; 3]| {
+        entry
+            .iter()
+            .zip(rgb)
+            .map(|(a, b)| (i32::from(*a) - i32::from(b)).pow(2))
+            .sum::<i32>()
+    };
+    (0..PALETTE.len())
+        .min_by_key(|&index| distance(&PALETTE[index]))
+        .unwrap_or_default() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(gif: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn test_render_gif() {
+        let events = [
+            (
+                Duration::ZERO,
+                SessionEvent::Output(b"\x1b[?25lhi".to_vec()),
+            ),
+            (
+                Duration::from_millis(250),
+                SessionEvent::Output(b" \x1b[31mred".to_vec()),
+            ),
+        ];
+        let mut gif = Vec::new();
+        render(&events, (8, 2), 10, DEFAULT_HOLD, &mut gif).unwrap();
+        let frames = decode(&gif);
+
+        // The frames at 0, 0.1s and 0.2s are the same, so merge into one
+        // shown until the second is sampled at 0.3s
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, 30);
+        assert_eq!(frames[1].0, 200);
+        assert_eq!(frames[0].1.len(), 8 * CELL_WIDTH * 2 * CELL_HEIGHT);
+        assert!(frames[0].1.contains(&DEFAULT_FG));
+        assert!(!frames[0].1.contains(&1));
+        assert!(frames[1].1.contains(&1));
+    }
+
+    #[test]
+    fn test_colour_mapping() {
+        assert_eq!(colour(vt100::Color::Default, DEFAULT_FG), DEFAULT_FG);
+        assert_eq!(colour(vt100::Color::Idx(12), DEFAULT_FG), 12);
+        assert_eq!(colour(vt100::Color::Idx(196), DEFAULT_FG), 9);
+        assert_eq!(colour(vt100::Color::Idx(232), DEFAULT_FG), 0);
+        assert_eq!(colour(vt100::Color::Rgb(250, 250, 250), DEFAULT_FG), 15);
+    }
+
+    #[test]
+    fn test_oversized_session() {
+        let err = render(&[], (10000, 24), 10, DEFAULT_HOLD, Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("too big"), "{err}");
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Special keys looked up in the terminfo database
+//!
+//! With `@ terminfo` on, named keys in `$` lines send the sequences a
+//! terminal type's terminfo entry gives for them rather than quipu's built-in
+//! xterm ones. Keys the entry doesn't list keep their built-in sequences.
+
+use terminfo::Database;
+use terminfo::capability::Value;
+
+use crate::keymap::Keymap;
+
+// quipu's key names and the terminfo capabilities they are read from
+const CAPABILITIES: [(&[&str], &str); 23] = [
+    (&["backspace", "bs"], "key_backspace"),
+    (&["F1"], "key_f1"),
+    (&["F2"], "key_f2"),
+    (&["F3"], "key_f3"),
+    (&["F4"], "key_f4"),
+    (&["F5"], "key_f5"),
+    (&["F6"], "key_f6"),
+    (&["F7"], "key_f7"),
+    (&["F8"], "key_f8"),
+    (&["F9"], "key_f9"),
+    (&["F10"], "key_f10"),
+    (&["F11"], "key_f11"),
+    (&["F12"], "key_f12"),
+    (&["up"], "key_up"),
+    (&["down"], "key_down"),
+    (&["right"], "key_right"),
+    (&["left"], "key_left"),
+    (&["home"], "key_home"),
+    (&["end"], "key_end"),
+    (&["pageup", "pgup"], "key_ppage"),
+    (&["pagedown", "pgdn"], "key_npage"),
+    (&["insert", "ins"], "key_ic"),
+    (&["delete", "del"], "key_dc"),
+];
+
+// The keys `term`'s terminfo entry defines
+pub fn keymap(term: &str) -> Result<Keymap, String> {
+    let db =
+        Database::from_name(term).map_err(|e| format!("no terminfo entry for '{term}': {e}"))?;
+    Ok(keys(&db))
+}
+
+fn keys(db: &Database) -> Keymap {
+    let mut keymap = Keymap::default();
+    for (names, capability) in CAPABILITIES {
+        let Some(Value::String(seq)) = db.raw(capability) else {
+            continue;
+        };
+        let Ok(seq) = std::str::from_utf8(seq) else {
+            continue;
+        };
+        if seq.is_empty() {
+            continue;
+        }
+        for name in names {
+            keymap.insert(name, seq);
+        }
+    }
+    keymap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminfo_keys() {
+        let mut db = Database::new();
+        db.name("test")
+            .raw("key_up", "\x1bOA")
+            .raw("key_dc", "\x1b[3~")
+            .raw("key_f5", "");
+        let found = keys(&db.build().unwrap());
+
+        assert_eq!(found.get("up"), Some("\x1bOA"));
+        assert_eq!(found.get("delete"), Some("\x1b[3~"));
+        assert_eq!(found.get("del"), Some("\x1b[3~"));
+        // Missing or empty entries are left to the built-in keys
+        assert_eq!(found.get("down"), None);
+        assert_eq!(found.get("F5"), None);
+
+        let err = keymap("no-such-terminal").unwrap_err();
+        assert!(err.contains("no-such-terminal"), "{err}");
+    }
+}
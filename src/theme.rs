@@ -0,0 +1,139 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Colour themes for quipu recordings
+//!
+//! A theme file is TOML with a `[palette]` table mapping colour indexes
+//! (0-255) or the names of the 16 standard colours to `#rrggbb` values:
+//!
+//! ```toml
+//! [palette]
+//! black = "#1d1f21"
+//! bright_red = "#d54e53"
+//! 208 = "#de935f"
+//! ```
+//!
+//! Applying a theme sets those palette entries on the host terminal with
+//! OSC 4 sequences.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+// Resets every palette entry to the terminal's default (OSC 104)
+pub const RESET_PALETTE: &str = "\x1b]104\x07";
+
+const COLOUR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    // Palette index and RGB value, in index order
+    pub colours: Vec<(u8, [u8; 3])>,
+}
+
+fn palette_index(key: &str) -> Option<u8> {
+    if let Ok(index) = key.parse::<u8>() {
+        return Some(index);
+    }
+    let (base, offset) = match key.strip_prefix("bright_") {
+        Some(base) => (base, 8),
+        None => (key, 0),
+    };
+    let position = COLOUR_NAMES.iter().position(|name| *name == base)?;
+    u8::try_from(position + offset).ok()
+}
+
+fn parse_hex_colour(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl Theme {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let table: toml::Table = source.parse().map_err(|e| format!("{e}"))?;
+        let palette = match table.get("palette") {
+            Some(toml::Value::Table(palette)) => palette,
+            Some(_) => return Err("'palette' must be a table".to_string()),
+            None => return Err("missing [palette] table".to_string()),
+        };
+
+        let mut colours = Vec::new();
+        for (key, value) in palette {
+            let index =
+                palette_index(key).ok_or_else(|| format!("unknown palette entry '{key}'"))?;
+            let rgb = value
+                .as_str()
+                .and_then(parse_hex_colour)
+                .ok_or_else(|| format!("colour for '{key}' must be a \"#rrggbb\" string"))?;
+            colours.push((index, rgb));
+        }
+        colours.sort_by_key(|&(index, _)| index);
+        Ok(Self { colours })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read theme {}", path.display()))?;
+        Self::parse(&source).map_err(|e| anyhow::anyhow!("Invalid theme {}: {e}", path.display()))
+    }
+
+    // OSC 4 sequences setting each palette entry
+    pub fn osc_sequences(&self) -> String {
+        self.colours
+            .iter()
+            .map(|(index, [r, g, b])| format!("\x1b]4;{index};rgb:{r:02x}/{g:02x}/{b:02x}\x07"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_theme() {
+        let theme = Theme::parse(
+            "[palette]\nbright_red = \"#D54E53\"\nblack = \"#1d1f21\"\n208 = \"#de935f\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            theme.colours,
+            vec![
+                (0, [0x1d, 0x1f, 0x21]),
+                (9, [0xd5, 0x4e, 0x53]),
+                (208, [0xde, 0x93, 0x5f])
+            ]
+        );
+        assert_eq!(
+            theme.osc_sequences(),
+            "\x1b]4;0;rgb:1d/1f/21\x07\x1b]4;9;rgb:d5/4e/53\x07\x1b]4;208;rgb:de/93/5f\x07"
+        );
+    }
+
+    #[test]
+    fn test_invalid_themes() {
+        assert!(Theme::parse("").is_err());
+        assert!(Theme::parse("palette = 1").is_err());
+        assert!(Theme::parse("[palette]\npurple = \"#000000\"").is_err());
+        assert!(Theme::parse("[palette]\n256 = \"#000000\"").is_err());
+        assert!(Theme::parse("[palette]\nred = \"#12345\"").is_err());
+        assert!(Theme::parse("[palette]\nred = 7").is_err());
+    }
+}
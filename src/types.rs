@@ -20,36 +20,1349 @@ use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
-    SetSpeed(f64),
-    SetJitter(f64),
+    SetSpeed(ValueRange),
+    SetJitter(ValueRange),
+    // Draw keystroke delays from a normal distribution instead of jitter, or
+    // go back to jitter when None
+    SetJitterParams(Option<JitterParams>),
+    // Keep in step with the schedule or the shell's prompts
+    SetSync(SyncMode),
+    // Whether an empty `$` line presses Enter rather than doing nothing
+    SetAutoEnter(bool),
+    // Fraction of words mistyped, then erased and typed again
+    SetWordTypos(f64),
+    // Characters typos may involve
+    SetTypoChars(TypoChars),
+    // What the Backspace key sends, for `<bs>` and playback's own erasing
+    SetBackspace(BackspaceKey),
+    // Extra pause after typing a space, on top of the keystroke delay
+    SetWordPause(Duration),
+    // Type `$` lines faster the longer they are, or not when None
+    SetLongLines(Option<LongLines>),
+    // Save the playback settings, for the matching PopConfig to restore
+    PushConfig,
+    // Go back to the settings saved by the last PushConfig
+    PopConfig,
     Wait(Duration),
-    // Must come before any Type commands
+    // Wait until the local clock reads this time, or with `true` tomorrow's
+    // once today's has passed
+    WaitUntil(chrono::NaiveTime, bool),
+    // Setup: applied before PTY creation, so must come before any command
+    // that sends input (see Script::partition)
     SetShell(String),
-    // Must come before PTY creation
     SetSize(u16, u16),
+    SetEnv(String, String),
+    // Seed for jitter and shuffling, so a shared script plays the same way
+    SetSeed(u64),
+    // Wait for the shell to echo a sentinel before playback starts
+    SetReadySentinel(bool),
+    // Run a no-op command and let the shell settle before playback starts
+    SetPrewarm(bool),
+    // File mode creation mask the shell starts with (Unix only)
+    SetUmask(u32),
     Type(String),
+    // Type the value of an environment variable, read at playback time so the
+    // secret never appears in the parsed script or anything rendered from it
+    TypeSecret(String),
+    // Backspace over the current line, then type the replacement
+    Retype(String),
+    // Move back to a column of the current line (counting from 1), type the
+    // text over what is there, then move back to the end of the line
+    Overwrite(usize, String),
+    // Flush keystrokes written to the session so far
+    Flush,
+    // Save the settings and the next command to the checkpoint file, if
+    // there is one, for --resume. Only at the top level.
+    Checkpoint,
     // Snapshot the current screen (escape codes) to a file
     Capture(PathBuf),
+    // Blank the mirrored screen captures are taken from
+    ResetCapture,
+    // Once playback ends, fail unless the shell exits with this code
+    ExpectExit(u32),
+    // Once playback ends, run this command on the host (not in the session)
+    // and fail unless it succeeds
+    Verify(String),
+    // Run the enclosed commands in a random order, drawn from the engine's
+    // seedable RNG
+    Shuffle(Vec<Command>),
+    // Run the enclosed commands as entry into a field that doesn't echo, e.g.
+    // a password prompt: what they type isn't counted as being on the line
+    NoEcho(Vec<Command>),
+    // Send the lines (resolved bytes) at once as a bracketed paste, separated
+    // by newlines as a paste delivers them rather than the Enter key's
+    // carriage return
+    Paste(Vec<String>),
+    // A section tagged with any of the names, for picking out with
+    // Script::select_tags
+    Tagged(Vec<String>, Vec<Command>),
+    // Run the enclosed commands with the viewer's own keystrokes held back
+    // while they type, and passed to the session in between
+    Live(Vec<Command>),
+    // Key (resolved bytes) that accepts a shell autosuggestion
+    SetSuggestionKey(String),
+    // Wait for the suggestion to render, then accept it
+    AcceptSuggestion,
+    // Print a caption to the host terminal, bypassing the PTY. May contain
+    // {colour} markup.
+    Echo(String),
+    // Like Echo, but animated a character at a time at typing speed
+    Reveal(String),
+    // Timed caption for the subtitle track; no effect on live output
+    Subtitle(String),
+    // Set the host terminal's window title, bypassing the PTY
+    SetTitle(String),
+    // Set the host terminal's palette from a theme file, bypassing the PTY
+    Theme(PathBuf),
+    // Restore the host terminal's default palette
+    ResetTheme,
+    // Resize the session mid-script (cols, rows), then pause to let the
+    // program redraw
+    Resize(u16, u16, Duration),
+    // Pause until the viewer presses a key (resolved bytes), or any key
+    WaitKey(Option<String>),
+    // Send a query (resolved bytes) to the host terminal and wait up to the
+    // timeout for it to answer
+    WaitResponse(String, Duration),
+}
+
+// A setting that is either fixed or picked at random from `min..=max` for
+// each command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ValueRange {
+    pub fn is_fixed(&self) -> bool {
+        self.min == self.max
+    }
+
+    // The average of values picked from the range
+    pub fn mid(&self) -> f64 {
+        f64::midpoint(self.min, self.max)
+    }
+}
+
+impl From<f64> for ValueRange {
+    fn from(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+        }
+    }
+}
+
+impl std::fmt::Display for ValueRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_fixed() {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}..{}", self.min, self.max)
+        }
+    }
+}
+
+// The byte the Backspace key sends. Most terminals send DEL; some, and some
+// programs, expect ^H.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackspaceKey {
+    #[default]
+    Del,
+    Bs,
+}
+
+impl BackspaceKey {
+    pub fn bytes(self) -> &'static str {
+        match self {
+            BackspaceKey::Del => "\x7f",
+            BackspaceKey::Bs => "\x08",
+        }
+    }
+
+    // As written in `@ backspace`
+    pub fn name(self) -> &'static str {
+        match self {
+            BackspaceKey::Del => "del",
+            BackspaceKey::Bs => "bs",
+        }
+    }
+}
+
+// What playback keeps in step with, beyond its own pauses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    #[default]
+    Off,
+    // Shorten pauses to catch up when playback falls behind schedule
+    Schedule,
+    // After a command presses Enter, wait for the shell to draw its next
+    // prompt, as signalled by an OSC 133 marker
+    Prompt,
 }
 
 #[derive(Debug, Clone)]
 pub struct PlaybackConfig {
     // Base time between keystrokes in seconds
-    pub speed: f64,
+    pub speed: ValueRange,
     // Jitter as a fraction (0.0 to 1.0) of speed
-    pub jitter: f64,
+    pub jitter: ValueRange,
+    // Normal distribution used for keystroke delays in place of jitter
+    pub jitter_params: Option<JitterParams>,
+    // Sent by AcceptSuggestion
+    pub suggestion_key: String,
+    pub sync: SyncMode,
+    // Press Enter for an empty `$` line
+    pub auto_enter: bool,
+    // Chance (0.0 to 1.0) of mistyping each word
+    pub word_typos: f64,
+    // What a mistyped word may be made of
+    pub typo_chars: TypoChars,
+    // Sent to erase typos and by Retype
+    pub backspace: BackspaceKey,
+    // Added to the delay after each space typed
+    pub word_pause: Duration,
+    // Speedup for long `$` lines
+    pub long_lines: Option<LongLines>,
+}
+
+impl PlaybackConfig {
+    // Setting directives that take a default config to this one
+    pub fn to_commands(&self) -> Vec<Command> {
+        vec![
+            Command::SetSpeed(self.speed),
+            Command::SetJitter(self.jitter),
+            Command::SetJitterParams(self.jitter_params),
+            Command::SetSync(self.sync),
+            Command::SetAutoEnter(self.auto_enter),
+            Command::SetWordTypos(self.word_typos),
+            Command::SetTypoChars(self.typo_chars.clone()),
+            Command::SetBackspace(self.backspace),
+            Command::SetWordPause(self.word_pause),
+            Command::SetLongLines(self.long_lines),
+            Command::SetSuggestionKey(self.suggestion_key.clone()),
+        ]
+    }
 }
 
 impl Default for PlaybackConfig {
     fn default() -> Self {
         Self {
-            speed: 0.1,  // 100ms per keystroke
-            jitter: 0.0, // No jitter
+            speed: 0.1.into(),  // 100ms per keystroke
+            jitter: 0.0.into(), // No jitter
+            jitter_params: None,
+            suggestion_key: "\x1b[C".to_string(), // Right arrow
+            sync: SyncMode::Off,
+            auto_enter: false,
+            word_typos: 0.0,
+            typo_chars: TypoChars::default(),
+            backspace: BackspaceKey::Del,
+            word_pause: Duration::ZERO,
+            long_lines: None,
+        }
+    }
+}
+
+// Characters @ word_typos may get wrong, as inclusive ranges. A typo only
+// ever happens on a run of them, so quotes, pipes and the like are always
+// typed as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypoChars(pub Vec<(char, char)>);
+
+impl Default for TypoChars {
+    fn default() -> Self {
+        Self(vec![('a', 'z'), ('A', 'Z')])
+    }
+}
+
+impl TypoChars {
+    pub fn contains(&self, c: char) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| (first..=last).contains(&c))
+    }
+}
+
+// As written in `@ typo_chars`, with a lone `-` last so it isn't read as a
+// range
+impl std::fmt::Display for TypoChars {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for &(first, last) in &self.0 {
+            match (first, last) {
+                ('-', '-') => {}
+                (first, last) if first == last => write!(f, "{first}")?,
+                (first, last) => write!(f, "{first}-{last}")?,
+            }
+        }
+        if self.0.contains(&('-', '-')) {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+// Normal distribution of keystroke delays, in multiples of the speed: a
+// delay is speed * (mean + stddev * z) for a standard normal z, never below 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JitterParams {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+// Speedup for `$` lines longer than `threshold` characters: their keystroke
+// delay is scaled by threshold / length, so they take about as long to type
+// as a line of `threshold` characters, but are never more than `max_speedup`
+// times faster
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LongLines {
+    pub threshold: usize,
+    pub max_speedup: f64,
+}
+
+impl LongLines {
+    pub const DEFAULT_MAX_SPEEDUP: f64 = 4.0;
+
+    // Factor for the keystroke delay of a line of `chars` characters
+    pub fn delay_scale(&self, chars: usize) -> f64 {
+        if chars <= self.threshold {
+            1.0
+        } else {
+            (self.threshold as f64 / chars as f64).max(1.0 / self.max_speedup)
         }
     }
 }
 
+// Keys sent periodically during long waits so idle SSH or tmux sessions
+// don't time out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    // Resolved bytes; NUL by default, which shells ignore or treat as set-mark
+    pub keys: String,
+}
+
+// How playback finishes once the last command has run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Ending {
+    // Run the script exactly as written
+    #[default]
+    Keep,
+    // Drop a trailing Enter from the last typed line, so the recording ends on
+    // the typed command rather than a fresh prompt
+    NoFinalEnter,
+    // Hold briefly, then clear the screen (Ctrl-L) to end on a clean prompt
+    Clear,
+}
+
+// What to do when a keystroke can't be written to the PTY
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WriteErrorPolicy {
+    // Stop playback with the error
+    #[default]
+    Abort,
+    // Report the error, skip the rest of the failing command and carry on
+    Continue,
+}
+
+// What a Ctrl-C typed by the viewer during playback does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CtrlCPolicy {
+    // Stop playback at the first one, still passing it to the shell
+    #[default]
+    Abort,
+    // Pass it to the shell only; a second one in quick succession stops
+    // playback
+    Forward,
+}
+
+// How control characters are written in the keystroke log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyNotation {
+    // Script syntax, e.g. `<ret>` and `<up>`
+    #[default]
+    Names,
+    // Caret notation, as `cat -v` shows them, e.g. `^M` and `^[[A`
+    Caret,
+}
+
+// What to do when typed text resolves to a byte outside the allowed bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ByteCheck {
+    // Refuse to parse the script
+    #[default]
+    Error,
+    // Print a warning and play it anyway
+    Warn,
+}
+
+// Whether window title changes (OSC 0/1/2) from the session reach the host
+// terminal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TitlePolicy {
+    // Keep them when stdout is a terminal, strip them when it isn't
+    #[default]
+    Auto,
+    Keep,
+    Strip,
+}
+
 #[derive(Debug)]
 pub struct Script {
     pub commands: Vec<Command>,
 }
+
+// Session configuration applied before the PTY is created
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Setup {
+    pub shell: Option<String>,
+    pub size: Option<(u16, u16)>,
+    pub env: Vec<(String, String)>,
+    pub seed: Option<u64>,
+    pub ready_sentinel: Option<bool>,
+    pub prewarm: Option<bool>,
+    pub umask: Option<u32>,
+}
+
+impl Setup {
+    // Combine the setup of scripts run one after another in a single session.
+    // Whichever sets the shell, size or seed first wins, and any later script
+    // that sets them must agree; environment variables accumulate.
+    pub fn merge(&mut self, other: Setup) -> Result<(), String> {
+        match (&self.shell, other.shell) {
+            (Some(ours), Some(theirs)) if *ours != theirs => {
+                return Err(format!(
+                    "@shell:{theirs} conflicts with the earlier @shell:{ours}"
+                ));
+            }
+            (None, theirs) => self.shell = theirs,
+            _ => {}
+        }
+        match (self.size, other.size) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                return Err(format!(
+                    "@size:{}:{} conflicts with the earlier @size:{}:{}",
+                    theirs.0, theirs.1, ours.0, ours.1
+                ));
+            }
+            (None, theirs) => self.size = theirs,
+            _ => {}
+        }
+        match (self.seed, other.seed) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                return Err(format!(
+                    "@seed:{theirs} conflicts with the earlier @seed:{ours}"
+                ));
+            }
+            (None, theirs) => self.seed = theirs,
+            _ => {}
+        }
+        match (self.ready_sentinel, other.ready_sentinel) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                let (theirs, ours) = if theirs { ("on", "off") } else { ("off", "on") };
+                return Err(format!(
+                    "@ready_sentinel:{theirs} conflicts with the earlier @ready_sentinel:{ours}"
+                ));
+            }
+            (None, theirs) => self.ready_sentinel = theirs,
+            _ => {}
+        }
+        match (self.prewarm, other.prewarm) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                let (theirs, ours) = if theirs { ("on", "off") } else { ("off", "on") };
+                return Err(format!(
+                    "@prewarm:{theirs} conflicts with the earlier @prewarm:{ours}"
+                ));
+            }
+            (None, theirs) => self.prewarm = theirs,
+            _ => {}
+        }
+        match (self.umask, other.umask) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                return Err(format!(
+                    "@umask:{theirs:03o} conflicts with the earlier @umask:{ours:03o}"
+                ));
+            }
+            (None, theirs) => self.umask = theirs,
+            _ => {}
+        }
+        self.env.extend(other.env);
+        Ok(())
+    }
+}
+
+impl Command {
+    // First line of the command's canonical source, e.g. to label it
+    pub fn source_line(&self) -> String {
+        let mut out = String::new();
+        write_source(
+            std::slice::from_ref(self),
+            &mut SourceState::default(),
+            &mut out,
+        );
+        out.lines().next().unwrap_or_default().to_string()
+    }
+
+    pub(crate) fn is_setup(&self) -> bool {
+        matches!(
+            self,
+            Command::SetShell(_)
+                | Command::SetSize(_, _)
+                | Command::SetEnv(_, _)
+                | Command::SetSeed(_)
+                | Command::SetReadySentinel(_)
+                | Command::SetPrewarm(_)
+                | Command::SetUmask(_)
+        )
+    }
+
+    fn setup_name(&self) -> &'static str {
+        match self {
+            Command::SetShell(_) => "shell",
+            Command::SetSize(_, _) => "size",
+            Command::SetSeed(_) => "seed",
+            Command::SetReadySentinel(_) => "ready_sentinel",
+            Command::SetPrewarm(_) => "prewarm",
+            Command::SetUmask(_) => "umask",
+            _ => "env",
+        }
+    }
+
+    // The directive's name if it acts on this machine rather than the session:
+    // running a program, reading or writing a file, or reading the environment
+    pub(crate) fn host_directive(&self) -> Option<&'static str> {
+        match self {
+            Command::SetShell(_) => Some("shell"),
+            Command::TypeSecret(_) => Some("secret"),
+            Command::Capture(_) => Some("capture"),
+            Command::Verify(_) => Some("verify"),
+            Command::Theme(_) => Some("theme"),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn sends_input(&self) -> bool {
+        matches!(
+            self,
+            Command::Type(_)
+                | Command::TypeSecret(_)
+                | Command::Retype(_)
+                | Command::Overwrite(_, _)
+                | Command::Paste(_)
+                | Command::Shuffle(_)
+                | Command::NoEcho(_)
+                | Command::Tagged(_, _)
+                | Command::Live(_)
+                | Command::AcceptSuggestion
+        )
+    }
+
+    // Commands kept outside the sections chosen by --tags, and allowed in a
+    // checkpoint. Most only change how later commands play and send nothing
+    // to the session. The exceptions are kept on purpose: `@ set_title`,
+    // `@ theme` and `@ reset_theme` write straight to the host terminal, and
+    // `@ expect_exit` checks the shell's exit status, which the chosen
+    // sections should still get.
+    pub(crate) fn is_setting(&self) -> bool {
+        matches!(
+            self,
+            Command::SetSpeed(_)
+                | Command::SetJitter(_)
+                | Command::SetJitterParams(_)
+                | Command::SetSync(_)
+                | Command::SetAutoEnter(_)
+                | Command::SetWordTypos(_)
+                | Command::SetTypoChars(_)
+                | Command::SetBackspace(_)
+                | Command::SetWordPause(_)
+                | Command::SetLongLines(_)
+                | Command::PushConfig
+                | Command::PopConfig
+                | Command::SetSuggestionKey(_)
+                | Command::ExpectExit(_)
+                | Command::SetTitle(_)
+                | Command::Theme(_)
+                | Command::ResetTheme
+        ) || self.is_setup()
+    }
+}
+
+impl Script {
+    // Split off the setup commands, to apply before opening the PTY, from the
+    // commands run once it exists. Setup must come before anything that sends
+    // input, since the session already exists by then. Later setup commands
+    // override earlier ones, except environment variables, which accumulate.
+    pub fn partition(self) -> Result<(Setup, Script), String> {
+        let mut setup = Setup::default();
+        let mut commands = Vec::new();
+        let mut started = false;
+
+        for command in self.commands {
+            if command.is_setup() && started {
+                return Err(format!(
+                    "@{} must come before any typing commands",
+                    command.setup_name()
+                ));
+            }
+            started |= command.sends_input();
+            match command {
+                Command::SetShell(shell) => setup.shell = Some(shell),
+                Command::SetSize(cols, rows) => setup.size = Some((cols, rows)),
+                Command::SetEnv(name, value) => setup.env.push((name, value)),
+                Command::SetSeed(seed) => setup.seed = Some(seed),
+                Command::SetReadySentinel(on) => setup.ready_sentinel = Some(on),
+                Command::SetPrewarm(on) => setup.prewarm = Some(on),
+                Command::SetUmask(mask) => setup.umask = Some(mask),
+                other => commands.push(other),
+            }
+        }
+
+        Ok((setup, Script { commands }))
+    }
+
+    // Render the script back into quipu source in canonical form. Parsing the
+    // result yields the same commands.
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        write_source(&self.commands, &mut SourceState::default(), &mut out);
+        out
+    }
+
+    // Merge runs of adjacent `$` lines into single Type commands, so playback
+    // types them in one go. Only done where it can't change timing: while
+    // speed and jitter are fixed rather than ranges (which are picked per
+    // command), prompt sync is off (which waits after each command) and long
+    // lines aren't sped up (which depends on each line's length). Empty lines
+    // and shuffled blocks are left alone. Hooks, markers and write
+    // errors then see the merged command as one.
+    pub fn coalesce_typing(&mut self) {
+        coalesce(&mut self.commands, &mut Pacing::default(), &mut Vec::new());
+    }
+
+    // Keep only the sections tagged with one of `tags`, everything inside
+    // them included (nested sections too). Elsewhere only settings are kept,
+    // so the chosen sections play with the speed, theme and so on set before
+    // them. Fails if a tag isn't used anywhere in the script.
+    pub fn select_tags(&mut self, tags: &[String]) -> Result<(), String> {
+        let mut used = Vec::new();
+        collect_tags(&self.commands, &mut used);
+        if let Some(missing) = tags.iter().find(|tag| !used.contains(tag)) {
+            return Err(format!("no section is tagged '{missing}'"));
+        }
+        select(&mut self.commands, tags);
+        Ok(())
+    }
+
+    // Render a best-effort plain shell script from what the script types:
+    // each line ended with Enter becomes a line of the script. Timing and
+    // anything not typed are dropped, and keys with no shell equivalent are
+    // noted in comments. Lossy: keystrokes meant for an interactive program
+    // (an editor, a password prompt) come out as shell lines too.
+    pub fn to_shell(&self) -> String {
+        let mut writer = ShellWriter::default();
+        writer.walk(&self.commands);
+        writer.finish()
+    }
+}
+
+// What decides whether adjacent Type commands can be merged, as of a point in
+// the script
+#[derive(Debug, Clone, Copy)]
+struct Pacing {
+    speed_fixed: bool,
+    jitter_fixed: bool,
+    prompt_sync: bool,
+    // Merging would change how much a line is sped up
+    long_lines: bool,
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        let config = PlaybackConfig::default();
+        Self {
+            speed_fixed: config.speed.is_fixed(),
+            jitter_fixed: config.jitter.is_fixed(),
+            prompt_sync: config.sync == SyncMode::Prompt,
+            long_lines: config.long_lines.is_some(),
+        }
+    }
+}
+
+impl Pacing {
+    // For when the settings in force can't be known
+    fn unmergeable() -> Self {
+        Self {
+            speed_fixed: false,
+            jitter_fixed: false,
+            prompt_sync: true,
+            long_lines: true,
+        }
+    }
+
+    fn mergeable(self) -> bool {
+        self.speed_fixed && self.jitter_fixed && !self.prompt_sync && !self.long_lines
+    }
+
+    fn apply(&mut self, command: &Command) {
+        match command {
+            Command::SetSpeed(speed) => self.speed_fixed = speed.is_fixed(),
+            Command::SetJitter(jitter) => self.jitter_fixed = jitter.is_fixed(),
+            Command::SetSync(sync) => self.prompt_sync = *sync == SyncMode::Prompt,
+            Command::SetLongLines(long_lines) => self.long_lines = long_lines.is_some(),
+            // Only reached inside a shuffle, where what is restored isn't
+            // known
+            Command::PopConfig => *self = Pacing::unmergeable(),
+            Command::NoEcho(inner) | Command::Tagged(_, inner) | Command::Live(inner) => {
+                inner.iter().for_each(|command| self.apply(command));
+            }
+            // Any of the commands may run last, so assume the least
+            // mergeable outcome
+            Command::Shuffle(inner) => {
+                for command in inner {
+                    let mut after = *self;
+                    after.apply(command);
+                    self.speed_fixed &= after.speed_fixed;
+                    self.jitter_fixed &= after.jitter_fixed;
+                    self.prompt_sync |= after.prompt_sync;
+                    self.long_lines |= after.long_lines;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// `saved` holds the pacing `@ push` saved, for `@ pop` to restore
+fn coalesce(commands: &mut Vec<Command>, pacing: &mut Pacing, saved: &mut Vec<Pacing>) {
+    let mut merged: Vec<Command> = Vec::with_capacity(commands.len());
+    for command in std::mem::take(commands) {
+        match (merged.last_mut(), command) {
+            (_, Command::PushConfig) => {
+                saved.push(*pacing);
+                merged.push(Command::PushConfig);
+            }
+            (_, Command::PopConfig) => {
+                *pacing = saved.pop().unwrap_or_else(Pacing::unmergeable);
+                merged.push(Command::PopConfig);
+            }
+            (Some(Command::Type(previous)), Command::Type(text))
+                if pacing.mergeable() && !previous.is_empty() && !text.is_empty() =>
+            {
+                previous.push_str(&text);
+            }
+            (_, Command::NoEcho(mut inner)) => {
+                coalesce(&mut inner, pacing, saved);
+                merged.push(Command::NoEcho(inner));
+            }
+            (_, Command::Tagged(names, mut inner)) => {
+                coalesce(&mut inner, pacing, saved);
+                merged.push(Command::Tagged(names, inner));
+            }
+            (_, Command::Live(mut inner)) => {
+                coalesce(&mut inner, pacing, saved);
+                merged.push(Command::Live(inner));
+            }
+            (_, command) => {
+                pacing.apply(&command);
+                merged.push(command);
+            }
+        }
+    }
+    *commands = merged;
+}
+
+fn collect_tags<'a>(commands: &'a [Command], used: &mut Vec<&'a String>) {
+    for command in commands {
+        match command {
+            Command::Tagged(names, inner) => {
+                used.extend(names);
+                collect_tags(inner, used);
+            }
+            Command::Shuffle(inner) | Command::NoEcho(inner) | Command::Live(inner) => {
+                collect_tags(inner, used)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Drop all but settings from `commands`, apart from sections tagged with one
+// of `tags`
+fn select(commands: &mut Vec<Command>, tags: &[String]) {
+    commands.retain_mut(|command| match command {
+        Command::Tagged(names, _) if names.iter().any(|name| tags.contains(name)) => true,
+        Command::Tagged(_, inner)
+        | Command::Shuffle(inner)
+        | Command::NoEcho(inner)
+        | Command::Live(inner) => {
+            select(inner, tags);
+            !inner.is_empty()
+        }
+        command => command.is_setting(),
+    });
+}
+
+// Quote a value for a POSIX shell: single-quoted unless it is made only of
+// characters the shell leaves alone
+pub(crate) fn shell_quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./+:@%,=".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+// State for Script::to_shell
+#[derive(Default)]
+struct ShellWriter {
+    interpreter: Option<String>,
+    body: String,
+    // Text typed on the current line so far
+    line: String,
+    // Keys on the current line that have no shell equivalent
+    notes: Vec<String>,
+}
+
+impl ShellWriter {
+    fn walk(&mut self, commands: &[Command]) {
+        for command in commands {
+            match command {
+                Command::SetShell(shell) => self.interpreter = Some(shell.clone()),
+                Command::SetEnv(name, value) => {
+                    self.body
+                        .push_str(&format!("export {name}={}\n", shell_quote(value)));
+                }
+                Command::Type(text) => self.type_text(text),
+                Command::Retype(text) => {
+                    self.line.clear();
+                    self.type_text(text);
+                }
+                Command::Overwrite(col, text) => {
+                    let mut line: Vec<char> = self.line.chars().collect();
+                    let start = (col - 1).min(line.len());
+                    let end = (start + text.chars().count()).min(line.len());
+                    line.splice(start..end, text.chars());
+                    self.line = line.into_iter().collect();
+                }
+                Command::TypeSecret(var) => {
+                    self.line.push_str(&format!("\"${var}\""));
+                    self.notes.push(format!("the value of ${var}"));
+                }
+                Command::AcceptSuggestion => self.notes.push("an autosuggestion".to_string()),
+                Command::Shuffle(inner) => {
+                    self.body
+                        .push_str("# The next commands were played in a random order\n");
+                    self.walk(inner);
+                }
+                Command::NoEcho(inner) | Command::Tagged(_, inner) | Command::Live(inner) => {
+                    self.walk(inner)
+                }
+                Command::Paste(lines) => self.type_text(&lines.join("\n")),
+                _ => {}
+            }
+        }
+    }
+
+    fn type_text(&mut self, text: &str) {
+        use crate::parser::encode_type_content;
+
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            match c {
+                '\r' | '\n' => self.end_line(),
+                '\x7f' | '\x08' => {
+                    self.line.pop();
+                }
+                // Ctrl-U clears the line in most shells
+                '\x15' => self.line.clear(),
+                '\x1b' => {
+                    // CSI and SS3 sequences run to their final byte; anything
+                    // else is Alt with the next character
+                    let mut end = start + 1;
+                    match chars.next() {
+                        Some((i, introducer @ ('[' | 'O'))) => {
+                            end = i + 1;
+                            for (i, c) in chars.by_ref() {
+                                end = i + c.len_utf8();
+                                if introducer == 'O' || ('@'..='~').contains(&c) {
+                                    break;
+                                }
+                            }
+                        }
+                        Some((i, c)) => end = i + c.len_utf8(),
+                        None => {}
+                    }
+                    self.notes.push(encode_type_content(&text[start..end]));
+                }
+                c if c.is_control() => self.notes.push(encode_type_content(&c.to_string())),
+                c => self.line.push(c),
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        if !self.notes.is_empty() {
+            self.body.push_str(&format!(
+                "# Also typed: {}\n",
+                std::mem::take(&mut self.notes).join(" ")
+            ));
+        }
+        if !self.line.trim().is_empty() {
+            self.body.push_str(self.line.trim_end());
+            self.body.push('\n');
+        }
+        self.line.clear();
+    }
+
+    fn finish(mut self) -> String {
+        if !self.line.trim().is_empty() {
+            self.body.push_str(&format!(
+                "# Typed without pressing Enter: {}\n",
+                self.line.trim_end()
+            ));
+            self.line.clear();
+        }
+        self.end_line();
+
+        let interpreter = match self.interpreter {
+            Some(shell) if shell.starts_with('/') => shell,
+            Some(shell) => format!("/usr/bin/env {shell}"),
+            None => "/bin/sh".to_string(),
+        };
+        format!(
+            "#!{interpreter}\n\
+             # Generated by quipu as an approximation of a script. Timing and keys\n\
+             # with no shell equivalent are left out, so check it before running.\n\
+             {}",
+            self.body
+        )
+    }
+}
+
+// What write_source tracks through a script: the key set by `@ backspace`, so
+// the Backspace key is written under the name that reads back as the same
+// byte, and those saved by `@ push`
+#[derive(Default)]
+struct SourceState {
+    backspace: BackspaceKey,
+    saved: Vec<BackspaceKey>,
+}
+
+fn write_source(commands: &[Command], state: &mut SourceState, out: &mut String) {
+    use crate::parser::encode_type_content_with;
+
+    for command in commands {
+        let current = state.backspace;
+        let encode = |text: &str| encode_type_content_with(text, current);
+        let line = match command {
+            Command::SetSpeed(speed) => format!("@ speed:{speed}"),
+            Command::SetJitter(jitter) => format!("@ jitter:{jitter}"),
+            Command::SetJitterParams(Some(params)) => {
+                format!("@ jitter_params:{}:{}", params.mean, params.stddev)
+            }
+            Command::SetJitterParams(None) => "@ jitter_params:off".to_string(),
+            Command::SetSync(SyncMode::Off) => "@ sync:off".to_string(),
+            Command::SetSync(SyncMode::Schedule) => "@ sync:on".to_string(),
+            Command::SetSync(SyncMode::Prompt) => "@ sync:osc133".to_string(),
+            Command::SetAutoEnter(true) => "@ autoenter:on".to_string(),
+            Command::SetAutoEnter(false) => "@ autoenter:off".to_string(),
+            Command::SetWordTypos(rate) => format!("@ word_typos:{rate}"),
+            Command::SetTypoChars(chars) => format!("@ typo_chars:{chars}"),
+            Command::SetWordPause(pause) => format!("@ word_pause:{}", pause.as_secs_f64()),
+            Command::SetLongLines(Some(long_lines)) => format!(
+                "@ long_lines:{}:{}",
+                long_lines.threshold, long_lines.max_speedup
+            ),
+            Command::SetLongLines(None) => "@ long_lines:off".to_string(),
+            Command::SetBackspace(key) => {
+                state.backspace = *key;
+                format!("@ backspace:{}", key.name())
+            }
+            Command::PushConfig => {
+                state.saved.push(state.backspace);
+                "@ push".to_string()
+            }
+            Command::PopConfig => {
+                state.backspace = state.saved.pop().unwrap_or_default();
+                "@ pop".to_string()
+            }
+            Command::Wait(duration) => format!("@ wait:{}", duration.as_secs_f64()),
+            Command::WaitUntil(time, next_day) => format!(
+                "@ wait_until:{}{}",
+                time.format("%H:%M:%S"),
+                if *next_day { ":next_day" } else { "" }
+            ),
+            Command::SetShell(shell) => format!("@ shell:{shell}"),
+            Command::SetSize(cols, rows) => format!("@ size:{cols}:{rows}"),
+            Command::SetEnv(name, value) => format!("@ env:{name}={value}"),
+            Command::SetSeed(seed) => format!("@ seed:{seed}"),
+            Command::SetReadySentinel(true) => "@ ready_sentinel:on".to_string(),
+            Command::SetReadySentinel(false) => "@ ready_sentinel:off".to_string(),
+            Command::SetPrewarm(true) => "@ prewarm:on".to_string(),
+            Command::SetPrewarm(false) => "@ prewarm:off".to_string(),
+            Command::SetUmask(mask) => format!("@ umask:{mask:03o}"),
+            Command::Type(text) if text.is_empty() => "$".to_string(),
+            Command::Type(text) => format!("$ {}", encode(text)),
+            Command::TypeSecret(var) => format!("@ secret:${var}"),
+            Command::Retype(text) => format!("@ retype:{}", encode(text)),
+            Command::Overwrite(col, text) => format!("@ overwrite:{col}:{}", encode(text)),
+            Command::Capture(path) => format!("@ capture:{}", path.display()),
+            Command::ResetCapture => "@ capture_reset".to_string(),
+            Command::Flush => "@ flush".to_string(),
+            Command::Checkpoint => "@ checkpoint".to_string(),
+            Command::ExpectExit(code) => format!("@ expect_exit:{code}"),
+            Command::Verify(command) => format!("@ verify:{command}"),
+            Command::Shuffle(inner) => {
+                out.push_str("@ shuffle\n");
+                write_source(inner, state, out);
+                "@ end".to_string()
+            }
+            Command::NoEcho(inner) => {
+                out.push_str("@ noecho\n");
+                write_source(inner, state, out);
+                "@ end".to_string()
+            }
+            Command::Paste(lines) => {
+                out.push_str("@ paste\n");
+                for line in lines {
+                    if line.is_empty() {
+                        out.push_str("$\n");
+                    } else {
+                        out.push_str(&format!("$ {}\n", encode(line)));
+                    }
+                }
+                "@ end".to_string()
+            }
+            Command::Tagged(names, inner) => {
+                out.push_str(&format!("@ tag:{}\n", names.join("|")));
+                write_source(inner, state, out);
+                "@ end".to_string()
+            }
+            Command::Live(inner) => {
+                out.push_str("@ live\n");
+                write_source(inner, state, out);
+                "@ end".to_string()
+            }
+            Command::SetSuggestionKey(key) => {
+                format!("@ suggestion_key:{}", encode(key))
+            }
+            Command::AcceptSuggestion => "@ accept_suggestion".to_string(),
+            Command::WaitKey(None) => "@ wait_key".to_string(),
+            Command::WaitKey(Some(key)) => format!("@ wait_key:{}", encode(key)),
+            Command::WaitResponse(query, timeout) => format!(
+                "@ wait_response:{}:{}",
+                encode(query),
+                timeout.as_secs_f64()
+            ),
+            Command::Echo(text) => format!("@ echo:{text}"),
+            Command::Reveal(text) => format!("@ reveal:{text}"),
+            Command::Subtitle(text) => format!("@ subtitle:{text}"),
+            Command::SetTitle(text) => format!("@ set_title:{text}"),
+            Command::Theme(path) => format!("@ theme:{}", path.display()),
+            Command::ResetTheme => "@ reset_theme".to_string(),
+            Command::Resize(cols, rows, pause) if pause.is_zero() => {
+                format!("@ resize:{cols}:{rows}")
+            }
+            Command::Resize(cols, rows, pause) => {
+                format!("@ resize:{cols}:{rows}:{}", pause.as_secs_f64())
+            }
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition() {
+        let script = Script {
+            commands: vec![
+                Command::SetSpeed(0.05.into()),
+                Command::SetShell("/bin/sh".to_string()),
+                Command::SetSize(100, 30),
+                Command::SetEnv("A".to_string(), "1".to_string()),
+                Command::SetSeed(7),
+                Command::SetReadySentinel(true),
+                Command::SetPrewarm(true),
+                Command::SetUmask(0o022),
+                Command::SetShell("/bin/bash".to_string()),
+                Command::Type("ls\r".to_string()),
+                Command::Wait(Duration::from_secs(1)),
+            ],
+        };
+        let (setup, runtime) = script.partition().unwrap();
+        assert_eq!(
+            setup,
+            Setup {
+                shell: Some("/bin/bash".to_string()),
+                size: Some((100, 30)),
+                env: vec![("A".to_string(), "1".to_string())],
+                seed: Some(7),
+                ready_sentinel: Some(true),
+                prewarm: Some(true),
+                umask: Some(0o022),
+            }
+        );
+        assert_eq!(
+            runtime.commands,
+            vec![
+                Command::SetSpeed(0.05.into()),
+                Command::Type("ls\r".to_string()),
+                Command::Wait(Duration::from_secs(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_tags() {
+        let tagged = |names: &[&str], commands: Vec<Command>| {
+            Command::Tagged(
+                names.iter().map(|name| name.to_string()).collect(),
+                commands,
+            )
+        };
+        let mut script = Script {
+            commands: vec![
+                Command::SetSpeed(0.05.into()),
+                Command::Type("echo always\r".to_string()),
+                tagged(
+                    &["intro"],
+                    vec![
+                        Command::SetJitter(0.1.into()),
+                        Command::Type("echo intro\r".to_string()),
+                        tagged(&["deep"], vec![Command::Type("echo deep\r".to_string())]),
+                    ],
+                ),
+                tagged(
+                    &["git", "advanced"],
+                    vec![
+                        Command::Wait(Duration::from_secs(1)),
+                        Command::Type("echo git\r".to_string()),
+                    ],
+                ),
+                Command::Shuffle(vec![Command::Type("echo a\r".to_string())]),
+            ],
+        };
+        let original = script.commands.clone();
+
+        // Settings outside the chosen sections are kept, everything else
+        // dropped; a chosen section keeps all of its contents
+        script
+            .select_tags(&["deep".to_string(), "advanced".to_string()])
+            .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(0.05.into()),
+                tagged(
+                    &["intro"],
+                    vec![
+                        Command::SetJitter(0.1.into()),
+                        tagged(&["deep"], vec![Command::Type("echo deep\r".to_string())]),
+                    ],
+                ),
+                original[3].clone(),
+            ]
+        );
+
+        let mut script = Script { commands: original };
+        let err = script.select_tags(&["intro".to_string(), "nope".to_string()]);
+        assert_eq!(err, Err("no section is tagged 'nope'".to_string()));
+
+        // Directives with a lasting effect on the host terminal are kept too,
+        // but not checks run at the end
+        let mut script = crate::parser::parse_script(
+            "@ set_title:Demo
+@ verify:true
+@ expect_exit:0
+@ tag:a
+$ a
+@ end",
+        )
+        .unwrap();
+        script.select_tags(&["a".to_string()]).unwrap();
+        assert_eq!(
+            script.to_source(),
+            "@ set_title:Demo\n@ expect_exit:0\n@ tag:a\n$ a\n@ end\n"
+        );
+    }
+
+    #[test]
+    fn test_coalesce_typing() {
+        let coalesced = |source: &str| {
+            let mut script = crate::parser::parse_script(source).unwrap();
+            script.coalesce_typing();
+            script.to_source()
+        };
+        assert_eq!(
+            coalesced("$ ls<ret>\n$ pwd<ret>\n$ cd\n@ wait:1\n$ a\n$ b\n"),
+            "$ ls<ret>pwd<ret>cd\n@ wait:1\n$ ab\n"
+        );
+        // Empty lines, speed ranges and prompt sync keep lines apart
+        assert_eq!(coalesced("$ a\n$\n$ b\n"), "$ a\n$\n$ b\n");
+        assert_eq!(
+            coalesced("@ speed:0.1..0.2\n$ a\n$ b\n@ speed:0.1\n$ c\n$ d\n"),
+            "@ speed:0.1..0.2\n$ a\n$ b\n@ speed:0.1\n$ cd\n"
+        );
+        assert_eq!(
+            coalesced("@ sync:osc133\n$ a<ret>\n$ b<ret>\n"),
+            "@ sync:osc133\n$ a<ret>\n$ b<ret>\n"
+        );
+        assert_eq!(
+            coalesced("@ long_lines:10:4\n$ a\n$ b\n"),
+            "@ long_lines:10:4\n$ a\n$ b\n"
+        );
+        // Inside noecho blocks but not shuffles, and a shuffle that may leave
+        // a speed range in place stops merging after it
+        assert_eq!(
+            coalesced(
+                "@ noecho\n$ a\n$ b\n@ end\n@ shuffle\n$ c\n$ d\n@ speed:0..1\n@ end\n$ e\n$ f\n"
+            ),
+            "@ noecho\n$ ab\n@ end\n@ shuffle\n$ c\n$ d\n@ speed:0..1\n@ end\n$ e\n$ f\n"
+        );
+        // A speed range set after @push is dropped again by @pop
+        assert_eq!(
+            coalesced("@ push\n@ speed:0..1\n$ a\n$ b\n@ pop\n$ c\n$ d\n"),
+            "@ push\n@ speed:0..1\n$ a\n$ b\n@ pop\n$ cd\n"
+        );
+    }
+
+    #[test]
+    fn test_long_lines_delay_scale() {
+        let long_lines = LongLines {
+            threshold: 10,
+            max_speedup: 4.0,
+        };
+        assert_eq!(long_lines.delay_scale(5), 1.0);
+        assert_eq!(long_lines.delay_scale(10), 1.0);
+        assert_eq!(long_lines.delay_scale(20), 0.5);
+        assert_eq!(long_lines.delay_scale(100), 0.25);
+    }
+
+    #[test]
+    fn test_to_shell() {
+        let script = Script {
+            commands: vec![
+                Command::SetShell("bash".to_string()),
+                Command::SetEnv("GREETING".to_string(), "it's here".to_string()),
+                Command::Type("echo hello\r".to_string()),
+                Command::Wait(Duration::from_secs(1)),
+                Command::Type("cat a.txt".to_string()),
+                Command::Overwrite(5, "b.md!".to_string()),
+                Command::Overwrite(9, "ed".to_string()),
+                Command::Type("\r".to_string()),
+                Command::Type("ls -lx\x7f\ra".to_string()),
+                Command::Retype("git status\x1b[D\x1b[C".to_string()),
+                Command::Type("\r\x12vim\x1bOP\r".to_string()),
+                Command::NoEcho(vec![Command::TypeSecret("TOKEN".to_string())]),
+                Command::Type("\rvim notes.txt\r:wq\r\x1bf".to_string()),
+            ],
+        };
+        assert_eq!(
+            script.to_shell(),
+            "#!/usr/bin/env bash\n\
+             # Generated by quipu as an approximation of a script. Timing and keys\n\
+             # with no shell equivalent are left out, so check it before running.\n\
+             export GREETING='it'\\''s here'\n\
+             echo hello\n\
+             cat b.mded\n\
+             ls -l\n\
+             # Also typed: <left> <right>\n\
+             git status\n\
+             # Also typed: <C-r> <F1>\n\
+             vim\n\
+             # Also typed: the value of $TOKEN\n\
+             \"$TOKEN\"\n\
+             vim notes.txt\n\
+             :wq\n\
+             # Also typed: <esc>f\n"
+        );
+    }
+
+    #[test]
+    fn test_setup_merge() {
+        let mut setup = Setup {
+            shell: None,
+            size: Some((100, 30)),
+            env: vec![("A".to_string(), "1".to_string())],
+            seed: None,
+            ready_sentinel: Some(true),
+            prewarm: None,
+            umask: Some(0o022),
+        };
+        setup
+            .merge(Setup {
+                shell: Some("/bin/sh".to_string()),
+                size: Some((100, 30)),
+                env: vec![("B".to_string(), "2".to_string())],
+                seed: Some(1),
+                ready_sentinel: None,
+                prewarm: Some(false),
+                umask: Some(0o022),
+            })
+            .unwrap();
+        assert_eq!(setup.seed, Some(1));
+        assert_eq!(setup.prewarm, Some(false));
+        assert_eq!(setup.shell.as_deref(), Some("/bin/sh"));
+        assert_eq!(setup.size, Some((100, 30)));
+        assert_eq!(setup.env.len(), 2);
+
+        let err = setup
+            .merge(Setup {
+                size: Some((80, 24)),
+                ..Setup::default()
+            })
+            .unwrap_err();
+        assert!(err.contains("@size:80:24"), "unexpected error: {err}");
+        assert!(
+            setup
+                .merge(Setup {
+                    shell: Some("/bin/zsh".to_string()),
+                    ..Setup::default()
+                })
+                .is_err()
+        );
+        let err = setup
+            .merge(Setup {
+                ready_sentinel: Some(false),
+                ..Setup::default()
+            })
+            .unwrap_err();
+        assert!(
+            err.contains("@ready_sentinel:off"),
+            "unexpected error: {err}"
+        );
+        let err = setup
+            .merge(Setup {
+                prewarm: Some(true),
+                ..Setup::default()
+            })
+            .unwrap_err();
+        assert!(err.contains("@prewarm:on"), "unexpected error: {err}");
+        let err = setup
+            .merge(Setup {
+                umask: Some(0o077),
+                ..Setup::default()
+            })
+            .unwrap_err();
+        assert!(
+            err.contains("@umask:077 conflicts with the earlier @umask:022"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn test_partition_rejects_late_setup() {
+        let script = Script {
+            commands: vec![Command::Type("ls\r".to_string()), Command::SetSize(100, 30)],
+        };
+        assert_eq!(
+            script.partition().unwrap_err(),
+            "@size must come before any typing commands"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Golden tests: play each script in tests/fixtures into a memory sink and
+//! compare the bytes sent with the committed `.out` file next to it.
+//!
+//! The clock is paused, so waits and typing delays take no real time. Set
+//! QUIPU_BLESS=1 to rewrite the expected files after an intended change.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::AtomicBool};
+
+use quipu::parser::parse_script;
+use quipu::playback::PlaybackEngine;
+use quipu::pty::{MemorySink, PtyManager};
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut scripts: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "qp"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+// Escape the bytes so the expected files are readable text, breaking lines
+// after each Enter
+fn render(bytes: &[u8]) -> String {
+    let mut rendered = bytes.escape_ascii().to_string().replace(r"\r", "\\r\n");
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    rendered
+}
+
+async fn play(path: &Path) -> Vec<u8> {
+    let source = std::fs::read_to_string(path).unwrap();
+    let script = parse_script(&source).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    let (_, script) = script.partition().unwrap();
+
+    let sink = MemorySink::default();
+    let pty = PtyManager::with_sink(sink.clone(), 80, 24);
+    let mut engine = PlaybackEngine::without_signal_handler(pty, Arc::new(AtomicBool::new(true)));
+    engine.set_seed(0);
+    engine.execute(script).await.unwrap();
+    sink.contents()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_golden_byte_streams() {
+    let bless = std::env::var_os("QUIPU_BLESS").is_some();
+    let mut failures = Vec::new();
+
+    for path in fixtures() {
+        let actual = render(&play(&path).await);
+        let expected_path = path.with_extension("out");
+        if bless {
+            std::fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path)
+            .unwrap_or_else(|_| panic!("missing {}", expected_path.display()));
+        if actual != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{expected}--- actual\n{actual}",
+                path.display()
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,151 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Headless playback against a real shell
+
+#![cfg(unix)]
+
+#[tokio::test]
+async fn test_run_headless_captures_output() {
+    let output = quipu::run_headless(
+        "@ env:PS1=$ \n$ echo hello<ret>\n$ echo $((6 * 7))<ret>",
+        "/bin/sh",
+    )
+    .await
+    .unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(lines.contains(&"hello"), "{output:?}");
+    assert!(lines.contains(&"42"), "{output:?}");
+}
+
+#[tokio::test]
+async fn test_ready_sentinel() {
+    let output = quipu::run_headless(
+        "@ ready_sentinel:on\n@ env:PS1=$ \n$ echo hello<ret>",
+        "/bin/sh",
+    )
+    .await
+    .unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    let ready = lines
+        .iter()
+        .position(|line| line.ends_with("__QUIPU_READY__"));
+    let hello = lines.iter().position(|line| *line == "hello");
+    assert!(ready.is_some() && ready < hello, "{output:?}");
+}
+
+#[test]
+fn test_primed_input_comes_first() {
+    use quipu::pty::{MemorySink, PtyManager, PtyOptions};
+
+    let options = PtyOptions {
+        env: vec![("PS1".to_string(), "$ ".to_string())],
+        prime: b"echo primed\n".to_vec(),
+        ..PtyOptions::default()
+    };
+    let sink = MemorySink::default();
+    let mut pty = PtyManager::headless("/bin/sh", 80, 24, &options, sink.clone()).unwrap();
+    pty.send_keystroke("echo typed\r").unwrap();
+    assert!(pty.shutdown(quipu::pty::EXIT_TIMEOUT));
+
+    let output = quipu::headless::strip_ansi(&String::from_utf8_lossy(&sink.contents()));
+    // Echoed input appears as the shell starts up, so skip it and find the
+    // lines each command printed
+    let printed = |word: &str| {
+        output
+            .lines()
+            .position(|line| !line.contains("echo") && line.trim_end().ends_with(word))
+    };
+    let (primed, typed) = (printed("primed"), printed("typed"));
+    assert!(primed.is_some() && primed < typed, "{output:?}");
+}
+
+#[test]
+fn test_recording() {
+    use quipu::pty::{MemorySink, PtyManager, PtyOptions, SessionEvent};
+
+    let options = PtyOptions {
+        record: true,
+        ..PtyOptions::default()
+    };
+    let mut pty = PtyManager::headless("/bin/sh", 80, 24, &options, MemorySink::default()).unwrap();
+    let recording = pty.recording().unwrap();
+    pty.send_keystroke("echo recorded\r").unwrap();
+    assert!(pty.shutdown(quipu::pty::EXIT_TIMEOUT));
+
+    let events = recording.events();
+    assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    let output: Vec<u8> = events
+        .into_iter()
+        .flat_map(|(_, event)| match event {
+            SessionEvent::Output(bytes) => bytes,
+            SessionEvent::Resize(..) => Vec::new(),
+        })
+        .collect();
+    assert!(String::from_utf8_lossy(&output).contains("recorded"));
+    assert_eq!(recording.size(), (80, 24));
+}
+
+#[tokio::test]
+async fn test_expect_exit() {
+    quipu::run_headless("$ true<ret>\n@ expect_exit:0", "/bin/sh")
+        .await
+        .unwrap();
+    let err = quipu::run_headless("$ false<ret>\n@ expect_exit:0", "/bin/sh")
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "The shell exited with code 1, expected 0");
+}
+
+#[tokio::test]
+async fn test_exit_budget() {
+    use quipu::playback::PlaybackEngine;
+    use quipu::pty::{MemorySink, PtyManager, PtyOptions};
+    use std::sync::{Arc, atomic::AtomicBool};
+    use std::time::Duration;
+
+    let play = |script: &str, budget: f64| {
+        let script = quipu::parser::parse_script(script).unwrap();
+        async move {
+            let pty = PtyManager::headless(
+                "/bin/sh",
+                80,
+                24,
+                &PtyOptions::default(),
+                MemorySink::default(),
+            )
+            .unwrap();
+            let mut engine =
+                PlaybackEngine::without_signal_handler(pty, Arc::new(AtomicBool::new(true)));
+            engine.set_time_scale(0.0, 1.0);
+            engine.set_exit_budget(Some(Duration::from_secs_f64(budget)));
+            let result = engine.execute(script).await;
+            (result, engine.exit_time())
+        }
+    };
+
+    let (result, exit_time) = play("$ echo done<ret>", 10.0).await;
+    result.unwrap();
+    assert!(exit_time.unwrap() < Duration::from_secs(10));
+
+    // Still sleeping when the budget runs out, so it is killed
+    let (result, exit_time) = play("$ sleep 30<ret>", 0.5).await;
+    assert_eq!(
+        format!("{:#}", result.unwrap_err()),
+        "The shell didn't exit within 0.5s of playback ending: \
+         the shell was still running 0.5s after its input was closed"
+    );
+    assert_eq!(exit_time, None);
+}
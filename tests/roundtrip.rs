@@ -0,0 +1,50 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Round-trip tests: every fixture script, including the known-tricky inputs
+//! in tests/fixtures/roundtrip, must parse back to the same commands from
+//! its canonical source.
+
+use std::path::{Path, PathBuf};
+
+use quipu::parser::{parse_script, reparse_stable};
+
+fn scripts_in(dir: &Path) -> Vec<PathBuf> {
+    let mut scripts: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "qp"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn test_fixtures_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut scripts = scripts_in(&dir);
+    scripts.extend(scripts_in(&dir.join("roundtrip")));
+
+    for path in scripts {
+        let source = std::fs::read_to_string(&path).unwrap();
+        // A fixture the parser rejects would pass vacuously
+        parse_script(&source).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+        assert!(
+            reparse_stable(&source),
+            "{} does not round-trip",
+            path.display()
+        );
+    }
+}